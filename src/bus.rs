@@ -0,0 +1,187 @@
+use crate::mfm::*;
+use teensycore::prelude::*;
+
+/**
+ * An entry this long in a list of raw intervals doesn't end in a
+ * transition, it carries on into the next entry. That lets 16 bit
+ * entries hold the long gaps found on unformatted tracks.
+ */
+pub const INTERVAL_CONTINUES: u16 = u16::MAX;
+
+/**
+ * Everything the driver needs from the hardware. Every signal is
+ * expressed in terms of whether it is asserted, so implementations
+ * take care of the active-low wiring of the floppy interface.
+ *
+ * TeensyBus drives the real pins. Anything else (like the mock
+ * used by the tests) lets the drive logic run off-target.
+ */
+pub trait FloppyBus {
+    /** Assert or release the step line */
+    fn set_step(&mut self, active: bool);
+
+    /** Choose the step direction, true for towards the spindle */
+    fn set_dir(&mut self, inward: bool);
+
+    /** Select which head reads and writes */
+    fn set_side(&mut self, head: u8);
+
+    /** Spin the motor up or down */
+    fn set_motor(&mut self, on: bool);
+
+    /** Assert or release drive select */
+    fn set_select(&mut self, selected: bool);
+
+    /** Open or close the write gate */
+    fn set_gate(&mut self, open: bool);
+
+    /** Assert or release the write data line */
+    fn set_write_data(&mut self, active: bool);
+
+    /** True while the index pulse is present */
+    fn index(&mut self) -> bool;
+
+    /** True while the head is over track 0 */
+    fn track00(&mut self) -> bool;
+
+    /** True if the media has its write protect tab set */
+    fn write_protected(&mut self) -> bool;
+
+    /** True if the drive reports it is ready */
+    fn ready(&mut self) -> bool;
+
+    /** True while a flux transition is being reported */
+    fn read_data(&mut self) -> bool;
+
+    /** A monotonic clock in nanoseconds */
+    fn nanos(&mut self) -> uNano;
+
+    /** Block for the given amount of nanoseconds */
+    fn wait_ns(&mut self, ns: uNano);
+
+    /** Adjust flux timings for the data rate of the media */
+    fn set_data_rate(&mut self, rate: DataRate);
+
+    /** How many ticks per second read_interval counts */
+    fn flux_clock(&self) -> u32;
+
+    /**
+     * Wait for the next flux transition and return how long it
+     * came after the previous one, in ticks of the flux clock.
+     */
+    fn read_interval(&mut self) -> u32;
+
+    /** Time the next flux transition */
+    fn read_symbol(&mut self) -> Symbol;
+
+    /**
+     * Time the next flux transition in bit cells, as the data
+     * separator sees it. Unlike read_symbol this isn't clamped to
+     * what MFM can produce, so damaged flux can be told apart.
+     */
+    fn read_cells(&mut self) -> u32;
+
+    /**
+     * Forget what the data separator has learned. The flux which
+     * comes next was written at another time, or is on another
+     * track or head.
+     */
+    fn reset_separator(&mut self) {}
+
+    /**
+     * Classify flux with limits measured off the disk, rather
+     * than the ones implied by the data rate.
+     */
+    fn set_pulse_limits(&mut self, limits: &PulseLimits);
+
+    /**
+     * Shift written transitions by this many nanoseconds, according
+     * to mfm_precomp_shift. Zero writes every pulse as it is.
+     */
+    fn set_precompensation(&mut self, ns: u32);
+
+    /**
+     * Open the write gate, write the flux signals and close the gate
+     * again. Assumes the head is already in the right spot.
+     */
+    fn write_flux(&mut self, flux_signals: &[Symbol]);
+
+    /**
+     * Open the write gate, write transitions spaced by the given
+     * intervals and close the gate again. Intervals are in ticks of
+     * `clock` and are written as they are, for flux which didn't
+     * come from the MFM encoder. See INTERVAL_CONTINUES.
+     */
+    fn write_intervals(&mut self, intervals: &[u16], clock: u32);
+
+    /**
+     * Wait for an A1 sync marker. Returns false if the index pulse
+     * arrives first.
+     */
+    fn sync(&mut self) -> bool {
+        let mut detector = SyncDetector::new();
+        self.reset_separator();
+        loop {
+            if self.index() {
+                return false;
+            }
+
+            match Symbol::checked_from_cells(self.read_cells()) {
+                Some(sym) => {
+                    if detector.push(sym) == Some(SyncKind::A1) {
+                        return true;
+                    }
+                }
+                // Flux MFM can't produce breaks up any marker
+                None => detector = SyncDetector::new(),
+            }
+        }
+    }
+
+    /** Report progress, by default nowhere */
+    fn debug_str(&mut self, _message: &[u8]) {}
+
+    /**
+     * Turn progress reports on or off. Anything which shares a
+     * port with them, like a file transfer, needs them off.
+     */
+    fn set_debug(&mut self, _enabled: bool) {}
+}
+
+/**
+ * A byte stream to a host computer, such as the USB serial port.
+ */
+pub trait SerialPort {
+    /** The next byte from the host, if one has arrived */
+    fn read(&mut self) -> Option<u8>;
+
+    /** Send bytes to the host */
+    fn write(&mut self, bytes: &[u8]);
+
+    /** Send a number in decimal */
+    fn write_dec(&mut self, mut value: u32) {
+        let mut digits = [0u8; 10];
+        let mut len = 0;
+        loop {
+            digits[digits.len() - 1 - len] = b'0' + (value % 10) as u8;
+            len += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+
+        self.write(&digits[digits.len() - len..]);
+    }
+
+    /** Send a number in hex, zero padded to the given digits */
+    fn write_hex(&mut self, value: u32, digits: usize) {
+        let mut text = [0u8; 8];
+        for i in 0..digits {
+            let nibble = (value >> (4 * (digits - 1 - i))) & 0xF;
+            text[i] = b"0123456789ABCDEF"[nibble as usize];
+        }
+
+        self.write(&text[0..digits]);
+    }
+}
@@ -1,15 +1,136 @@
-pub static INDEX_PIN: usize = 3; // This pin assigmnet is recreational. DO NOT CHANGE. It won't work.
-pub static DRIVE_PIN: usize = 4;
-pub static MOTOR_PIN: usize = 5;
-pub static DIR_PIN: usize = 6;
-pub static STEP_PIN: usize = 7;
-pub static WRITE_PIN: usize = 8;
-pub static GATE_PIN: usize = 9;
-pub static TRACK00_PIN: usize = 10;
-pub static WRITE_PROTECT_PIN: usize = 11;
-pub static READ_PIN: usize = 12; // This pin assigmnet is recreational. DO NOT CHANGE. It won't work.
-pub static HEAD_SEL_PIN: usize = 14;
-pub static READY_PIN: usize = 15;
+use teensycore::prelude::*;
+
+/**
+ * Which teensy pin each floppy signal is wired to. Any of the
+ * teensy 4.0 pins will do, the fast register accessors used by
+ * the timing critical code are derived from this at init. The
+ * exception is the read pin, which is timed by quad timer 1 and
+ * so has to be one of pins 10, 11 or 12.
+ */
+#[derive(Copy, Clone)]
+pub struct PinMap {
+    pub index: usize,
+    pub drive: usize,
+    pub motor: usize,
+    pub dir: usize,
+    pub step: usize,
+    pub write: usize,
+    pub gate: usize,
+    pub track00: usize,
+    pub write_protect: usize,
+    pub read: usize,
+    pub head_sel: usize,
+    pub ready: usize,
+}
+
+/**
+ * The wiring described in the README.
+ */
+pub const DEFAULT_PINS: PinMap = PinMap {
+    index: 3,
+    drive: 4,
+    motor: 5,
+    dir: 6,
+    step: 7,
+    write: 8,
+    gate: 9,
+    track00: 10,
+    write_protect: 11,
+    read: 12,
+    head_sel: 14,
+    ready: 15,
+};
+
+/** The index is a teensy pin, the output is the bit within its gpio register */
+const PIN_BITS: [u8; 40] = [
+    3, 2, 4, 5, 6, 8, 10, 17, 16, 11, 0, 2, 1, 3, 18, 19, 23, 22, 17, 16, 26, 27, 24, 25, 12, 13,
+    30, 31, 18, 31, 23, 22, 12, 7, 15, 14, 13, 12, 17, 16,
+];
+
+/** The index is a teensy pin, the output is the fast gpio register that controls it */
+const PIN_GPIO: [u32; 40] = [
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO9,
+    addrs::GPIO9,
+    addrs::GPIO9,
+    addrs::GPIO9,
+    addrs::GPIO7,
+    addrs::GPIO7,
+    addrs::GPIO7,
+    addrs::GPIO7,
+    addrs::GPIO7,
+    addrs::GPIO7,
+    addrs::GPIO7,
+    addrs::GPIO7,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO6,
+    addrs::GPIO8,
+    addrs::GPIO9,
+    addrs::GPIO8,
+    addrs::GPIO8,
+    addrs::GPIO7,
+    addrs::GPIO9,
+    addrs::GPIO8,
+    addrs::GPIO8,
+    addrs::GPIO8,
+    addrs::GPIO8,
+    addrs::GPIO8,
+    addrs::GPIO8,
+];
+
+/**
+ * A gpio register and bitmask for poking a pin directly. This
+ * bypasses pin_read and pin_out in teensycore which are too
+ * slow for the flux timing.
+ */
+#[derive(Copy, Clone)]
+pub struct FastPin {
+    pub addr: u32,
+    pub mask: u32,
+}
+
+impl FastPin {
+    pub const fn new(pin: usize) -> Self {
+        return FastPin {
+            addr: PIN_GPIO[pin],
+            mask: 0x1 << PIN_BITS[pin],
+        };
+    }
+
+    #[inline(always)]
+    pub fn read(&self) -> u32 {
+        unsafe {
+            return *(self.addr as *mut u32) & self.mask;
+        }
+    }
+
+    #[inline(always)]
+    pub fn set(&self) {
+        unsafe {
+            *((self.addr + 0x84) as *mut u32) = self.mask;
+        }
+    }
+
+    #[inline(always)]
+    pub fn clear(&self) {
+        unsafe {
+            *((self.addr + 0x88) as *mut u32) = self.mask;
+        }
+    }
+}
 
 /*
  Informationally, 3.5" floppy disks have the following features:
@@ -0,0 +1,57 @@
+/**
+ * CRC-16/CCITT as used by the IBM System/34 format. Polynomial
+ * 0x1021, initial value 0xFFFF, no reflection and no final xor.
+ */
+const CRC_POLY: u16 = 0x1021;
+const CRC_INIT: u16 = 0xFFFF;
+
+/**
+ * The CRC of the three 0xA1 sync bytes which precede every
+ * ID field and data field. Both CRCs are computed over the
+ * sync bytes too, so this is the seed to continue from.
+ */
+pub const CRC_SYNC_SEED: u16 = 0xCDB4;
+
+/**
+ * Continue a CRC computation over the given bytes.
+ */
+pub fn crc16_update(mut crc: u16, bytes: &[u8]) -> u16 {
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 > 0 {
+                crc = (crc << 1) ^ CRC_POLY;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    return crc;
+}
+
+/**
+ * Compute the CRC of a field which was preceded by the
+ * A1 A1 A1 sync marker. The bytes should start with the
+ * address mark (0xFE, 0xFB, ...).
+ */
+pub fn crc16_field(bytes: &[u8]) -> u16 {
+    return crc16_update(CRC_SYNC_SEED, bytes);
+}
+
+#[cfg(test)]
+mod test_crc {
+    use super::*;
+
+    #[test]
+    pub fn test_sync_seed() {
+        assert_eq!(crc16_update(CRC_INIT, &[0xA1, 0xA1, 0xA1]), CRC_SYNC_SEED);
+        assert_eq!(crc16_update(CRC_INIT, b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    pub fn test_id_field() {
+        // Cylinder 0, head 0, sector 1, 512 bytes
+        assert_eq!(crc16_field(&[0xFE, 0x00, 0x00, 0x01, 0x02]), 0xCA6F);
+    }
+}
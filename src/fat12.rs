@@ -0,0 +1,1946 @@
+use crate::bus::*;
+use crate::fdd::*;
+use crate::geometry::*;
+
+/** Directory entries are always 32 bytes */
+const DIR_ENTRY_SIZE: usize = 32;
+
+/** Directory entries in one 512 byte sector */
+const ENTRIES_PER_BLOCK: u32 = 512 / DIR_ENTRY_SIZE as u32;
+
+/** FAT12 volumes hold fewer clusters than this */
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+
+/** Sectors in the largest FAT a FAT12 volume needs */
+const FAT12_MAX_FAT_SECTORS: usize = ((FAT12_MAX_CLUSTERS as usize + 2) * 3 / 2 + 511) / 512;
+
+/** Any FAT value at or above this marks the end of a chain */
+const FAT_EOC: u16 = 0xFF8;
+
+/** A FAT value which marks a cluster as bad */
+const FAT_BAD: u16 = 0xFF7;
+
+/** Longest VFAT name, in UCS-2 characters */
+pub const MAX_LONG_NAME: usize = 255;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/**
+ * Anything which can read and write 512 byte blocks by their
+ * logical address. The filesystem doesn't care whether that is
+ * a real drive or an image in memory.
+ */
+pub trait BlockDevice {
+    fn read_block(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), FddError>;
+    fn write_block(&mut self, lba: u32, data: &[u8]) -> Result<(), FddError>;
+}
+
+impl<B: FloppyBus> BlockDevice for FloppyDrive<B> {
+    fn read_block(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), FddError> {
+        return FloppyDrive::read_block(self, lba, buf);
+    }
+
+    fn write_block(&mut self, lba: u32, data: &[u8]) -> Result<(), FddError> {
+        return FloppyDrive::write_block(self, lba, data);
+    }
+}
+
+/**
+ * Everything that can go wrong while walking the filesystem.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FatError {
+    /// The drive failed to read or write a sector
+    Device(FddError),
+    /// The boot sector doesn't describe a FAT12 volume
+    NotFat,
+    /// A FAT chain points somewhere it shouldn't
+    Corrupt,
+    /// No entry with that name exists
+    NotFound,
+    /// A directory was expected
+    NotADirectory,
+    /// A file was expected
+    IsADirectory,
+    /// An entry with that name already exists
+    Exists,
+    /// Only empty directories can be removed
+    NotEmpty,
+    /// The name can't be stored as an 8.3 name
+    InvalidName,
+    /// There are no free clusters left
+    DiskFull,
+    /// The root directory has no free entries left
+    DirFull,
+    /// A sector read back differently than it was written
+    Verify,
+}
+
+impl From<FddError> for FatError {
+    fn from(error: FddError) -> Self {
+        return FatError::Device(error);
+    }
+}
+
+/**
+ * The BIOS parameter block from the boot sector, along with
+ * where each region of the volume starts.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bpb {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    pub media: u8,
+    pub sectors_per_fat: u16,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub serial: u32,
+    pub label: [u8; 11],
+    /// First sector of the first FAT
+    pub fat_start: u32,
+    /// First sector of the root directory
+    pub root_start: u32,
+    /// First sector of cluster 2
+    pub data_start: u32,
+    /// How many data clusters exist
+    pub clusters: u32,
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    return bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8;
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    return le16(bytes, offset) as u32 | (le16(bytes, offset + 2) as u32) << 16;
+}
+
+impl Bpb {
+    /**
+     * Parse and sanity check the boot sector. Only 512 byte
+     * sectors and FAT12 sized volumes are accepted.
+     */
+    pub fn parse(sector: &[u8]) -> Result<Bpb, FatError> {
+        let bytes_per_sector = le16(sector, 11);
+        let sectors_per_cluster = sector[13];
+        let reserved_sectors = le16(sector, 14);
+        let fats = sector[16];
+        let root_entries = le16(sector, 17);
+        let mut total_sectors = le16(sector, 19) as u32;
+        let sectors_per_fat = le16(sector, 22);
+
+        if total_sectors == 0 {
+            total_sectors = le32(sector, 32);
+        }
+
+        if bytes_per_sector != 512
+            || sectors_per_cluster == 0
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fats == 0
+            || root_entries == 0
+            || sectors_per_fat == 0
+        {
+            return Err(FatError::NotFat);
+        }
+
+        let fat_start = reserved_sectors as u32;
+        let root_start = fat_start + fats as u32 * sectors_per_fat as u32;
+        let root_sectors = (root_entries as u32 + ENTRIES_PER_BLOCK - 1) / ENTRIES_PER_BLOCK;
+        let data_start = root_start + root_sectors;
+        if data_start >= total_sectors {
+            return Err(FatError::NotFat);
+        }
+
+        let clusters = (total_sectors - data_start) / sectors_per_cluster as u32;
+        if clusters >= FAT12_MAX_CLUSTERS {
+            return Err(FatError::NotFat);
+        }
+
+        // The serial and label only exist with the extended signature
+        let mut serial = 0;
+        let mut label = [b' '; 11];
+        if sector[38] == 0x29 {
+            serial = le32(sector, 39);
+            label.copy_from_slice(&sector[43..54]);
+        }
+
+        return Ok(Bpb {
+            bytes_per_sector: bytes_per_sector,
+            sectors_per_cluster: sectors_per_cluster,
+            reserved_sectors: reserved_sectors,
+            fats: fats,
+            root_entries: root_entries,
+            total_sectors: total_sectors,
+            media: sector[21],
+            sectors_per_fat: sectors_per_fat,
+            sectors_per_track: le16(sector, 24),
+            heads: le16(sector, 26),
+            serial: serial,
+            label: label,
+            fat_start: fat_start,
+            root_start: root_start,
+            data_start: data_start,
+            clusters: clusters,
+        });
+    }
+
+    /**
+     * How many bytes are in a cluster.
+     */
+    pub fn cluster_size(&self) -> u32 {
+        return self.sectors_per_cluster as u32 * 512;
+    }
+
+    /**
+     * The first sector of a data cluster.
+     */
+    pub fn cluster_lba(&self, cluster: u16) -> u32 {
+        return self.data_start + (cluster as u32 - 2) * self.sectors_per_cluster as u32;
+    }
+
+    /**
+     * True if the cluster number refers to the data region.
+     */
+    pub fn is_data_cluster(&self, cluster: u16) -> bool {
+        return cluster >= 2 && (cluster as u32) < self.clusters + 2;
+    }
+}
+
+/**
+ * A moment in time, for stamping directory entries. FAT can
+ * only store years 1980 to 2107 and seconds in steps of two.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/**
+ * The earliest moment FAT can represent. Used until the caller
+ * provides a real time.
+ */
+pub const FAT_EPOCH: Timestamp = Timestamp {
+    year: 1980,
+    month: 1,
+    day: 1,
+    hour: 0,
+    minute: 0,
+    second: 0,
+};
+
+impl Timestamp {
+    /**
+     * Pack into the FAT (time, date) pair.
+     */
+    pub fn to_fat(&self) -> (u16, u16) {
+        let time = (self.hour as u16) << 11 | (self.minute as u16) << 5 | (self.second as u16) / 2;
+        let year = self.year.clamp(1980, 2107) - 1980;
+        let date = year << 9 | (self.month as u16) << 5 | self.day as u16;
+        return (time, date);
+    }
+}
+
+/**
+ * Turn a name into its space padded 8.3 form, upper casing it
+ * on the way. Names which need a VFAT entry are rejected.
+ */
+pub fn to_short_name(name: &str) -> Result<[u8; 11], FatError> {
+    let mut short = [b' '; 11];
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[0..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return Err(FatError::InvalidName);
+    }
+
+    for (i, byte) in base.bytes().chain(ext.bytes()).enumerate() {
+        let allowed = byte.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&byte);
+        if !allowed {
+            return Err(FatError::InvalidName);
+        }
+
+        let slot = if i < base.len() {
+            i
+        } else {
+            8 + i - base.len()
+        };
+        short[slot] = byte.to_ascii_uppercase();
+    }
+
+    return Ok(short);
+}
+
+/**
+ * A directory on the volume. Cluster 0 is the root directory,
+ * which lives in its own fixed region.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Dir {
+    pub cluster: u16,
+}
+
+/**
+ * A single file or directory, as found while walking a directory.
+ */
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    /// The 8.3 name, space padded, without the dot
+    pub name: [u8; 11],
+    pub attr: u8,
+    /// The first cluster of the contents (0 when empty)
+    pub cluster: u16,
+    pub size: u32,
+    pub time: u16,
+    pub date: u16,
+    /// The VFAT name in UCS-2, if there was one
+    pub long_name: [u16; MAX_LONG_NAME],
+    pub long_len: usize,
+    /// The directory holding this entry
+    pub parent: Dir,
+    /// Position of the 8.3 entry within its directory
+    index: u32,
+    /// Position of the first VFAT entry belonging to it
+    first_index: u32,
+}
+
+impl DirEntry {
+    fn parse(raw: &[u8]) -> Self {
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&raw[0..11]);
+
+        // 0x05 stands in for a real 0xE5, which marks deleted entries
+        if name[0] == 0x05 {
+            name[0] = 0xE5;
+        }
+
+        return DirEntry {
+            name: name,
+            attr: raw[11],
+            cluster: le16(raw, 26),
+            size: le32(raw, 28),
+            time: le16(raw, 22),
+            date: le16(raw, 24),
+            long_name: [0; MAX_LONG_NAME],
+            long_len: 0,
+            parent: Dir { cluster: 0 },
+            index: 0,
+            first_index: 0,
+        };
+    }
+
+    /**
+     * The raw 32 byte form of the 8.3 entry.
+     */
+    fn to_raw(&self, raw: &mut [u8; DIR_ENTRY_SIZE]) {
+        raw.fill(0);
+        raw[0..11].copy_from_slice(&self.name);
+        if raw[0] == 0xE5 {
+            raw[0] = 0x05;
+        }
+
+        raw[11] = self.attr;
+        raw[14..16].copy_from_slice(&self.time.to_le_bytes());
+        raw[16..18].copy_from_slice(&self.date.to_le_bytes());
+        raw[18..20].copy_from_slice(&self.date.to_le_bytes());
+        raw[22..24].copy_from_slice(&self.time.to_le_bytes());
+        raw[24..26].copy_from_slice(&self.date.to_le_bytes());
+        raw[26..28].copy_from_slice(&self.cluster.to_le_bytes());
+        raw[28..32].copy_from_slice(&self.size.to_le_bytes());
+    }
+
+    pub fn is_dir(&self) -> bool {
+        return self.attr & ATTR_DIRECTORY > 0;
+    }
+
+    /**
+     * Write the 8.3 name as "NAME.EXT" and return its length.
+     */
+    pub fn short_name(&self, buf: &mut [u8; 12]) -> usize {
+        let mut len = 0;
+        for i in 0..8 {
+            if self.name[i] != b' ' {
+                len = i + 1;
+            }
+        }
+        buf[0..len].copy_from_slice(&self.name[0..len]);
+
+        let mut ext = 0;
+        for i in 0..3 {
+            if self.name[8 + i] != b' ' {
+                ext = i + 1;
+            }
+        }
+
+        if ext > 0 {
+            buf[len] = b'.';
+            buf[len + 1..len + 1 + ext].copy_from_slice(&self.name[8..8 + ext]);
+            len += 1 + ext;
+        }
+
+        return len;
+    }
+
+    /**
+     * Write the name as UTF-8, preferring the long name. Returns
+     * how many bytes were written, truncated to fit the buffer.
+     */
+    pub fn display_name(&self, buf: &mut [u8]) -> usize {
+        if self.long_len == 0 {
+            let mut short = [0u8; 12];
+            let len = self.short_name(&mut short).min(buf.len());
+            buf[0..len].copy_from_slice(&short[0..len]);
+            return len;
+        }
+
+        let mut len = 0;
+        for unit in &self.long_name[0..self.long_len] {
+            let ch = char::from_u32(*unit as u32).unwrap_or('?');
+            if len + ch.len_utf8() > buf.len() {
+                break;
+            }
+            len += ch.encode_utf8(&mut buf[len..]).len();
+        }
+
+        return len;
+    }
+
+    /**
+     * Compare against a name, ignoring ASCII case. Both the long
+     * name and the 8.3 name are considered.
+     */
+    pub fn matches(&self, name: &str) -> bool {
+        if self.long_len > 0 {
+            let mut units = name.encode_utf16();
+            let mut same = true;
+            for unit in &self.long_name[0..self.long_len] {
+                match units.next() {
+                    Some(other) if fold(*unit) == fold(other) => {}
+                    _ => {
+                        same = false;
+                        break;
+                    }
+                }
+            }
+
+            if same && units.next().is_none() {
+                return true;
+            }
+        }
+
+        let mut short = [0u8; 12];
+        let len = self.short_name(&mut short);
+        return name.as_bytes().eq_ignore_ascii_case(&short[0..len]);
+    }
+}
+
+/** Lowercase ASCII so names compare without regard to case */
+fn fold(unit: u16) -> u16 {
+    if unit >= b'A' as u16 && unit <= b'Z' as u16 {
+        return unit + 32;
+    }
+    return unit;
+}
+
+/**
+ * The checksum of an 8.3 name, stored in each of its VFAT entries.
+ */
+pub fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    let mut sum: u8 = 0;
+    for byte in name {
+        sum = (sum >> 1 | sum << 7).wrapping_add(*byte);
+    }
+    return sum;
+}
+
+/** Where the 13 characters of a VFAT entry are stored */
+const LFN_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/**
+ * Tracks a position while walking the entries of a directory.
+ */
+pub struct DirCursor {
+    dir: Dir,
+    cluster: u16,
+    index: u32,
+    done: bool,
+}
+
+impl DirCursor {
+    pub fn new(dir: Dir) -> Self {
+        return DirCursor {
+            dir: dir,
+            cluster: dir.cluster,
+            index: 0,
+            done: false,
+        };
+    }
+}
+
+/**
+ * An open file and how far into it we've read.
+ */
+pub struct File {
+    pub size: u32,
+    pub position: u32,
+    cluster: u16,
+}
+
+/**
+ * A FAT12 volume on a block device. Every sector that gets
+ * written is read back and compared before moving on.
+ *
+ * The first FAT is kept in memory. Changes to it are written
+ * out once an operation is done, each changed sector once per
+ * copy, and always before a directory entry which relies on them.
+ */
+pub struct Fat12<D: BlockDevice> {
+    dev: D,
+    bpb: Bpb,
+    block: [u8; 512],
+    block_lba: Option<u32>,
+    now: Timestamp,
+    fat: [u8; FAT12_MAX_FAT_SECTORS * 512],
+    fat_loaded: bool,
+    /// FAT sectors changed since they were last written, one bit each
+    fat_dirty: u16,
+}
+
+impl<D: BlockDevice> Fat12<D> {
+    /**
+     * Read the boot sector and get ready to walk the volume.
+     */
+    pub fn mount(mut dev: D) -> Result<Self, FatError> {
+        let mut block = [0u8; 512];
+        dev.read_block(0, &mut block)?;
+        let bpb = Bpb::parse(&block)?;
+
+        return Ok(Fat12 {
+            dev: dev,
+            bpb: bpb,
+            block: block,
+            block_lba: Some(0),
+            now: FAT_EPOCH,
+            fat: [0; FAT12_MAX_FAT_SECTORS * 512],
+            fat_loaded: false,
+            fat_dirty: 0,
+        });
+    }
+
+    pub fn bpb(&self) -> &Bpb {
+        return &self.bpb;
+    }
+
+    /**
+     * Direct access to the underlying device.
+     */
+    pub fn device(&mut self) -> &mut D {
+        return &mut self.dev;
+    }
+
+    /**
+     * Give the device back.
+     */
+    pub fn unmount(self) -> D {
+        return self.dev;
+    }
+
+    /**
+     * The time stamped on entries that are created or changed
+     * from now on. There's no clock on board, so the caller has
+     * to provide it.
+     */
+    pub fn set_time(&mut self, now: Timestamp) {
+        self.now = now;
+    }
+
+    pub fn root(&self) -> Dir {
+        return Dir { cluster: 0 };
+    }
+
+    /**
+     * Bring a block into the cache. Consecutive reads of the same
+     * sector (FAT lookups, small file reads) only hit the disk once.
+     */
+    fn load(&mut self, lba: u32) -> Result<(), FatError> {
+        if self.block_lba != Some(lba) {
+            self.block_lba = None;
+            self.dev.read_block(lba, &mut self.block)?;
+            self.block_lba = Some(lba);
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * How many sectors of the FAT are in use. Anything past
+     * the entry of the last cluster isn't cached.
+     */
+    fn fat_sectors(&self) -> usize {
+        return (self.bpb.sectors_per_fat as usize).min(FAT12_MAX_FAT_SECTORS);
+    }
+
+    /**
+     * Bring the first FAT into memory, if it isn't already.
+     */
+    fn load_fat(&mut self) -> Result<(), FatError> {
+        if !self.fat_loaded {
+            for i in 0..self.fat_sectors() {
+                let lba = self.bpb.fat_start + i as u32;
+                self.dev
+                    .read_block(lba, &mut self.fat[i * 512..(i + 1) * 512])?;
+            }
+            self.fat_loaded = true;
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * Read a byte of the first FAT.
+     */
+    fn fat_byte(&mut self, offset: u32) -> Result<u8, FatError> {
+        self.load_fat()?;
+        if offset as usize >= self.fat_sectors() * 512 {
+            return Err(FatError::Corrupt);
+        }
+
+        return Ok(self.fat[offset as usize]);
+    }
+
+    /**
+     * Look up the FAT entry of a cluster. Entries are 12 bits, so
+     * two of them share three bytes and may straddle a sector.
+     */
+    pub fn fat_entry(&mut self, cluster: u16) -> Result<u16, FatError> {
+        let offset = cluster as u32 * 3 / 2;
+        let value = self.fat_byte(offset)? as u16 | (self.fat_byte(offset + 1)? as u16) << 8;
+
+        if cluster & 1 == 1 {
+            return Ok(value >> 4);
+        }
+
+        return Ok(value & 0xFFF);
+    }
+
+    /**
+     * Follow the chain to the next cluster. Returns None at the
+     * end of the chain.
+     */
+    pub fn next_cluster(&mut self, cluster: u16) -> Result<Option<u16>, FatError> {
+        let next = self.fat_entry(cluster)?;
+        if next >= FAT_EOC {
+            return Ok(None);
+        } else if next == FAT_BAD || !self.bpb.is_data_cluster(next) {
+            return Err(FatError::Corrupt);
+        }
+
+        return Ok(Some(next));
+    }
+
+    /**
+     * Produce the next entry of a directory, skipping deleted
+     * entries and volume labels. Returns None at the end.
+     */
+    pub fn next_entry(&mut self, cursor: &mut DirCursor) -> Result<Option<DirEntry>, FatError> {
+        let mut long_name = [0u16; MAX_LONG_NAME];
+        let mut long_len = 0;
+        let mut long_start = 0;
+        let mut checksum = 0u8;
+
+        while !cursor.done {
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            if !self.read_raw_entry(cursor, &mut raw)? {
+                cursor.done = true;
+                break;
+            }
+
+            if raw[0] == 0x00 {
+                cursor.done = true;
+                break;
+            } else if raw[0] == 0xE5 {
+                long_len = 0;
+                continue;
+            }
+
+            if raw[11] & 0x3F == ATTR_LONG_NAME {
+                let sequence = (raw[0] & 0x1F) as usize;
+                if sequence == 0 || sequence * 13 > MAX_LONG_NAME + 13 {
+                    long_len = 0;
+                    continue;
+                }
+
+                // The last part of the name comes first
+                if raw[0] & 0x40 > 0 {
+                    long_len = 0;
+                    long_start = cursor.index - 1;
+                    checksum = raw[13];
+                }
+
+                for (i, offset) in LFN_OFFSETS.iter().enumerate() {
+                    let unit = le16(&raw, *offset);
+                    let position = (sequence - 1) * 13 + i;
+                    if unit == 0x0000 || unit == 0xFFFF || position >= MAX_LONG_NAME {
+                        break;
+                    }
+                    long_name[position] = unit;
+                    long_len = long_len.max(position + 1);
+                }
+                continue;
+            }
+
+            let mut entry = DirEntry::parse(&raw);
+            if entry.attr & ATTR_VOLUME_ID > 0 {
+                long_len = 0;
+                continue;
+            }
+
+            entry.parent = cursor.dir;
+            entry.index = cursor.index - 1;
+            entry.first_index = entry.index;
+            if long_len > 0 && lfn_checksum(&raw[0..11].try_into().unwrap()) == checksum {
+                entry.long_name = long_name;
+                entry.long_len = long_len;
+                entry.first_index = long_start;
+            }
+
+            return Ok(Some(entry));
+        }
+
+        return Ok(None);
+    }
+
+    /**
+     * Copy the next 32 byte entry out of the directory. Returns
+     * false once the directory has no more room.
+     */
+    fn read_raw_entry(
+        &mut self,
+        cursor: &mut DirCursor,
+        raw: &mut [u8; DIR_ENTRY_SIZE],
+    ) -> Result<bool, FatError> {
+        let lba;
+        if cursor.dir.cluster == 0 {
+            if cursor.index >= self.bpb.root_entries as u32 {
+                return Ok(false);
+            }
+            lba = self.bpb.root_start + cursor.index / ENTRIES_PER_BLOCK;
+        } else {
+            let per_cluster = self.bpb.cluster_size() / DIR_ENTRY_SIZE as u32;
+            if cursor.index > 0 && cursor.index % per_cluster == 0 {
+                match self.next_cluster(cursor.cluster)? {
+                    Some(next) => cursor.cluster = next,
+                    None => {
+                        return Ok(false);
+                    }
+                }
+            }
+
+            let within = cursor.index % per_cluster;
+            lba = self.bpb.cluster_lba(cursor.cluster) + within / ENTRIES_PER_BLOCK;
+        }
+
+        self.load(lba)?;
+        let offset = (cursor.index % ENTRIES_PER_BLOCK) as usize * DIR_ENTRY_SIZE;
+        raw.copy_from_slice(&self.block[offset..offset + DIR_ENTRY_SIZE]);
+        cursor.index += 1;
+        return Ok(true);
+    }
+
+    /**
+     * Find an entry in a directory by name.
+     */
+    pub fn find(&mut self, dir: Dir, name: &str) -> Result<DirEntry, FatError> {
+        let mut cursor = DirCursor::new(dir);
+        while let Some(entry) = self.next_entry(&mut cursor)? {
+            if entry.matches(name) {
+                return Ok(entry);
+            }
+        }
+
+        return Err(FatError::NotFound);
+    }
+
+    /**
+     * Find an entry by its path from the root, with components
+     * separated by '/'.
+     */
+    pub fn open(&mut self, path: &str) -> Result<DirEntry, FatError> {
+        let mut dir = self.root();
+        let mut found = None;
+
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            if let Some(entry) = found {
+                dir = self.open_dir(&entry)?;
+            }
+            found = Some(self.find(dir, part)?);
+        }
+
+        return found.ok_or(FatError::IsADirectory);
+    }
+
+    /**
+     * The directory an entry refers to. Cluster 0 is the root, which
+     * is what ".." holds in a top level directory.
+     */
+    pub fn open_dir(&self, entry: &DirEntry) -> Result<Dir, FatError> {
+        if !entry.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+
+        if entry.cluster != 0 && !self.bpb.is_data_cluster(entry.cluster) {
+            return Err(FatError::Corrupt);
+        }
+
+        return Ok(Dir {
+            cluster: entry.cluster,
+        });
+    }
+
+    /**
+     * Start reading a file from the beginning.
+     */
+    pub fn open_file(&self, entry: &DirEntry) -> Result<File, FatError> {
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+
+        if entry.size > 0 && !self.bpb.is_data_cluster(entry.cluster) {
+            return Err(FatError::Corrupt);
+        }
+
+        return Ok(File {
+            size: entry.size,
+            position: 0,
+            cluster: entry.cluster,
+        });
+    }
+
+    /**
+     * Read the next chunk of a file. Returns how many bytes were
+     * read, which is 0 once the end of the file is reached.
+     */
+    pub fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, FatError> {
+        let cluster_size = self.bpb.cluster_size();
+        let mut count = 0;
+
+        while count < buf.len() && file.position < file.size {
+            let within = file.position % cluster_size;
+            let lba = self.bpb.cluster_lba(file.cluster) + within / 512;
+            let offset = (within % 512) as usize;
+            let len = (512 - offset)
+                .min(buf.len() - count)
+                .min((file.size - file.position) as usize);
+
+            self.load(lba)?;
+            buf[count..count + len].copy_from_slice(&self.block[offset..offset + len]);
+            count += len;
+            file.position += len as u32;
+
+            if file.position % cluster_size == 0 && file.position < file.size {
+                file.cluster = self.next_cluster(file.cluster)?.ok_or(FatError::Corrupt)?;
+            }
+        }
+
+        return Ok(count);
+    }
+
+    /**
+     * Write a block with verify, keeping the cache in step.
+     */
+    fn store(&mut self, lba: u32, data: &[u8; 512]) -> Result<(), FatError> {
+        self.block_lba = None;
+        store_verified(&mut self.dev, lba, data)?;
+
+        self.block = *data;
+        self.block_lba = Some(lba);
+        return Ok(());
+    }
+
+    /**
+     * Change a FAT entry. Only the copy in memory changes,
+     * flush_fat writes it to the disk.
+     */
+    fn set_fat_entry(&mut self, cluster: u16, value: u16) -> Result<(), FatError> {
+        let offset = cluster as usize * 3 / 2;
+        self.load_fat()?;
+        if offset + 1 >= self.fat_sectors() * 512 {
+            return Err(FatError::Corrupt);
+        }
+
+        for i in 0..2 {
+            let byte = &mut self.fat[offset + i];
+            *byte = match (cluster & 1 == 1, i) {
+                (false, 0) => value as u8,
+                (false, _) => (*byte & 0xF0) | (value >> 8) as u8 & 0x0F,
+                (true, 0) => (*byte & 0x0F) | (value << 4) as u8,
+                (true, _) => (value >> 4) as u8,
+            };
+            self.fat_dirty |= 1 << ((offset + i) / 512);
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * Write every changed FAT sector to each copy of the FAT.
+     */
+    fn flush_fat(&mut self) -> Result<(), FatError> {
+        for i in 0..self.fat_sectors() {
+            if self.fat_dirty & (1 << i) == 0 {
+                continue;
+            }
+
+            let mut sector = [0u8; 512];
+            sector.copy_from_slice(&self.fat[i * 512..(i + 1) * 512]);
+            for fat in 0..self.bpb.fats as u32 {
+                let start = self.bpb.fat_start + fat * self.bpb.sectors_per_fat as u32;
+                self.store(start + i as u32, &sector)?;
+            }
+            self.fat_dirty &= !(1 << i);
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * Wrap up an operation which changes the FAT. If it worked the
+     * changes are written out, otherwise they're dropped and the
+     * FAT is read back from the disk the next time it's needed.
+     */
+    fn finish<T>(&mut self, result: Result<T, FatError>) -> Result<T, FatError> {
+        let result = match result {
+            Ok(value) => self.flush_fat().map(|_| value),
+            Err(error) => Err(error),
+        };
+
+        if result.is_err() {
+            self.fat_loaded = false;
+            self.fat_dirty = 0;
+        }
+
+        return result;
+    }
+
+    /**
+     * Claim a free cluster as the new end of a chain, linking it
+     * after `previous` if given.
+     */
+    fn allocate(&mut self, previous: Option<u16>) -> Result<u16, FatError> {
+        for cluster in 2..(self.bpb.clusters + 2) as u16 {
+            if self.fat_entry(cluster)? == 0 {
+                self.set_fat_entry(cluster, 0xFFF)?;
+                if let Some(previous) = previous {
+                    self.set_fat_entry(previous, cluster)?;
+                }
+                return Ok(cluster);
+            }
+        }
+
+        return Err(FatError::DiskFull);
+    }
+
+    /**
+     * Release every cluster of a chain.
+     */
+    fn free_chain(&mut self, start: u16) -> Result<(), FatError> {
+        let mut cluster = Some(start);
+        let mut steps = 0;
+        while let Some(current) = cluster {
+            if !self.bpb.is_data_cluster(current) || steps > self.bpb.clusters {
+                return Err(FatError::Corrupt);
+            }
+
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, 0)?;
+            steps += 1;
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * Fill a cluster with zeros.
+     */
+    fn zero_cluster(&mut self, cluster: u16) -> Result<(), FatError> {
+        let lba = self.bpb.cluster_lba(cluster);
+        for i in 0..self.bpb.sectors_per_cluster as u32 {
+            self.store(lba + i, &[0; 512])?;
+        }
+
+        return Ok(());
+    }
+
+    /**
+     * Where the entry at a position of a directory is stored,
+     * as (lba, byte offset).
+     */
+    fn entry_location(&mut self, dir: Dir, index: u32) -> Result<(u32, usize), FatError> {
+        let offset = (index % ENTRIES_PER_BLOCK) as usize * DIR_ENTRY_SIZE;
+        if dir.cluster == 0 {
+            return Ok((self.bpb.root_start + index / ENTRIES_PER_BLOCK, offset));
+        }
+
+        let per_cluster = self.bpb.cluster_size() / DIR_ENTRY_SIZE as u32;
+        let mut cluster = dir.cluster;
+        for _ in 0..index / per_cluster {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupt)?;
+        }
+
+        let lba = self.bpb.cluster_lba(cluster) + (index % per_cluster) / ENTRIES_PER_BLOCK;
+        return Ok((lba, offset));
+    }
+
+    /**
+     * Overwrite the raw entry at a position of a directory. The
+     * FAT goes to the disk first, so the entry never refers to
+     * clusters which aren't allocated there yet.
+     */
+    fn put_raw_entry(
+        &mut self,
+        dir: Dir,
+        index: u32,
+        raw: &[u8; DIR_ENTRY_SIZE],
+    ) -> Result<(), FatError> {
+        self.flush_fat()?;
+        let (lba, offset) = self.entry_location(dir, index)?;
+        self.load(lba)?;
+
+        let mut block = self.block;
+        block[offset..offset + DIR_ENTRY_SIZE].copy_from_slice(raw);
+        return self.store(lba, &block);
+    }
+
+    /**
+     * Find an unused entry in a directory. Subdirectories grow by
+     * a cluster when they are full, the root directory can't.
+     */
+    fn free_slot(&mut self, dir: Dir) -> Result<u32, FatError> {
+        let mut cursor = DirCursor::new(dir);
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        while self.read_raw_entry(&mut cursor, &mut raw)? {
+            if raw[0] == 0x00 || raw[0] == 0xE5 {
+                return Ok(cursor.index - 1);
+            }
+        }
+
+        if dir.cluster == 0 {
+            return Err(FatError::DirFull);
+        }
+
+        let cluster = self.allocate(Some(cursor.cluster))?;
+        self.zero_cluster(cluster)?;
+        return Ok(cursor.index);
+    }
+
+    /**
+     * Add a new entry to a directory.
+     */
+    fn add_entry(&mut self, dir: Dir, entry: &mut DirEntry) -> Result<(), FatError> {
+        let index = self.free_slot(dir)?;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        entry.to_raw(&mut raw);
+        self.put_raw_entry(dir, index, &raw)?;
+
+        entry.parent = dir;
+        entry.index = index;
+        entry.first_index = index;
+        return Ok(());
+    }
+
+    /**
+     * Store the data in a fresh cluster chain. Returns the first
+     * cluster, or 0 when there is no data.
+     */
+    fn write_chain(&mut self, data: &[u8]) -> Result<u16, FatError> {
+        let mut first = 0;
+        let mut previous = None;
+        for chunk in data.chunks(self.bpb.cluster_size() as usize) {
+            let cluster = match self.allocate(previous) {
+                Ok(cluster) => cluster,
+                Err(error) => {
+                    // Don't leak what was claimed so far
+                    if first != 0 {
+                        self.free_chain(first)?;
+                    }
+                    return Err(error);
+                }
+            };
+
+            if first == 0 {
+                first = cluster;
+            }
+            previous = Some(cluster);
+
+            let lba = self.bpb.cluster_lba(cluster);
+            for (i, part) in chunk.chunks(512).enumerate() {
+                let mut block = [0u8; 512];
+                block[0..part.len()].copy_from_slice(part);
+                self.store(lba + i as u32, &block)?;
+            }
+        }
+
+        return Ok(first);
+    }
+
+    /**
+     * Create a file, or replace the contents of an existing one.
+     * The new contents are written before the old clusters are
+     * released, so an interrupted write leaves the old file intact.
+     */
+    pub fn write_file(&mut self, dir: Dir, name: &str, data: &[u8]) -> Result<DirEntry, FatError> {
+        let result = self.store_file(dir, name, data);
+        return self.finish(result);
+    }
+
+    fn store_file(&mut self, dir: Dir, name: &str, data: &[u8]) -> Result<DirEntry, FatError> {
+        let short = to_short_name(name)?;
+        let existing = match self.find(dir, name) {
+            Ok(entry) => Some(entry),
+            Err(FatError::NotFound) => None,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        if let Some(entry) = existing {
+            if entry.is_dir() || entry.attr & ATTR_READ_ONLY > 0 {
+                return Err(FatError::Exists);
+            }
+        }
+
+        let (time, date) = self.now.to_fat();
+        let first = self.write_chain(data)?;
+
+        match existing {
+            Some(mut entry) => {
+                let old = entry.cluster;
+                entry.cluster = first;
+                entry.size = data.len() as u32;
+                entry.time = time;
+                entry.date = date;
+                entry.attr |= ATTR_ARCHIVE;
+
+                // Keep the creation stamp of the original
+                let (lba, offset) = self.entry_location(dir, entry.index)?;
+                self.load(lba)?;
+                let mut raw = [0u8; DIR_ENTRY_SIZE];
+                raw.copy_from_slice(&self.block[offset..offset + DIR_ENTRY_SIZE]);
+                raw[11] = entry.attr;
+                raw[18..20].copy_from_slice(&date.to_le_bytes());
+                raw[22..24].copy_from_slice(&time.to_le_bytes());
+                raw[24..26].copy_from_slice(&date.to_le_bytes());
+                raw[26..28].copy_from_slice(&first.to_le_bytes());
+                raw[28..32].copy_from_slice(&entry.size.to_le_bytes());
+                self.put_raw_entry(dir, entry.index, &raw)?;
+
+                if self.bpb.is_data_cluster(old) {
+                    self.free_chain(old)?;
+                }
+                return Ok(entry);
+            }
+            None => {
+                let mut entry = DirEntry::parse(&[0; DIR_ENTRY_SIZE]);
+                entry.name = short;
+                entry.attr = ATTR_ARCHIVE;
+                entry.cluster = first;
+                entry.size = data.len() as u32;
+                entry.time = time;
+                entry.date = date;
+
+                if let Err(error) = self.add_entry(dir, &mut entry) {
+                    if first != 0 {
+                        self.free_chain(first)?;
+                    }
+                    return Err(error);
+                }
+                return Ok(entry);
+            }
+        }
+    }
+
+    /**
+     * Create an empty subdirectory.
+     */
+    pub fn create_dir(&mut self, dir: Dir, name: &str) -> Result<Dir, FatError> {
+        let result = self.make_dir(dir, name);
+        return self.finish(result);
+    }
+
+    fn make_dir(&mut self, dir: Dir, name: &str) -> Result<Dir, FatError> {
+        let short = to_short_name(name)?;
+        match self.find(dir, name) {
+            Ok(_) => {
+                return Err(FatError::Exists);
+            }
+            Err(FatError::NotFound) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        let (time, date) = self.now.to_fat();
+        let cluster = self.allocate(None)?;
+        self.zero_cluster(cluster)?;
+
+        let mut entry = DirEntry::parse(&[0; DIR_ENTRY_SIZE]);
+        entry.attr = ATTR_DIRECTORY;
+        entry.time = time;
+        entry.date = date;
+
+        // Every directory starts with "." and ".."
+        let created = Dir { cluster: cluster };
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        entry.name = *b".          ";
+        entry.cluster = cluster;
+        entry.to_raw(&mut raw);
+        self.put_raw_entry(created, 0, &raw)?;
+
+        entry.name = *b"..         ";
+        entry.cluster = dir.cluster;
+        entry.to_raw(&mut raw);
+        self.put_raw_entry(created, 1, &raw)?;
+
+        entry.name = short;
+        entry.cluster = cluster;
+        if let Err(error) = self.add_entry(dir, &mut entry) {
+            self.free_chain(cluster)?;
+            return Err(error);
+        }
+
+        return Ok(created);
+    }
+
+    /**
+     * Delete a file or an empty directory, along with its
+     * VFAT entries.
+     */
+    pub fn remove(&mut self, dir: Dir, name: &str) -> Result<(), FatError> {
+        let result = self.unlink(dir, name);
+        return self.finish(result);
+    }
+
+    fn unlink(&mut self, dir: Dir, name: &str) -> Result<(), FatError> {
+        let entry = self.find(dir, name)?;
+        if entry.name[0] == b'.' {
+            return Err(FatError::InvalidName);
+        }
+
+        if entry.is_dir() {
+            let mut cursor = DirCursor::new(self.open_dir(&entry)?);
+            while let Some(child) = self.next_entry(&mut cursor)? {
+                if child.name[0] != b'.' {
+                    return Err(FatError::NotEmpty);
+                }
+            }
+        }
+
+        // Hide the entry first so a failure never leaves it
+        // pointing at freed clusters
+        for index in entry.first_index..=entry.index {
+            let (lba, offset) = self.entry_location(dir, index)?;
+            self.load(lba)?;
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            raw.copy_from_slice(&self.block[offset..offset + DIR_ENTRY_SIZE]);
+            raw[0] = 0xE5;
+            self.put_raw_entry(dir, index, &raw)?;
+        }
+
+        if self.bpb.is_data_cluster(entry.cluster) {
+            self.free_chain(entry.cluster)?;
+        }
+
+        return Ok(());
+    }
+}
+
+/**
+ * Pick the media descriptor, cluster size and root directory
+ * size DOS uses for a geometry.
+ */
+fn fat_layout(geometry: &Geometry) -> (u8, u8, u16) {
+    return match (geometry.cylinders, geometry.sectors_per_track) {
+        // 1.44M
+        (_, 18) => (0xF0, 1, 224),
+        // 1.2M
+        (_, 15) => (0xF9, 1, 224),
+        // 360K
+        (40, _) => (0xFD, 2, 112),
+        // 720K
+        _ => (0xF9, 2, 112),
+    };
+}
+
+/**
+ * Write a block and read it back to make sure it stuck.
+ */
+fn store_verified<D: BlockDevice>(dev: &mut D, lba: u32, data: &[u8; 512]) -> Result<(), FatError> {
+    let mut check = [0u8; 512];
+    dev.write_block(lba, data)?;
+    dev.read_block(lba, &mut check)?;
+    if check != *data {
+        return Err(FatError::Verify);
+    }
+
+    return Ok(());
+}
+
+/**
+ * Lay down an empty FAT12 filesystem on a low-level formatted
+ * disk: a DOS 3.3+ compatible boot sector describing the
+ * geometry, both FATs and an empty root directory. An empty
+ * label leaves the volume unnamed.
+ */
+pub fn mkfs_fat12<D: BlockDevice>(
+    dev: &mut D,
+    geometry: &Geometry,
+    label: &str,
+    serial: u32,
+) -> Result<(), FatError> {
+    if geometry.sector_size() != 512 || label.len() > 11 {
+        return Err(FatError::InvalidName);
+    }
+
+    let mut volume = [b' '; 11];
+    for (i, byte) in label.bytes().enumerate() {
+        if byte < 0x20 || b"\"*+,./:;<=>?[\\]|".contains(&byte) {
+            return Err(FatError::InvalidName);
+        }
+        volume[i] = byte.to_ascii_uppercase();
+    }
+
+    let (media, sectors_per_cluster, root_entries) = fat_layout(geometry);
+    let total = geometry.blocks();
+    let reserved: u32 = 1;
+    let fats: u32 = 2;
+    let root_sectors = (root_entries as u32 + ENTRIES_PER_BLOCK - 1) / ENTRIES_PER_BLOCK;
+
+    // The FAT has to cover the clusters left over once it's
+    // been carved out, so settle on a size iteratively.
+    let mut sectors_per_fat: u32 = 1;
+    loop {
+        let data = total - reserved - root_sectors - fats * sectors_per_fat;
+        let clusters = data / sectors_per_cluster as u32;
+        let needed = ((clusters + 2) * 3 / 2 + 511) / 512;
+        if needed <= sectors_per_fat {
+            break;
+        }
+        sectors_per_fat = needed;
+    }
+
+    let mut boot = [0u8; 512];
+    boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"MSDOS5.0");
+    boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot[13] = sectors_per_cluster;
+    boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+    boot[16] = fats as u8;
+    boot[17..19].copy_from_slice(&root_entries.to_le_bytes());
+    boot[19..21].copy_from_slice(&(total as u16).to_le_bytes());
+    boot[21] = media;
+    boot[22..24].copy_from_slice(&(sectors_per_fat as u16).to_le_bytes());
+    boot[24..26].copy_from_slice(&(geometry.sectors_per_track as u16).to_le_bytes());
+    boot[26..28].copy_from_slice(&(geometry.heads as u16).to_le_bytes());
+
+    // Extended BPB
+    boot[36] = 0x00;
+    boot[38] = 0x29;
+    boot[39..43].copy_from_slice(&serial.to_le_bytes());
+    if label.is_empty() {
+        boot[43..54].copy_from_slice(b"NO NAME    ");
+    } else {
+        boot[43..54].copy_from_slice(&volume);
+    }
+    boot[54..62].copy_from_slice(b"FAT12   ");
+
+    // Not bootable: the jump lands on a loop which halts
+    boot[62..66].copy_from_slice(&[0xFA, 0xF4, 0xEB, 0xFD]);
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    store_verified(dev, 0, &boot)?;
+
+    // Each FAT starts with the media descriptor and an end of
+    // chain marker for the reserved cluster 1.
+    let empty = [0u8; 512];
+    let mut first = [0u8; 512];
+    first[0..3].copy_from_slice(&[media, 0xFF, 0xFF]);
+    for fat in 0..fats {
+        let start = reserved + fat * sectors_per_fat;
+        store_verified(dev, start, &first)?;
+        for i in 1..sectors_per_fat {
+            store_verified(dev, start + i, &empty)?;
+        }
+    }
+
+    let root_start = reserved + fats * sectors_per_fat;
+    let mut root = [0u8; 512];
+    if !label.is_empty() {
+        root[0..11].copy_from_slice(&volume);
+        root[11] = ATTR_VOLUME_ID;
+    }
+    store_verified(dev, root_start, &root)?;
+    for i in 1..root_sectors {
+        store_verified(dev, root_start + i, &empty)?;
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test_fat12 {
+    use super::*;
+    use crate::mock::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    /** A disk image in memory */
+    struct RamDisk {
+        image: Vec<u8>,
+    }
+
+    impl BlockDevice for RamDisk {
+        fn read_block(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), FddError> {
+            let offset = lba as usize * 512;
+            if offset + 512 > self.image.len() {
+                return Err(FddError::InvalidArgument);
+            }
+            buf.copy_from_slice(&self.image[offset..offset + 512]);
+            return Ok(());
+        }
+
+        fn write_block(&mut self, lba: u32, data: &[u8]) -> Result<(), FddError> {
+            let offset = lba as usize * 512;
+            if offset + 512 > self.image.len() {
+                return Err(FddError::InvalidArgument);
+            }
+            self.image[offset..offset + 512].copy_from_slice(data);
+            return Ok(());
+        }
+    }
+
+    fn mount_image() -> Fat12<RamDisk> {
+        return Fat12::mount(RamDisk {
+            image: fat12_image(),
+        })
+        .unwrap();
+    }
+
+    fn read_all<D: BlockDevice>(fs: &mut Fat12<D>, entry: &DirEntry) -> Vec<u8> {
+        let mut file = fs.open_file(entry).unwrap();
+        let mut contents = vec![0u8; entry.size as usize];
+        assert_eq!(fs.read(&mut file, &mut contents), Ok(entry.size as usize));
+        return contents;
+    }
+
+    /** Both copies of the FAT must always agree */
+    fn assert_fats_match(fs: Fat12<RamDisk>) {
+        let disk = fs.unmount();
+        assert_eq!(disk.image[512..10 * 512], disk.image[10 * 512..19 * 512]);
+    }
+
+    fn set_fat(image: &mut [u8], cluster: u16, value: u16) {
+        for fat in 0..2 {
+            let offset = 512 + fat * 9 * 512 + cluster as usize * 3 / 2;
+            if cluster & 1 == 1 {
+                image[offset] = (image[offset] & 0x0F) | ((value << 4) as u8);
+                image[offset + 1] = (value >> 4) as u8;
+            } else {
+                image[offset] = value as u8;
+                image[offset + 1] = (image[offset + 1] & 0xF0) | ((value >> 8) as u8 & 0x0F);
+            }
+        }
+    }
+
+    fn put_entry(
+        image: &mut [u8],
+        offset: usize,
+        name: &[u8; 11],
+        attr: u8,
+        cluster: u16,
+        size: u32,
+    ) {
+        image[offset..offset + 11].copy_from_slice(name);
+        image[offset + 11] = attr;
+        image[offset + 26..offset + 28].copy_from_slice(&cluster.to_le_bytes());
+        image[offset + 28..offset + 32].copy_from_slice(&size.to_le_bytes());
+    }
+
+    fn put_long_name(image: &mut [u8], offset: usize, name: &str, short: &[u8; 11]) -> usize {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let parts = (units.len() + 12) / 13;
+        let mut at = offset;
+        for part in (0..parts).rev() {
+            image[at] = (part + 1) as u8 | if part + 1 == parts { 0x40 } else { 0 };
+            image[at + 11] = ATTR_LONG_NAME;
+            image[at + 13] = lfn_checksum(short);
+            for (i, field) in LFN_OFFSETS.iter().enumerate() {
+                let index = part * 13 + i;
+                let unit = match index {
+                    _ if index < units.len() => units[index],
+                    _ if index == units.len() => 0x0000,
+                    _ => 0xFFFF,
+                };
+                image[at + field..at + field + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            at += DIR_ENTRY_SIZE;
+        }
+        return at;
+    }
+
+    /**
+     * A 1.44M volume holding:
+     *   HELLO.TXT (1000 bytes in clusters 2 and 3)
+     *   DOCS/
+     *     A long file name.txt (10 bytes in cluster 5)
+     *     BIG.BIN (2000 bytes in clusters 342, 341, 6, 7)
+     */
+    fn fat12_image() -> Vec<u8> {
+        let mut image = vec![0u8; 2880 * 512];
+        let boot = [
+            0xEB, 0x3C, 0x90, b'M', b'S', b'D', b'O', b'S', b'5', b'.', b'0', 0x00, 0x02, 1, 1, 0,
+            2, 224, 0, 0x40, 0x0B, 0xF0, 9, 0, 18, 0, 2, 0,
+        ];
+        image[0..boot.len()].copy_from_slice(&boot);
+        image[38] = 0x29;
+        image[39..43].copy_from_slice(&0x1234ABCDu32.to_le_bytes());
+        image[43..54].copy_from_slice(b"TESTDISK   ");
+        image[510] = 0x55;
+        image[511] = 0xAA;
+
+        set_fat(&mut image, 0, 0xFF0);
+        set_fat(&mut image, 1, 0xFFF);
+        set_fat(&mut image, 2, 3);
+        set_fat(&mut image, 3, 0xFFF);
+        set_fat(&mut image, 4, 0xFFF);
+        set_fat(&mut image, 5, 0xFFF);
+        set_fat(&mut image, 342, 341);
+        set_fat(&mut image, 341, 6);
+        set_fat(&mut image, 6, 7);
+        set_fat(&mut image, 7, 0xFFF);
+
+        let root = 19 * 512;
+        put_entry(&mut image, root, b"TESTDISK   ", ATTR_VOLUME_ID, 0, 0);
+        put_entry(&mut image, root + 32, b"HELLO   TXT", ATTR_ARCHIVE, 2, 1000);
+        image[root + 64] = 0xE5;
+        put_entry(&mut image, root + 96, b"DOCS       ", ATTR_DIRECTORY, 4, 0);
+
+        let data = |cluster: usize| (33 + cluster - 2) * 512;
+        for i in 0..1000 {
+            image[data(2) + i] = (i % 251) as u8;
+        }
+
+        let docs = data(4);
+        put_entry(&mut image, docs, b".          ", ATTR_DIRECTORY, 4, 0);
+        put_entry(&mut image, docs + 32, b"..         ", ATTR_DIRECTORY, 0, 0);
+        let short = b"ALONGF~1TXT";
+        let at = put_long_name(&mut image, docs + 64, "A long file name.txt", short);
+        put_entry(&mut image, at, short, ATTR_ARCHIVE, 5, 10);
+        put_entry(&mut image, at + 32, b"BIG     BIN", ATTR_ARCHIVE, 342, 2000);
+
+        image[data(5)..data(5) + 10].copy_from_slice(b"0123456789");
+        for (n, cluster) in [342, 341, 6, 7].iter().enumerate() {
+            image[data(*cluster)..data(*cluster) + 512].fill(n as u8 + 1);
+        }
+
+        return image;
+    }
+
+    fn names<D: BlockDevice>(fs: &mut Fat12<D>, dir: Dir) -> Vec<std::string::String> {
+        let mut cursor = DirCursor::new(dir);
+        let mut names = Vec::new();
+        while let Some(entry) = fs.next_entry(&mut cursor).unwrap() {
+            let mut buf = [0u8; 256];
+            let len = entry.display_name(&mut buf);
+            names.push(std::string::String::from_utf8(buf[0..len].to_vec()).unwrap());
+        }
+        return names;
+    }
+
+    #[test]
+    pub fn test_bpb() {
+        let fs = Fat12::mount(RamDisk {
+            image: fat12_image(),
+        })
+        .unwrap();
+        let bpb = fs.bpb();
+        assert_eq!(bpb.fat_start, 1);
+        assert_eq!(bpb.root_start, 19);
+        assert_eq!(bpb.data_start, 33);
+        assert_eq!(bpb.clusters, 2847);
+        assert_eq!(bpb.serial, 0x1234ABCD);
+        assert_eq!(&bpb.label, b"TESTDISK   ");
+
+        let blank = RamDisk {
+            image: vec![0u8; 2880 * 512],
+        };
+        assert_eq!(Fat12::mount(blank).err(), Some(FatError::NotFat));
+    }
+
+    #[test]
+    pub fn test_fat_chain() {
+        let mut fs = Fat12::mount(RamDisk {
+            image: fat12_image(),
+        })
+        .unwrap();
+
+        assert_eq!(fs.fat_entry(2), Ok(3));
+        assert_eq!(fs.next_cluster(3), Ok(None));
+
+        // Cluster 341 straddles the first and second FAT sector
+        assert_eq!(fs.fat_entry(341), Ok(6));
+        assert_eq!(fs.fat_entry(342), Ok(341));
+        assert_eq!(fs.next_cluster(8), Err(FatError::Corrupt));
+    }
+
+    #[test]
+    pub fn test_directories() {
+        let mut fs = Fat12::mount(RamDisk {
+            image: fat12_image(),
+        })
+        .unwrap();
+
+        let root = fs.root();
+        assert_eq!(names(&mut fs, root), ["HELLO.TXT", "DOCS"]);
+
+        let mut entry = fs.open("/docs").unwrap();
+        let docs = fs.open_dir(&entry).unwrap();
+        assert_eq!(
+            names(&mut fs, docs),
+            [".", "..", "A long file name.txt", "BIG.BIN"]
+        );
+
+        entry.cluster = 1;
+        assert_eq!(fs.open_dir(&entry).err(), Some(FatError::Corrupt));
+        entry.cluster = fs.bpb.clusters as u16 + 2;
+        assert_eq!(fs.open_dir(&entry).err(), Some(FatError::Corrupt));
+
+        let entry = fs.open("DOCS/a long FILE name.txt").unwrap();
+        assert_eq!(entry.size, 10);
+        assert!(fs.open("DOCS/ALONGF~1.TXT").is_ok());
+        assert_eq!(fs.open("DOCS/missing").err(), Some(FatError::NotFound));
+        assert_eq!(fs.open("HELLO.TXT/x").err(), Some(FatError::NotADirectory));
+    }
+
+    #[test]
+    pub fn test_read_file() {
+        let image = fat12_image();
+        let mut fs = Fat12::mount(RamDisk {
+            image: image.clone(),
+        })
+        .unwrap();
+
+        // Small reads across the cluster boundary
+        let entry = fs.open("HELLO.TXT").unwrap();
+        let mut file = fs.open_file(&entry).unwrap();
+        let mut contents = Vec::new();
+        let mut buf = [0u8; 100];
+        loop {
+            let count = fs.read(&mut file, &mut buf).unwrap();
+            if count == 0 {
+                break;
+            }
+            contents.extend_from_slice(&buf[0..count]);
+        }
+        assert_eq!(contents[..], image[33 * 512..33 * 512 + 1000]);
+
+        let entry = fs.open("DOCS/BIG.BIN").unwrap();
+        let mut file = fs.open_file(&entry).unwrap();
+        let mut buf = [0u8; 4096];
+        assert_eq!(fs.read(&mut file, &mut buf), Ok(2000));
+        assert_eq!(buf[0..512], [1; 512]);
+        assert_eq!(buf[512..1024], [2; 512]);
+        assert_eq!(buf[1024..1536], [3; 512]);
+        assert_eq!(buf[1536..2000], [4; 464]);
+
+        let docs = fs.open("DOCS").unwrap();
+        assert_eq!(fs.open_file(&docs).err(), Some(FatError::IsADirectory));
+    }
+
+    #[test]
+    pub fn test_read_from_drive() {
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &fat12_image());
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        let mut fs = Fat12::mount(drive).unwrap();
+        let entry = fs.open("DOCS/A long file name.txt").unwrap();
+        let mut file = fs.open_file(&entry).unwrap();
+        let mut buf = [0u8; 32];
+        assert_eq!(fs.read(&mut file, &mut buf), Ok(10));
+        assert_eq!(&buf[0..10], b"0123456789");
+    }
+
+    #[test]
+    pub fn test_short_names() {
+        assert_eq!(to_short_name("config.ini"), Ok(*b"CONFIG  INI"));
+        assert_eq!(to_short_name("README"), Ok(*b"README     "));
+        assert_eq!(to_short_name("toolongname.txt"), Err(FatError::InvalidName));
+        assert_eq!(to_short_name("a.b.c"), Err(FatError::InvalidName));
+        assert_eq!(to_short_name("file.text"), Err(FatError::InvalidName));
+        assert_eq!(to_short_name(".txt"), Err(FatError::InvalidName));
+        assert_eq!(to_short_name("a b.txt"), Err(FatError::InvalidName));
+
+        let stamp = Timestamp {
+            year: 2024,
+            month: 3,
+            day: 15,
+            hour: 13,
+            minute: 45,
+            second: 31,
+        };
+        assert_eq!(stamp.to_fat(), (0x6DAF, 0x586F));
+    }
+
+    #[test]
+    pub fn test_write_file() {
+        let mut fs = mount_image();
+        let stamp = Timestamp {
+            year: 2024,
+            month: 3,
+            day: 15,
+            hour: 13,
+            minute: 45,
+            second: 31,
+        };
+        fs.set_time(stamp);
+
+        let data: Vec<u8> = (0..1500).map(|i| (i * 3) as u8).collect();
+        let root = fs.root();
+        fs.write_file(root, "config.ini", &data).unwrap();
+
+        let entry = fs.open("CONFIG.INI").unwrap();
+        assert_eq!(entry.size, 1500);
+        assert_eq!((entry.time, entry.date), stamp.to_fat());
+        assert_eq!(read_all(&mut fs, &entry), data);
+
+        // Overwriting releases the old clusters
+        let hello = fs.open("HELLO.TXT").unwrap();
+        fs.write_file(root, "hello.txt", b"replaced").unwrap();
+        assert_eq!(fs.fat_entry(hello.cluster), Ok(0));
+        assert_eq!(fs.fat_entry(hello.cluster + 1), Ok(0));
+        let hello = fs.open("HELLO.TXT").unwrap();
+        assert_eq!(read_all(&mut fs, &hello), b"replaced");
+
+        // Empty files don't own any clusters
+        let empty = fs.write_file(root, "EMPTY", &[]).unwrap();
+        assert_eq!(empty.cluster, 0);
+        assert_eq!(fs.open("EMPTY").unwrap().size, 0);
+        assert_fats_match(fs);
+    }
+
+    #[test]
+    pub fn test_directories_write() {
+        let mut fs = mount_image();
+        let root = fs.root();
+        let new = fs.create_dir(root, "new").unwrap();
+        assert_eq!(fs.create_dir(root, "NEW"), Err(FatError::Exists));
+        assert_eq!(names(&mut fs, new), [".", ".."]);
+
+        // Enough files to spill the directory into a second cluster
+        for i in 0..20 {
+            let name = std::format!("FILE{}.TXT", i);
+            fs.write_file(new, &name, name.as_bytes()).unwrap();
+        }
+        assert_eq!(names(&mut fs, new).len(), 22);
+        let last = fs.open("NEW/FILE19.TXT").unwrap();
+        assert_eq!(read_all(&mut fs, &last), b"FILE19.TXT");
+
+        assert_eq!(fs.remove(root, "NEW"), Err(FatError::NotEmpty));
+        for i in 0..20 {
+            fs.remove(new, &std::format!("FILE{}.TXT", i)).unwrap();
+        }
+        fs.remove(root, "NEW").unwrap();
+        assert_eq!(names(&mut fs, root), ["HELLO.TXT", "DOCS"]);
+        assert_eq!(fs.fat_entry(new.cluster), Ok(0));
+
+        // Long names go away along with their 8.3 entry
+        let docs = fs.open("DOCS").unwrap();
+        let docs = fs.open_dir(&docs).unwrap();
+        fs.remove(docs, "A long file name.txt").unwrap();
+        assert_eq!(names(&mut fs, docs), [".", "..", "BIG.BIN"]);
+        assert_eq!(fs.remove(docs, ".."), Err(FatError::InvalidName));
+        assert_fats_match(fs);
+    }
+
+    /** Counts the writes which land in the FATs */
+    struct FatWrites {
+        disk: RamDisk,
+        writes: usize,
+    }
+
+    impl BlockDevice for FatWrites {
+        fn read_block(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), FddError> {
+            return self.disk.read_block(lba, buf);
+        }
+
+        fn write_block(&mut self, lba: u32, data: &[u8]) -> Result<(), FddError> {
+            if (1..19).contains(&lba) {
+                self.writes += 1;
+            }
+            return self.disk.write_block(lba, data);
+        }
+    }
+
+    #[test]
+    pub fn test_fat_batching() {
+        let mut fs = Fat12::mount(FatWrites {
+            disk: RamDisk {
+                image: fat12_image(),
+            },
+            writes: 0,
+        })
+        .unwrap();
+
+        // 20 clusters, all of them in the first FAT sector
+        let root = fs.root();
+        let data = vec![0x5Au8; 20 * 512];
+        let entry = fs.write_file(root, "TWENTY.BIN", &data).unwrap();
+        assert_eq!(fs.device().writes, 2);
+        assert_eq!(read_all(&mut fs, &entry), data);
+
+        // Replacing it frees the old chain with one more write per copy
+        fs.write_file(root, "TWENTY.BIN", b"short").unwrap();
+        assert_eq!(fs.device().writes, 6);
+
+        // A failed write leaves the FAT alone
+        let huge = vec![0x11u8; 2847 * 512];
+        assert!(fs.write_file(root, "HUGE.BIN", &huge).is_err());
+        assert_eq!(fs.device().writes, 6);
+        assert_fats_match(Fat12::mount(fs.unmount().disk).unwrap());
+    }
+
+    #[test]
+    pub fn test_disk_full() {
+        let mut fs = mount_image();
+        let root = fs.root();
+        let huge = vec![0x11u8; 2847 * 512];
+        assert_eq!(
+            fs.write_file(root, "HUGE.BIN", &huge).err(),
+            Some(FatError::DiskFull)
+        );
+
+        // Nothing was leaked by the failed write
+        let fits = vec![0x22u8; (2847 - 9) * 512];
+        assert!(fs.write_file(root, "FITS.BIN", &fits).is_ok());
+    }
+
+    #[test]
+    pub fn test_write_to_drive() {
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &fat12_image());
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        let mut fs = Fat12::mount(drive).unwrap();
+        let root = fs.root();
+        fs.write_file(root, "SHOP.CFG", b"speed=3").unwrap();
+
+        // Start over from the media to be sure it really landed
+        let mut fs = Fat12::mount(fs.unmount()).unwrap();
+        let entry = fs.open("SHOP.CFG").unwrap();
+        assert_eq!(read_all(&mut fs, &entry), b"speed=3");
+
+        fs.device().bus().write_protect = true;
+        assert_eq!(
+            fs.write_file(root, "NOPE.CFG", b"").err(),
+            Some(FatError::Device(FddError::WriteProtected))
+        );
+    }
+
+    #[test]
+    pub fn test_mkfs() {
+        let mut disk = RamDisk {
+            image: vec![0xF6u8; 2880 * 512],
+        };
+        mkfs_fat12(&mut disk, &GEOMETRY_1440K, "shop floor", 0xCAFEF00D).unwrap();
+
+        // The same BPB DOS writes for a 1.44M disk
+        assert_eq!(
+            disk.image[11..30],
+            [
+                0x00, 0x02, 0x01, 0x01, 0x00, 0x02, 0xE0, 0x00, 0x40, 0x0B, 0xF0, 0x09, 0x00, 0x12,
+                0x00, 0x02, 0x00, 0x00, 0x00
+            ]
+        );
+        assert_eq!(disk.image[510..512], [0x55, 0xAA]);
+
+        let mut fs = Fat12::mount(disk).unwrap();
+        assert_eq!(fs.bpb().clusters, 2847);
+        assert_eq!(fs.bpb().serial, 0xCAFEF00D);
+        assert_eq!(&fs.bpb().label, b"SHOP FLOOR ");
+        assert_eq!(fs.fat_entry(0), Ok(0xFF0));
+        assert_eq!(fs.fat_entry(1), Ok(0xFFF));
+        assert_eq!(fs.fat_entry(2), Ok(0));
+
+        let root = fs.root();
+        assert_eq!(names(&mut fs, root).len(), 0);
+        fs.write_file(root, "A.TXT", b"hello").unwrap();
+        assert_eq!(names(&mut fs, root), ["A.TXT"]);
+        assert_fats_match(fs);
+    }
+
+    #[test]
+    pub fn test_mkfs_presets() {
+        // (geometry, media, sectors per cluster, root entries, sectors per fat)
+        let presets = [
+            (GEOMETRY_720K, 0xF9, 2, 112, 3),
+            (GEOMETRY_1200K, 0xF9, 1, 224, 7),
+            (GEOMETRY_360K, 0xFD, 2, 112, 2),
+        ];
+
+        for (geometry, media, spc, root, spf) in presets {
+            let mut disk = RamDisk {
+                image: vec![0u8; geometry.blocks() as usize * 512],
+            };
+            mkfs_fat12(&mut disk, &geometry, "", 1).unwrap();
+
+            let fs = Fat12::mount(disk).unwrap();
+            let bpb = fs.bpb();
+            assert_eq!(bpb.media, media);
+            assert_eq!(bpb.sectors_per_cluster, spc);
+            assert_eq!(bpb.root_entries, root);
+            assert_eq!(bpb.sectors_per_fat, spf);
+            assert_eq!(bpb.total_sectors, geometry.blocks());
+            assert_eq!(&bpb.label, b"NO NAME    ");
+        }
+
+        let mut disk = RamDisk {
+            image: vec![0u8; 2880 * 512],
+        };
+        assert_eq!(
+            mkfs_fat12(&mut disk, &GEOMETRY_1440K, "bad.label", 1),
+            Err(FatError::InvalidName)
+        );
+    }
+
+    #[test]
+    pub fn test_mkfs_on_drive() {
+        // A freshly low-level formatted disk
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &vec![0xF6u8; 2880 * 512]);
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        mkfs_fat12(&mut drive, &GEOMETRY_1440K, "FLOPPY", 7).unwrap();
+        let mut fs = Fat12::mount(drive).unwrap();
+        let root = fs.root();
+        fs.write_file(root, "BOOT.CFG", b"ok").unwrap();
+        let entry = fs.open("BOOT.CFG").unwrap();
+        assert_eq!(read_all(&mut fs, &entry), b"ok");
+    }
+}
@@ -1,14 +1,105 @@
 #![allow(unused)]
 
-use crate::config::*;
+use crate::bus::*;
+use crate::crc::*;
+use crate::geometry::*;
 use crate::mfm;
 use crate::mfm::*;
 use core::arch::asm;
 use teensycore::prelude::*;
 
-static mut FLOPPY_SIDE: u8 = 0;
-static mut FLOPPY_TRACK: u8 = 0;
-static mut FLOPPY_MOTOR_ON: bool = false;
+/** Bytes of 0x4E written after the data crc */
+const WRITE_GAP_LEN: usize = 4;
+
+/** Worst case flux signals for a data field (8 per byte, plus the lead-in) */
+const WRITE_FLUX_LEN: usize = 1 + 8 * (1 + 512 + 2 + WRITE_GAP_LEN);
+
+/** How many times the head may land on the wrong cylinder */
+const MAX_TRACK_FIXUPS: usize = 3;
+
+/** How long to wait for an index pulse before giving up */
+const INDEX_TIMEOUT: uNano = 1000 * MS_TO_NANO;
+
+/**
+ * How far off the speed of the geometry the disk may turn and
+ * still be written, in percent.
+ */
+const SPEED_TOLERANCE: uNano = 3;
+
+/** Raw bytes on the longest supported track, 500kbps at 300rpm */
+const MAX_TRACK_BYTES: usize = 12500;
+
+/** Worst case flux signals for a whole track (one per two bitcells) */
+pub const TRACK_FLUX_LEN: usize = MAX_TRACK_BYTES * 8;
+
+/** The most 512 byte sectors a track can hold (1.44M media) */
+pub const MAX_TRACK_SECTORS: usize = 18;
+
+/** How many index pulses read_sector waits through before giving up */
+const SECTOR_READ_REVOLUTIONS: usize = 36;
+
+/**
+ * How many index pulses a verify waits through. The sector comes
+ * back around on the next revolution, so two is plenty.
+ */
+const VERIFY_REVOLUTIONS: usize = 2;
+
+/** How many revolutions read_track spends filling in missing sectors */
+const TRACK_READ_REVOLUTIONS: usize = 8;
+
+/**
+ * The furthest a transition may be shifted to precompensate, in
+ * nanoseconds at 500kbps. A quarter of a bit cell still leaves the
+ * shortest pulse well above what the write loop can produce.
+ */
+const MAX_PRECOMPENSATION: u32 = 250;
+
+/**
+ * Everything that can go wrong while talking to the drive.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FddError {
+    /// The drive never produced an index pulse
+    NoIndex,
+    /// The track 0 sensor never triggered while seeking
+    Track00NotFound,
+    /// The requested sector ID was never seen
+    SectorNotFound,
+    /// The sector ID was found but its CRC did not match
+    IdCrc,
+    /// The data field was found but its CRC did not match
+    DataCrc,
+    /// The media has its write protect tab set
+    WriteProtected,
+    /// The motor hasn't been spun up
+    MotorOff,
+    /// The head kept landing on the wrong cylinder
+    WrongCylinder,
+    /// A parameter was out of range (e.g. too much data)
+    InvalidArgument,
+    /// The track has no flux that looks like MFM
+    NoFlux,
+    /// A written sector read back with the wrong data
+    VerifyFailed,
+}
+
+impl FddError {
+    pub fn message(&self) -> &'static [u8] {
+        return match self {
+            FddError::NoIndex => b"no index pulse",
+            FddError::Track00NotFound => b"track 0 not found",
+            FddError::SectorNotFound => b"sector not found",
+            FddError::IdCrc => b"ID field failed its CRC",
+            FddError::DataCrc => b"data field failed its CRC",
+            FddError::WriteProtected => b"media is write protected",
+            FddError::MotorOff => b"motor is not up to speed",
+            FddError::WrongCylinder => b"head keeps landing on the wrong cylinder",
+            FddError::InvalidArgument => b"invalid argument",
+            FddError::NoFlux => b"no readable flux on the track",
+            FddError::VerifyFailed => b"sector read back wrong after writing",
+        };
+    }
+}
 
 #[repr(C)]
 pub struct SectorID {
@@ -38,415 +129,1671 @@ impl SectorID {
 }
 
 /**
- * This is a total hack. It reads directly from the gpio register for pin 3.
- * Bypassing the pin_read method of teensycore because it's too slow.
+ * What read_track knows about a sector.
  */
-#[no_mangle]
-#[link_section = ".text"]
-#[inline(never)]
-pub fn fdd_read_index() -> u32 {
-    unsafe {
-        return *(addrs::GPIO9 as *mut u32) & (0x1 << 5);
-    }
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SectorStatus {
+    /// The ID field was never seen
+    Missing,
+    /// The ID field was seen but its CRC did not match
+    IdCrc,
+    /// The data field was seen but its CRC did not match
+    DataCrc,
+    /// The sector was read and verified
+    Good,
 }
 
 /**
- * True if the media is write protected
+ * Every sector of a single track, as collected by read_track.
  */
-pub fn fdd_read_write_protect() -> bool {
-    return pin_read(WRITE_PROTECT_PIN) > 0;
+pub struct TrackBuffer {
+    /// How many sectors the track is expected to hold
+    pub sectors: u8,
+    /// The number of the first sector on the track
+    pub first_sector: u8,
+    /// What was found for each sector, in sector number order
+    pub status: [SectorStatus; MAX_TRACK_SECTORS],
+    /// The data of each sector, in sector number order
+    pub data: [[u8; 512]; MAX_TRACK_SECTORS],
 }
 
-/**
- * True if the device is oriented on track0
- */
-fn fdd_sense_track00() -> bool {
-    return pin_read(TRACK00_PIN) == 0;
-}
+impl TrackBuffer {
+    /**
+     * A buffer for tracks of the given geometry. Only the first
+     * MAX_TRACK_SECTORS fit, and read_track refuses geometries
+     * with more.
+     */
+    pub fn new(geometry: &Geometry) -> Self {
+        return TrackBuffer {
+            sectors: geometry.sectors_per_track.min(MAX_TRACK_SECTORS as u8),
+            first_sector: geometry.first_sector,
+            status: [SectorStatus::Missing; MAX_TRACK_SECTORS],
+            data: [[0; 512]; MAX_TRACK_SECTORS],
+        };
+    }
 
-/**
- * Make the drive inactive
- */
-fn fdd_drive_deselect() {
-    pin_out(DRIVE_PIN, Power::High);
-    wait_exact_ns(MS_TO_NANO * 500);
-}
+    /**
+     * Forget everything that was read so the buffer can be
+     * used for another track.
+     */
+    pub fn clear(&mut self) {
+        self.status = [SectorStatus::Missing; MAX_TRACK_SECTORS];
+    }
+
+    /**
+     * Where a sector number lives in the buffer, if at all.
+     */
+    fn slot(&self, sector: u8) -> Option<usize> {
+        let slot = sector.wrapping_sub(self.first_sector) as usize;
+        if slot < self.sectors as usize {
+            return Some(slot);
+        }
 
-/** Make the drive active */
-fn fdd_drive_select() {
-    fdd_drive_deselect();
+        return None;
+    }
+
+    /**
+     * The data of a sector, if it was read successfully.
+     */
+    pub fn sector(&self, sector: u8) -> Option<&[u8; 512]> {
+        let slot = self.slot(sector)?;
+        if self.status[slot] == SectorStatus::Good {
+            return Some(&self.data[slot]);
+        }
+
+        return None;
+    }
+
+    /**
+     * How many sectors have been read successfully.
+     */
+    pub fn good(&self) -> usize {
+        return self.status[0..self.sectors as usize]
+            .iter()
+            .filter(|status| **status == SectorStatus::Good)
+            .count();
+    }
 
-    pin_out(DRIVE_PIN, Power::Low);
-    wait_exact_ns(MS_TO_NANO * 500);
+    /**
+     * True once every sector has been read successfully.
+     */
+    pub fn complete(&self) -> bool {
+        return self.good() == self.sectors as usize;
+    }
 }
 
 /**
- * Initialize the floppy driver. Configuring pull-ups and
- * setting a default value.
+ * True if the byte is a data address mark (normal or deleted)
  */
-pub fn fdd_init() {
-    // Create a generic configuration for normal pins
-    let generic_config: PadConfig = PadConfig {
-        hysterisis: false,
-        resistance: PullUpDown::PullUp100k,
-        pull_keep: PullKeep::Keeper,
-        pull_keep_en: false,
-        open_drain: false,
-        speed: PinSpeed::Max200MHz,
-        drive_strength: DriveStrength::Max,
-        fast_slew_rate: true,
-    };
-
-    pin_pad_config(GATE_PIN, generic_config.clone());
-    pin_pad_config(DIR_PIN, generic_config.clone());
-    pin_pad_config(STEP_PIN, generic_config.clone());
-    pin_pad_config(HEAD_SEL_PIN, generic_config.clone());
-    pin_pad_config(DRIVE_PIN, generic_config.clone());
-    pin_pad_config(MOTOR_PIN, generic_config.clone());
-    pin_pad_config(WRITE_PIN, generic_config.clone());
-
-    pin_out(DRIVE_PIN, Power::High);
-    pin_out(MOTOR_PIN, Power::High);
-    pin_out(DIR_PIN, Power::High);
-    pin_out(STEP_PIN, Power::High);
-    pin_out(HEAD_SEL_PIN, Power::High);
-    pin_out(GATE_PIN, Power::High);
-    pin_out(WRITE_PIN, Power::High);
-
-    pin_mode(DIR_PIN, Mode::Output);
-    pin_mode(STEP_PIN, Mode::Output);
-    pin_mode(GATE_PIN, Mode::Output);
-    pin_mode(HEAD_SEL_PIN, Mode::Output);
-    pin_mode(WRITE_PIN, Mode::Output);
-    pin_mode(DRIVE_PIN, Mode::Output);
-    pin_mode(MOTOR_PIN, Mode::Output);
-
-    // Create a generic configuration for pullup resistors
-    let pullup_config: PadConfig = PadConfig {
-        hysterisis: false,
-        resistance: PullUpDown::PullUp22k,
-        pull_keep: PullKeep::Pull,
-        pull_keep_en: true,
-        open_drain: true,
-        speed: PinSpeed::Max200MHz,
-        drive_strength: DriveStrength::Max,
-        fast_slew_rate: true,
-    };
-
-    // Set them to outputs
-    pin_mode(INDEX_PIN, Mode::Input);
-    pin_mode(TRACK00_PIN, Mode::Input);
-    pin_mode(WRITE_PROTECT_PIN, Mode::Input);
-    pin_mode(READY_PIN, Mode::Input);
-    pin_mode(READ_PIN, Mode::Input);
-    pin_pad_config(INDEX_PIN, pullup_config.clone());
-    pin_pad_config(TRACK00_PIN, pullup_config.clone());
-    pin_pad_config(WRITE_PROTECT_PIN, pullup_config.clone());
-    pin_pad_config(READY_PIN, pullup_config.clone());
-    pin_pad_config(READ_PIN, pullup_config.clone());
+fn is_data_mark(byte: u8) -> bool {
+    return byte == 0xFB || byte == 0xF8;
 }
 
 /**
- * Change the state of the motor.
+ * A floppy drive and everything we know about its current state.
  */
-pub fn fdd_set_motor(on: bool) {
-    let motor_active = unsafe { FLOPPY_MOTOR_ON };
+pub struct FloppyDrive<B: FloppyBus> {
+    bus: B,
+    side: u8,
+    track: u8,
+    motor_on: bool,
+    data_rate: DataRate,
+    geometry: Geometry,
+    track_calibration: bool,
+    calibrated: Option<(u8, u8)>,
+    precompensation: (u8, u32),
+    write_verify: Option<u8>,
+    last_index: Option<uNano>,
+    /// Whether the disk was timed at the right speed since the motor started
+    up_to_speed: bool,
+}
 
-    // If the motor is unchanged, don't do anything
-    if on == motor_active {
-        return;
+impl<B: FloppyBus> FloppyDrive<B> {
+    /**
+     * True if the media is write protected
+     */
+    pub fn read_write_protect(&mut self) -> bool {
+        return self.bus.write_protected();
     }
 
-    if on {
-        pin_out(MOTOR_PIN, Power::Low);
-        fdd_drive_select();
-        match fdd_seek_track00() {
-            None => {
-                debug_str(b"Failed power-on calibration");
+    /**
+     * True while the index pulse is active. Remembers when it was
+     * last seen, so writes can tell the disk is still turning.
+     */
+    fn sense_index(&mut self) -> bool {
+        let active = self.bus.index();
+        if active {
+            self.last_index = Some(self.bus.nanos());
+        }
+        return active;
+    }
+
+    /**
+     * True if the device is oriented on track0
+     */
+    fn sense_track00(&mut self) -> bool {
+        return self.bus.track00();
+    }
+
+    /**
+     * Make the drive inactive
+     */
+    fn drive_deselect(&mut self) {
+        self.bus.set_select(false);
+        self.bus.wait_ns(MS_TO_NANO * 500);
+    }
+
+    /** Make the drive active */
+    fn drive_select(&mut self) {
+        self.drive_deselect();
+
+        self.bus.set_select(true);
+        self.bus.wait_ns(MS_TO_NANO * 500);
+    }
+
+    /**
+     * Take ownership of the bus and put the drive in a known state.
+     */
+    pub fn new(mut bus: B) -> Self {
+        bus.set_data_rate(DataRate::Kbps500);
+
+        return FloppyDrive {
+            bus: bus,
+            side: 0,
+            track: 0,
+            motor_on: false,
+            data_rate: DataRate::Kbps500,
+            geometry: GEOMETRY_1440K,
+            track_calibration: false,
+            calibrated: None,
+            precompensation: (0, 0),
+            write_verify: None,
+            last_index: None,
+            up_to_speed: false,
+        };
+    }
+
+    /**
+     * Direct access to the underlying bus.
+     */
+    pub fn bus(&mut self) -> &mut B {
+        return &mut self.bus;
+    }
+
+    /**
+     * Select the data rate of the inserted media. This changes how
+     * flux transitions are classified and how long written pulses are.
+     */
+    pub fn set_data_rate(&mut self, rate: DataRate) {
+        self.data_rate = rate;
+        self.calibrated = None;
+
+        self.bus.set_data_rate(rate);
+    }
+
+    /**
+     * The data rate the drive is currently reading and writing at.
+     */
+    pub fn data_rate(&self) -> DataRate {
+        return self.data_rate;
+    }
+
+    /**
+     * Describe the inserted media. Addresses are validated against
+     * it and the drive switches to its data rate. The speed is
+     * checked again before the next write.
+     */
+    pub fn set_geometry(&mut self, geometry: &Geometry) {
+        self.geometry = *geometry;
+        self.set_data_rate(geometry.data_rate);
+        self.up_to_speed = false;
+    }
+
+    /**
+     * The layout of the inserted media.
+     */
+    pub fn geometry(&self) -> &Geometry {
+        return &self.geometry;
+    }
+
+    /**
+     * The cylinder the head is believed to be over.
+     */
+    pub fn cylinder(&self) -> u8 {
+        return self.track;
+    }
+
+    /**
+     * The currently selected head.
+     */
+    pub fn head(&self) -> u8 {
+        return self.side;
+    }
+
+    /**
+     * True if the motor is spinning and index pulses were seen.
+     */
+    pub fn motor_on(&self) -> bool {
+        return self.motor_on;
+    }
+
+    /**
+     * Change the state of the motor.
+     */
+    pub fn set_motor(&mut self, on: bool) -> Result<(), FddError> {
+        let motor_active = self.motor_on;
+
+        // If the motor is unchanged, don't do anything
+        if on == motor_active {
+            return Ok(());
+        }
+
+        let mut calibration = Ok(0);
+        self.up_to_speed = false;
+        if on {
+            self.bus.set_motor(true);
+            self.drive_select();
+            calibration = self.seek_track00();
+            match calibration {
+                Err(_) => {
+                    self.bus.debug_str(b"Failed power-on calibration");
+                }
+                Ok(_) => {
+                    self.bus.debug_str(b"Successfully calibrated track");
+                }
             }
-            Some(_) => {
-                debug_str(b"Successfully calibrated track");
+        } else {
+            self.drive_deselect();
+            self.bus.set_motor(false);
+        }
+
+        if !on {
+            self.bus.debug_str(b"Shutting down motor");
+
+            self.motor_on = false;
+            return Ok(());
+        }
+
+        self.bus.debug_str(b"Spinning up motor");
+        self.bus.debug_str(b"Waiting for index pulse...");
+        self.last_index = None;
+
+        let start = self.bus.nanos();
+        while !self.sense_index() && (self.bus.nanos() - start) < 10000 * MS_TO_NANO {
+            assembly!("nop");
+        }
+
+        if self.sense_index() {
+            self.bus.debug_str(b"Received index pulse!");
+            self.motor_on = true;
+        } else {
+            self.bus.debug_str(b"Did not receive index pulse");
+            self.bus.set_motor(false);
+            return Err(FddError::NoIndex);
+        }
+
+        return calibration.map(|_| ());
+    }
+
+    /**
+     * Change the active track.
+     */
+    pub fn step(&mut self, times: u8) {
+        for _ in 0..times {
+            self.bus.set_step(true);
+            self.bus.wait_ns(MS_TO_NANO * 3);
+            self.bus.set_step(false);
+            self.bus.wait_ns(MS_TO_NANO * 3);
+        }
+
+        self.bus.reset_separator();
+    }
+
+    fn step_dir(&mut self, inward: bool) {
+        self.bus.set_dir(inward);
+        self.bus.wait_ns(20 * MS_TO_NANO);
+    }
+
+    /**
+     * Seek to track 0.
+     */
+    pub fn seek_track00(&mut self) -> Result<usize, FddError> {
+        let mut cycles: usize = 0;
+
+        self.bus.debug_str(b"Seeking outwards...");
+        self.step_dir(false);
+        for _ in 0..120 {
+            if self.sense_track00() {
+                self.track = 0;
+                self.bus.wait_ns(MS_TO_NANO * 20);
+                return Ok(cycles);
+            }
+
+            cycles += 1;
+            self.step(1);
+        }
+
+        self.bus.debug_str(b"Seeking inwards...");
+        self.step_dir(true);
+        for _ in 0..20 {
+            if self.sense_track00() {
+                self.track = 0;
+                self.bus.wait_ns(MS_TO_NANO * 20);
+                return Ok(cycles);
             }
+
+            cycles += 1;
+            self.step(1);
+        }
+
+        return Err(FddError::Track00NotFound);
+    }
+
+    /**
+     * Navigate to a specific track
+     */
+    fn set_track(&mut self, track: u8) {
+        let cur = self.track;
+        if cur == track {
+            return;
+        } else if cur > track {
+            // Step right
+            self.step_dir(false);
+            self.step(cur - track);
+        } else {
+            // Step left
+            self.step_dir(true);
+            self.step(track - cur);
+        }
+
+        self.track = track;
+    }
+
+    fn fix_track(&mut self, desired_track: u8, sampled_track: u8) {
+        self.track = sampled_track;
+
+        self.set_track(desired_track);
+    }
+
+    /**
+     * Select which head reads and writes.
+     */
+    pub fn set_side(&mut self, side: u8) {
+        self.side = side;
+        self.bus.set_side(side);
+        self.bus.reset_separator();
+    }
+
+    /**
+     * Move the head to a cylinder. Seeking to cylinder 0
+     * recalibrates against the track 0 sensor. Cylinders past
+     * the end of the geometry are refused.
+     */
+    pub fn seek(&mut self, cylinder: u8) -> Result<(), FddError> {
+        if cylinder >= self.geometry.cylinders {
+            return Err(FddError::InvalidArgument);
+        }
+
+        if cylinder == 0 {
+            return self.seek_track00().map(|_| ());
         }
-    } else {
-        fdd_drive_deselect();
-        pin_out(MOTOR_PIN, Power::High);
+
+        self.set_track(cylinder);
+        return Ok(());
     }
 
-    if !on {
-        debug_str(b"Shutting down motor");
+    /**
+     * Wait for the leading edge of the index pulse.
+     */
+    pub fn wait_index(&mut self) -> Result<(), FddError> {
+        let start = self.bus.nanos();
+        while self.sense_index() {
+            if (self.bus.nanos() - start) > INDEX_TIMEOUT {
+                return Err(FddError::NoIndex);
+            }
+        }
 
-        unsafe {
-            FLOPPY_MOTOR_ON = false;
+        while !self.sense_index() {
+            if (self.bus.nanos() - start) > INDEX_TIMEOUT {
+                return Err(FddError::NoIndex);
+            }
         }
-        return;
+
+        return Ok(());
+    }
+
+    /**
+     * Time one revolution of the disk, from index to index.
+     */
+    pub fn revolution_time(&mut self) -> Result<uNano, FddError> {
+        self.wait_index()?;
+        let start = self.bus.nanos();
+        self.wait_index()?;
+        return Ok(self.bus.nanos() - start);
+    }
+
+    /**
+     * Measure the flux on the track under the head and classify
+     * from then on with limits derived from it. This copes with
+     * disks written, or read, on a drive that is off speed.
+     */
+    pub fn calibrate(&mut self) -> Result<PulseLimits, FddError> {
+        self.calibrated = Some((self.track, self.side));
+        self.wait_index()?;
+
+        let histogram = mfm_histogram(&mut self.bus, self.data_rate);
+        let limits = histogram.limits().ok_or(FddError::NoFlux)?;
+        self.bus.set_pulse_limits(&limits);
+        return Ok(limits);
     }
 
-    debug_str(b"Spinning up motor");
-    debug_str(b"Waiting for index pulse...");
+    /**
+     * Write with transitions shifted by `ns` to counter peak shift,
+     * on cylinders from `cylinder` inwards where the bits are packed
+     * tightest. An amount of 0 turns it off. Anything over a
+     * quarter of a bit cell at the current data rate is refused.
+     */
+    pub fn set_precompensation(&mut self, cylinder: u8, ns: u32) -> Result<(), FddError> {
+        if ns > self.data_rate.scale(MAX_PRECOMPENSATION) {
+            return Err(FddError::InvalidArgument);
+        }
 
-    let start = nanos();
-    while fdd_read_index() > 0 && (nanos() - start) < 10000 * MS_TO_NANO {
-        assembly!("nop");
+        self.precompensation = (cylinder, ns);
+        return Ok(());
     }
 
-    if fdd_read_index() == 0 {
-        debug_str(b"Received index pulse!");
-        unsafe {
-            FLOPPY_MOTOR_ON = true;
+    /**
+     * Hand the bus the precompensation for the cylinder under the
+     * head. The data rate may have gone up since it was set, so
+     * it is limited again here.
+     */
+    fn apply_precompensation(&mut self) {
+        let (cylinder, ns) = self.precompensation;
+        let ns = ns.min(self.data_rate.scale(MAX_PRECOMPENSATION));
+        match self.track >= cylinder {
+            true => self.bus.set_precompensation(ns),
+            false => self.bus.set_precompensation(0),
         }
-    } else {
-        debug_str(b"Did not receive index pulse");
-        pin_out(MOTOR_PIN, Power::High);
     }
-}
 
-/**
- * Change the active track.
- */
-pub fn fdd_step(times: u8) {
-    for _ in 0..times {
-        pin_out(STEP_PIN, Power::Low);
-        wait_exact_ns(MS_TO_NANO * 3);
-        pin_out(STEP_PIN, Power::High);
-        wait_exact_ns(MS_TO_NANO * 3);
+    /**
+     * Calibrate on every track that is read, rather than once for
+     * the whole disk. Costs a revolution each time the head moves.
+     */
+    pub fn set_track_calibration(&mut self, enabled: bool) {
+        self.track_calibration = enabled;
     }
-}
 
-fn fdd_step_dir(dir: Power) {
-    pin_out(DIR_PIN, dir);
-    wait_exact_ns(20 * MS_TO_NANO);
-}
+    /**
+     * Calibrate if the head has moved since last time and per-track
+     * calibration is on. A track which can't be calibrated is read
+     * with whatever limits came before.
+     */
+    fn calibrate_track(&mut self) {
+        if self.track_calibration && self.calibrated != Some((self.track, self.side)) {
+            self.calibrate().ok();
+        }
+    }
 
-/**
- * Seek to track 0.
- */
-pub fn fdd_seek_track00() -> Option<usize> {
-    let mut cycles: usize = 0;
-
-    debug_str(b"Seeking outwards...");
-    fdd_step_dir(Power::High);
-    for _ in 0..120 {
-        if fdd_sense_track00() {
-            unsafe {
-                FLOPPY_TRACK = 0;
+    /**
+     * Read the next sector ID which passes under the head,
+     * whichever sector it belongs to.
+     */
+    pub fn read_id(&mut self) -> Result<SectorID, FddError> {
+        let mut latch = false;
+        let mut revolutions = 0usize;
+        let mut id: [u8; 7] = [0; 7];
+        let start = self.bus.nanos();
+
+        while revolutions < 2 {
+            if self.bus.sync() && mfm_read_bytes(&mut self.bus, &mut id) && id[0] == 0xFE {
+                let mut result = SectorID::new();
+                result.id = id[0];
+                result.cylinder = id[1];
+                result.head = id[2];
+                result.sector = id[3];
+                result.size = id[4];
+                result.crc1 = ((id[5] as u16) << 8) | id[6] as u16;
+
+                if crc16_field(&id[0..5]) != result.crc1 {
+                    return Err(FddError::IdCrc);
+                }
+
+                return Ok(result);
+            }
+
+            if self.sense_index() {
+                if latch == false {
+                    latch = true;
+                    revolutions += 1;
+                }
+            } else {
+                latch = false;
+            }
+
+            if revolutions == 0 && (self.bus.nanos() - start) > INDEX_TIMEOUT {
+                return Err(FddError::NoIndex);
             }
-            wait_exact_ns(MS_TO_NANO * 20);
-            return Some(cycles);
         }
 
-        cycles += 1;
-        fdd_step(1);
+        return Err(FddError::SectorNotFound);
     }
 
-    debug_str(b"Seeking inwards...");
-    fdd_step_dir(Power::Low);
-    for _ in 0..20 {
-        if fdd_sense_track00() {
-            unsafe {
-                FLOPPY_TRACK = 0;
+    /**
+     * Read an entire sector
+     */
+    pub fn read_sector(
+        &mut self,
+        head: u8,
+        cylinder: u8,
+        sector: u8,
+    ) -> Result<SectorID, FddError> {
+        return self.read_sector_within(head, cylinder, sector, SECTOR_READ_REVOLUTIONS);
+    }
+
+    /**
+     * Read a sector, giving up once the given number of index
+     * pulses have gone by.
+     */
+    fn read_sector_within(
+        &mut self,
+        head: u8,
+        cylinder: u8,
+        sector: u8,
+        revolutions: usize,
+    ) -> Result<SectorID, FddError> {
+        if !self.geometry.contains(head, cylinder, sector) {
+            return Err(FddError::InvalidArgument);
+        }
+
+        self.set_track(cylinder);
+        self.set_side(head);
+        self.calibrate_track();
+
+        let mut latch = false;
+        let mut error = 0usize;
+        let mut fixups = 0usize;
+        let mut result = FddError::SectorNotFound;
+        let mut buf: [u8; 560] = [0; 560];
+        let mut ret = SectorID::new();
+        let offset = 45; // Overhead of the SectorID
+        let start = self.bus.nanos();
+        while error < revolutions {
+            if self.bus.sync() && mfm_read_bytes(&mut self.bus, &mut buf) {
+                // If we're on the wrong track, shimmy over to the correct one
+                if buf[0] == 0xFE && buf[1] != cylinder {
+                    fixups += 1;
+                    if fixups > MAX_TRACK_FIXUPS {
+                        return Err(FddError::WrongCylinder);
+                    }
+
+                    self.fix_track(cylinder, buf[1] as u8);
+                } else if buf[0] == 0xFE && buf[1] == cylinder && buf[2] == head && buf[3] == sector
+                {
+                    ret.id = buf[0];
+                    ret.cylinder = buf[1];
+                    ret.head = buf[2];
+                    ret.sector = buf[3];
+                    ret.size = buf[4];
+                    ret.crc1 = ((buf[5] as u16) << 8) | buf[6] as u16;
+                    ret.crc2 = ((buf[offset + 512] as u16) << 8) | buf[offset + 513] as u16;
+
+                    // Verify the ID field and the data field. If either one
+                    // is corrupt, try again on the next pass.
+                    if crc16_field(&buf[0..5]) != ret.crc1 {
+                        self.bus.debug_str(b"ID field CRC mismatch");
+                        result = FddError::IdCrc;
+                    } else if !is_data_mark(buf[offset - 1])
+                        || crc16_field(&buf[(offset - 1)..(offset + 512)]) != ret.crc2
+                    {
+                        self.bus.debug_str(b"Data field CRC mismatch");
+                        result = FddError::DataCrc;
+                    } else {
+                        // Copy the data
+                        for i in 0..512 {
+                            ret.data[i] = buf[i + offset];
+                        }
+
+                        return Ok(ret);
+                    }
+                }
+            }
+
+            if self.sense_index() {
+                if latch == false {
+                    latch = true;
+                    error += 1;
+                }
+            } else {
+                latch = false;
+            }
+
+            if error == 0 && (self.bus.nanos() - start) > INDEX_TIMEOUT {
+                return Err(FddError::NoIndex);
             }
-            wait_exact_ns(MS_TO_NANO * 20);
-            return Some(cycles);
         }
 
-        cycles += 1;
-        fdd_step(1);
+        return Err(result);
     }
 
-    return None;
-}
+    /**
+     * Read every sector of a track. Each ID and data field that
+     * passes under the head is decoded, so a clean track is read
+     * in a single revolution. Sectors which were missing or failed
+     * their CRC are picked up on following revolutions.
+     *
+     * The buffer reports the state of every sector even when an
+     * error is returned.
+     */
+    pub fn read_track(
+        &mut self,
+        head: u8,
+        cylinder: u8,
+        track: &mut TrackBuffer,
+    ) -> Result<(), FddError> {
+        if !self
+            .geometry
+            .contains(head, cylinder, self.geometry.first_sector)
+            || self.geometry.sectors_per_track as usize > MAX_TRACK_SECTORS
+        {
+            return Err(FddError::InvalidArgument);
+        }
 
-/**
- * Navigate to a specific track
- */
-fn fdd_set_track(track: u8) {
-    let cur = unsafe { FLOPPY_TRACK };
-    if cur == track {
-        return;
-    } else if cur > track {
-        // Step right
-        fdd_step_dir(Power::High);
-        fdd_step(cur - track);
-    } else {
-        // Step left
-        fdd_step_dir(Power::Low);
-        fdd_step(track - cur);
-    }
-
-    unsafe {
-        FLOPPY_TRACK = track;
-    }
-}
+        self.set_track(cylinder);
+        self.set_side(head);
+        self.calibrate_track();
+        track.clear();
+
+        let mut latch = false;
+        let mut revolutions = 0usize;
+        let mut fixups = 0usize;
+        let mut id: [u8; 7] = [0; 7];
+        let mut field: [u8; 515] = [0; 515];
+        let start = self.bus.nanos();
+
+        while revolutions < TRACK_READ_REVOLUTIONS && !track.complete() {
+            if self.bus.sync() && mfm_read_bytes(&mut self.bus, &mut id) && id[0] == 0xFE {
+                // If we're on the wrong track, shimmy over to the correct one
+                if id[1] != cylinder {
+                    fixups += 1;
+                    if fixups > MAX_TRACK_FIXUPS {
+                        return Err(FddError::WrongCylinder);
+                    }
+
+                    self.fix_track(cylinder, id[1]);
+                    continue;
+                }
+
+                let slot = match track.slot(id[3]) {
+                    Some(slot) if id[2] == head && track.status[slot] != SectorStatus::Good => slot,
+                    _ => continue,
+                };
+
+                let crc1 = ((id[5] as u16) << 8) | id[6] as u16;
+                if crc16_field(&id[0..5]) != crc1 {
+                    track.status[slot] = SectorStatus::IdCrc;
+                } else if self.bus.sync() && mfm_read_bytes(&mut self.bus, &mut field) {
+                    let crc2 = ((field[513] as u16) << 8) | field[514] as u16;
+                    if is_data_mark(field[0]) && crc16_field(&field[0..513]) == crc2 {
+                        track.data[slot].copy_from_slice(&field[1..513]);
+                        track.status[slot] = SectorStatus::Good;
+                    } else {
+                        track.status[slot] = SectorStatus::DataCrc;
+                    }
+                }
+            }
+
+            if self.sense_index() {
+                if latch == false {
+                    latch = true;
+                    revolutions += 1;
+                }
+            } else {
+                latch = false;
+            }
+
+            if revolutions == 0 && (self.bus.nanos() - start) > INDEX_TIMEOUT {
+                return Err(FddError::NoIndex);
+            }
+        }
+
+        if track.complete() {
+            return Ok(());
+        }
 
-fn fdd_fix_track(desired_track: u8, sampled_track: u8) {
-    unsafe {
-        FLOPPY_TRACK = sampled_track;
+        // Report the most specific problem
+        let status = &track.status[0..track.sectors as usize];
+        if status.contains(&SectorStatus::DataCrc) {
+            return Err(FddError::DataCrc);
+        } else if status.contains(&SectorStatus::IdCrc) {
+            return Err(FddError::IdCrc);
+        }
+
+        return Err(FddError::SectorNotFound);
     }
 
-    fdd_set_track(desired_track);
-}
+    /**
+     * Make sure it is safe to open the write gate: the media isn't
+     * protected, the motor is up to speed and the disk is turning.
+     * The first write after the motor starts times a revolution
+     * against the given rpm; writes for any rpm other than the one
+     * of the current geometry are timed every time. After that, if
+     * no index pulse was seen recently, wait for the next one.
+     */
+    fn check_writable(&mut self, rpm: u32) -> Result<(), FddError> {
+        if self.read_write_protect() {
+            self.bus.debug_str(b"ERROR: Media is write protected");
+            return Err(FddError::WriteProtected);
+        }
+
+        if !self.motor_on {
+            self.bus.debug_str(b"ERROR: Motor is not running");
+            return Err(FddError::MotorOff);
+        }
+
+        // Index pulses show up before the motor has settled, so time
+        // a revolution once after it starts
+        if !self.up_to_speed || rpm != self.geometry.rpm {
+            let time = self.revolution_time()?;
+            let expected = 60_000 * MS_TO_NANO / rpm as uNano;
+            if time.abs_diff(expected) > expected * SPEED_TOLERANCE / 100 {
+                self.bus
+                    .debug_str(b"ERROR: Disk is not turning at the right speed");
+                return Err(FddError::MotorOff);
+            }
+            if rpm == self.geometry.rpm {
+                self.up_to_speed = true;
+            }
+        }
+
+        let now = self.bus.nanos();
+        match self.last_index {
+            Some(seen) if now - seen <= INDEX_TIMEOUT => {}
+            _ => {
+                if self.wait_index().is_err() {
+                    self.bus
+                        .debug_str(b"ERROR: No index pulse, refusing to write");
+                    return Err(FddError::NoIndex);
+                }
+            }
+        }
 
-fn fdd_set_side(side: u8) {
-    if side == 0 {
-        pin_out(HEAD_SEL_PIN, Power::High);
-    } else {
-        pin_out(HEAD_SEL_PIN, Power::Low);
+        return Ok(());
     }
-}
 
-/**
- * Read an entire sector
- */
-pub fn fdd_read_sector(head: u8, cylinder: u8, sector: u8) -> Option<SectorID> {
-    fdd_set_track(cylinder);
-    fdd_set_side(head);
-
-    let mut latch = false;
-    let mut error = 0usize;
-    let mut buf: [u8; 560] = [0; 560];
-    let mut ret = SectorID::new();
-    let offset = 45; // Overhead of the SectorID
-    while error < 36 {
-        if (mfm_sync()) {
-            mfm_read_bytes(&mut buf);
-
-            // If we're on the wrong track, shimmy over to the correct one
-            if buf[0] == 0xFE && buf[1] != cylinder {
-                fdd_fix_track(cylinder, buf[1] as u8);
-            } else if buf[0] == 0xFE && buf[1] == cylinder && buf[2] == head && buf[3] == sector {
-                ret.id = buf[0];
-                ret.cylinder = buf[1];
-                ret.head = buf[2];
-                ret.sector = buf[3];
-                ret.size = buf[4];
-
-                // Copy the data
-                for i in 0..512 {
-                    ret.data[i] = buf[i + offset];
+    /**
+     * Check every sector written from now on by reading it back,
+     * and write it again up to `retries` times if it doesn't match.
+     * None writes without checking.
+     */
+    pub fn set_write_verify(&mut self, retries: Option<u8>) {
+        self.write_verify = retries;
+    }
+
+    /**
+     * Write a sector. With write verify on, it is read back on the
+     * next revolution and rewritten until it matches or the retries
+     * run out.
+     */
+    pub fn write_sector(
+        &mut self,
+        head: u8,
+        cylinder: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> Result<(), FddError> {
+        self.write_sector_once(head, cylinder, sector, data)?;
+        let retries = match self.write_verify {
+            Some(retries) => retries,
+            None => {
+                return Ok(());
+            }
+        };
+
+        let mut attempts = 0;
+        loop {
+            let error = match self.read_sector_within(head, cylinder, sector, VERIFY_REVOLUTIONS) {
+                Ok(found) => {
+                    // Short data is padded out with zeros
+                    let (written, padding) = found.data.split_at(data.len());
+                    if written == data && padding.iter().all(|byte| *byte == 0) {
+                        return Ok(());
+                    }
+                    FddError::VerifyFailed
                 }
+                Err(error) => error,
+            };
 
-                // TODO:  crc stuff
-                return Some(ret);
+            if attempts == retries {
+                self.bus.debug_str(b"ERROR: Sector failed to verify");
+                return Err(error);
             }
+
+            attempts += 1;
+            self.write_sector_once(head, cylinder, sector, data)?;
+        }
+    }
+
+    fn write_sector_once(
+        &mut self,
+        head: u8,
+        cylinder: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> Result<(), FddError> {
+        // Some basic validation
+        if data.len() > 512 {
+            self.bus.debug_str(b"ERROR: Data array is too large");
+            return Err(FddError::InvalidArgument);
         }
 
-        if fdd_read_index() == 0 {
-            if latch == false {
+        if !self.geometry.contains(head, cylinder, sector) {
+            return Err(FddError::InvalidArgument);
+        }
+
+        self.check_writable(self.geometry.rpm)?;
+
+        // The algorithm will work like so:
+        // First, seek the sector we want and then read the first 15 bytes
+        // which are the metadata. Compare with target. If approved then
+        // write based on timing.
+        self.set_side(head);
+        self.set_track(cylinder);
+        self.apply_precompensation();
+        let mut error = 0usize;
+        let mut buf: [u8; 15] = [0; 15];
+        let mut byte_buf: [u8; 1] = [0; 1];
+        let mut flux_signals: [Symbol; WRITE_FLUX_LEN] = [Symbol::Pulse10; WRITE_FLUX_LEN];
+
+        // Prepare the data field: payload, crc and a short gap
+        // so the gate closes well clear of the crc bytes.
+        let mut field: [u8; 512 + 2 + WRITE_GAP_LEN] = [0x4E; 512 + 2 + WRITE_GAP_LEN];
+        field[0..512].fill(0);
+        field[0..data.len()].copy_from_slice(data);
+
+        let crc = crc16_update(crc16_field(&[0xFB]), &field[0..512]);
+        field[512] = (crc >> 8) as u8;
+        field[513] = (crc & 0xFF) as u8;
+
+        // The sync returns on the last transition of the A1 marker. The
+        // first interval runs from there to the first bit of the 0xFB.
+        flux_signals[0] = Symbol::Pulse10;
+        let signal_count = 1 + mfm_prepare_write(0xFB, &field, &mut flux_signals[1..]);
+        let mut latch = false;
+        let mut fixups = 0usize;
+        let mut result = FddError::SectorNotFound;
+        let start = self.bus.nanos();
+
+        while error < 10 {
+            if self.bus.sync() && mfm_read_bytes(&mut self.bus, &mut buf) {
+                // If we're on the wrong track, shimmy over to the correct one
+                if buf[0] == 0xFE && buf[1] != cylinder {
+                    fixups += 1;
+                    if fixups > MAX_TRACK_FIXUPS {
+                        return Err(FddError::WrongCylinder);
+                    }
+
+                    self.fix_track(cylinder, buf[1] as u8);
+                } else if buf[0] == 0xFE && buf[1] == cylinder && buf[2] == head && buf[3] == sector
+                {
+                    // Never write behind a header we can't trust
+                    let crc = ((buf[5] as u16) << 8) | buf[6] as u16;
+                    if crc16_field(&buf[0..5]) != crc {
+                        result = FddError::IdCrc;
+                    } else if self.bus.sync() {
+                        self.bus.write_flux(&flux_signals[0..signal_count]);
+                        return Ok(());
+                    }
+                }
+            }
+
+            if self.sense_index() {
+                if latch == false {
+                    error += 1;
+                }
                 latch = true;
-                error += 1;
+            } else {
+                latch = false;
+            }
+
+            if error == 0 && (self.bus.nanos() - start) > INDEX_TIMEOUT {
+                return Err(FddError::NoIndex);
             }
-        } else {
-            latch = false;
         }
+
+        return Err(result);
     }
 
-    return None;
-}
+    pub fn debug_sector(
+        &mut self,
+        head: u8,
+        cylinder: u8,
+        sector: u8,
+        flux_signals: &mut [Symbol; 4096],
+        len: usize,
+    ) -> Result<(), FddError> {
+        if !self.geometry.contains(head, cylinder, sector) {
+            return Err(FddError::InvalidArgument);
+        }
 
-pub fn fdd_write_sector(head: u8, cylinder: u8, sector: u8, data: &[u8]) -> bool {
-    // Some basic validation
-    if data.len() > 512 {
-        debug_str(b"ERROR: Data array is too large");
-        teensycore::err(PanicType::Hardfault);
-    }
-
-    // The algorithm will work like so:
-    // First, seek the sector we want and then read the first 15 bytes
-    // which are the metadata. Compare with target. If approved then
-    // write based on timing.
-    fdd_set_side(head);
-    fdd_set_track(cylinder);
-    let mut error = 0usize;
-    let mut buf: [u8; 15] = [0; 15];
-    let mut byte_buf: [u8; 1] = [0; 1];
-    let mut flux_signals: [Symbol; 4096] = [Symbol::Pulse10; 4096];
-
-    // Prepare the data
-    let signal_count = mfm_prepare_write(0xFB, data, &mut flux_signals);
-    let mut latch = false;
-
-    while error < 10 {
-        if (mfm_sync()) {
-            mfm_read_bytes(&mut buf);
-
-            // If we're on the wrong track, shimmy over to the correct one
-            if buf[0] == 0xFE && buf[1] != cylinder {
-                fdd_fix_track(cylinder, buf[1] as u8);
-            } else if buf[0] == 0xFE && buf[1] == cylinder && buf[2] == head && buf[3] == sector {
-                if (mfm_sync()) {
-                    // Remember to skip the first pulse because it's already held high from
-                    // the barrier.
-                    mfm_write_bytes(&flux_signals[1..signal_count]);
-                    return true;
+        self.set_side(head);
+        self.set_track(cylinder);
+        let mut error = 0usize;
+        let mut fixups = 0usize;
+        let mut buf: [u8; 15] = [0; 15];
+        let start = self.bus.nanos();
+
+        // Prepare the data
+        let mut latch = false;
+        while error < 10 {
+            if self.bus.sync() && mfm_read_bytes(&mut self.bus, &mut buf) {
+                // If we're on the wrong track, shimmy over to the correct one
+                if buf[0] == 0xFE && buf[1] != cylinder {
+                    fixups += 1;
+                    if fixups > MAX_TRACK_FIXUPS {
+                        return Err(FddError::WrongCylinder);
+                    }
+
+                    self.fix_track(cylinder, buf[1] as u8);
+                } else if buf[0] == 0xFE && buf[1] == cylinder && buf[2] == head && buf[3] == sector
+                {
+                    self.bus.sync();
+                    mfm_read_flux(&mut self.bus, flux_signals, len);
+                    return Ok(());
                 }
             }
-        }
 
-        if fdd_read_index() == 0 {
-            if latch == false {
-                error += 1;
+            if self.sense_index() {
+                if latch == false {
+                    error += 1;
+                }
+                latch = true;
+            } else {
+                latch = false;
+            }
+
+            if error == 0 && (self.bus.nanos() - start) > INDEX_TIMEOUT {
+                return Err(FddError::NoIndex);
             }
-            latch = true;
-        } else {
-            latch = false;
         }
+
+        return Err(FddError::SectorNotFound);
     }
 
-    return false;
-}
+    /**
+     * Read a block by its logical address. The buffer must be
+     * exactly one sector long.
+     */
+    pub fn read_block(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), FddError> {
+        if buf.len() != 512 || self.geometry.sector_size() != 512 {
+            return Err(FddError::InvalidArgument);
+        }
 
-pub fn fdd_debug_sector(
-    head: u8,
-    cylinder: u8,
-    sector: u8,
-    flux_signals: &mut [Symbol; 4096],
-    len: usize,
-) -> bool {
-    fdd_set_side(head);
-    fdd_set_track(cylinder);
-    let mut error = 0usize;
-    let mut buf: [u8; 15] = [0; 15];
-
-    // Prepare the data
-    let mut latch = false;
-    while error < 10 {
-        if (mfm_sync()) {
-            mfm_read_bytes(&mut buf);
-
-            // If we're on the wrong track, shimmy over to the correct one
-            if buf[0] == 0xFE && buf[1] != cylinder {
-                fdd_fix_track(cylinder, buf[1] as u8);
-            } else if buf[0] == 0xFE && buf[1] == cylinder && buf[2] == head && buf[3] == sector {
-                mfm_sync();
-                mfm_read_flux(flux_signals, len);
-                return true;
+        let (cylinder, head, sector) = match self.geometry.lba_to_chs(lba) {
+            Some(chs) => chs,
+            None => {
+                return Err(FddError::InvalidArgument);
             }
+        };
+
+        let found = self.read_sector(head, cylinder, sector)?;
+        buf.copy_from_slice(&found.data);
+        return Ok(());
+    }
+
+    /**
+     * Write a block by its logical address. The data must be
+     * exactly one sector long.
+     */
+    pub fn write_block(&mut self, lba: u32, data: &[u8]) -> Result<(), FddError> {
+        if data.len() != 512 || self.geometry.sector_size() != 512 {
+            return Err(FddError::InvalidArgument);
         }
 
-        if fdd_read_index() == 0 {
-            if latch == false {
-                error += 1;
+        let (cylinder, head, sector) = match self.geometry.lba_to_chs(lba) {
+            Some(chs) => chs,
+            None => {
+                return Err(FddError::InvalidArgument);
             }
-            latch = true;
-        } else {
-            latch = false;
+        };
+
+        return self.write_sector(head, cylinder, sector, data);
+    }
+
+    /**
+     * Low-level format a single track in the IBM System/34 layout.
+     * The whole track is encoded up front and then written from
+     * one index pulse to the next. The drive is switched to the
+     * data rate of the geometry.
+     *
+     * The track is encoded into `flux`, which is too large for the
+     * stack. TRACK_FLUX_LEN symbols is enough for any track.
+     */
+    pub fn format_track(
+        &mut self,
+        head: u8,
+        cylinder: u8,
+        geometry: &Geometry,
+        flux: &mut [Symbol],
+    ) -> Result<(), FddError> {
+        if geometry.sectors_per_track == 0
+            || geometry.size_code > 7
+            || !geometry.contains(head, cylinder, geometry.first_sector)
+        {
+            return Err(FddError::InvalidArgument);
+        }
+
+        self.check_writable(geometry.rpm)?;
+
+        let track_bytes = geometry.track_bytes();
+        if track_bytes > MAX_TRACK_BYTES {
+            return Err(FddError::InvalidArgument);
+        }
+
+        self.set_data_rate(geometry.data_rate);
+        self.set_side(head);
+        self.set_track(cylinder);
+        self.apply_precompensation();
+
+        let mut encoder = MfmEncoder::new(flux);
+        let sector_size = geometry.sector_size();
+        let mut order = [0u8; 256];
+        geometry.sector_order(&mut order);
+
+        // Every data field holds the same bytes, so the crc only
+        // needs to be worked out once.
+        let mut data_crc = crc16_field(&[0xFB]);
+        for _ in 0..sector_size {
+            data_crc = crc16_update(data_crc, &[geometry.fill_byte]);
+        }
+
+        // Gap 4a, index address mark and gap 1
+        encoder.write_fill(0x4E, 80);
+        encoder.write_fill(0x00, 12);
+        encoder.write_sync_c2();
+        encoder.write_sync_c2();
+        encoder.write_sync_c2();
+        encoder.write_byte(0xFC);
+        encoder.write_fill(0x4E, 50);
+
+        for i in 0..geometry.sectors_per_track as usize {
+            // ID field
+            let id = [0xFE, cylinder, head, order[i], geometry.size_code];
+            let id_crc = crc16_field(&id);
+            encoder.write_fill(0x00, 12);
+            encoder.write_sync_a1();
+            encoder.write_sync_a1();
+            encoder.write_sync_a1();
+            encoder.write_bytes(&id);
+            encoder.write_bytes(&[(id_crc >> 8) as u8, (id_crc & 0xFF) as u8]);
+
+            // Gap 2
+            encoder.write_fill(0x4E, 22);
+
+            // Data field
+            encoder.write_fill(0x00, 12);
+            encoder.write_sync_a1();
+            encoder.write_sync_a1();
+            encoder.write_sync_a1();
+            encoder.write_byte(0xFB);
+            encoder.write_fill(geometry.fill_byte, sector_size);
+            encoder.write_bytes(&[(data_crc >> 8) as u8, (data_crc & 0xFF) as u8]);
+
+            // Gap 3
+            encoder.write_fill(0x4E, geometry.gap3 as usize);
+        }
+
+        // Gap 4b runs until the index comes back around
+        if encoder.bytes_written() > track_bytes {
+            self.bus
+                .debug_str(b"ERROR: Geometry does not fit on a track");
+            return Err(FddError::InvalidArgument);
+        }
+        encoder.write_fill(0x4E, track_bytes - encoder.bytes_written());
+
+        let signal_count = match encoder.finish() {
+            Some(count) => count,
+            None => {
+                return Err(FddError::InvalidArgument);
+            }
+        };
+
+        // Line up with the leading edge of the index pulse
+        self.wait_index()?;
+        self.bus.write_flux(&flux[0..signal_count]);
+        return Ok(());
+    }
+
+    /**
+     * Write raw flux to the track under the head, either straight
+     * away or starting at the leading edge of the index pulse. The
+     * flux goes down as it is, without precompensation.
+     */
+    pub fn write_flux(&mut self, flux_signals: &[Symbol], at_index: bool) -> Result<(), FddError> {
+        self.check_writable(self.geometry.rpm)?;
+
+        if at_index {
+            self.wait_index()?;
+        }
+
+        self.bus.set_precompensation(0);
+        self.bus.write_flux(flux_signals);
+        return Ok(());
+    }
+
+    /**
+     * Write raw flux intervals, in ticks of `clock`, either straight
+     * away or starting at the leading edge of the index pulse. This
+     * is for flux that didn't come from the encoder, so it can be
+     * written back exactly as it was read.
+     */
+    pub fn write_intervals(
+        &mut self,
+        intervals: &[u16],
+        clock: u32,
+        at_index: bool,
+    ) -> Result<(), FddError> {
+        self.check_writable(self.geometry.rpm)?;
+
+        if at_index {
+            self.wait_index()?;
         }
+
+        self.bus.write_intervals(intervals, clock);
+        return Ok(());
+    }
+
+    /**
+     * Hold the write gate open without writing any transitions,
+     * which wipes the track under the head for the given time.
+     */
+    pub fn erase_track(&mut self, duration: uNano) -> Result<(), FddError> {
+        self.check_writable(self.geometry.rpm)?;
+
+        self.bus.set_write_data(false);
+        self.bus.set_gate(true);
+        self.bus.wait_ns(duration);
+        self.bus.set_gate(false);
+        return Ok(());
     }
 
-    return false;
+    /**
+     * Turn off the motor and soft reset.
+     */
+    pub fn shutdown(&mut self) {
+        self.bus.set_select(false);
+        self.bus.set_motor(false);
+        self.bus.set_dir(false);
+        self.bus.set_step(false);
+        self.bus.set_write_data(false);
+        self.bus.set_gate(false);
+        self.bus.set_side(0);
+        self.bus.wait_ns(MS_TO_NANO * 500);
+        self.motor_on = false;
+        self.up_to_speed = false;
+    }
 }
 
-/**
- * Turn off the motor and soft reset.
- */
-pub fn fdd_shutdown() {
-    pin_out(DRIVE_PIN, Power::High);
-    pin_out(MOTOR_PIN, Power::High);
-    pin_out(DIR_PIN, Power::High);
-    pin_out(STEP_PIN, Power::High);
-    pin_out(WRITE_PIN, Power::High);
-    pin_out(GATE_PIN, Power::High);
-    pin_out(HEAD_SEL_PIN, Power::High);
-    wait_exact_ns(MS_TO_NANO * 500);
+#[cfg(test)]
+mod test_fdd {
+    use super::*;
+    use crate::mock::*;
+    use crate::pll::*;
+
+    fn spinning_drive(bus: MockBus) -> FloppyDrive<MockBus> {
+        let mut drive = FloppyDrive::new(bus);
+        drive.bus().motor_on = true;
+        drive.motor_on = true;
+        return drive;
+    }
+
+    fn track_flux() -> std::vec::Vec<Symbol> {
+        return std::vec![Symbol::Pulse10; TRACK_FLUX_LEN];
+    }
+
+    #[test]
+    pub fn test_seek_track00() {
+        let mut bus = MockBus::new();
+        bus.cylinder = 40;
+
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.seek_track00(), Ok(40));
+        assert_eq!(drive.bus().cylinder, 0);
+        assert_eq!(drive.cylinder(), 0);
+
+        // Only cylinders of the geometry can be reached
+        assert_eq!(drive.seek(79), Ok(()));
+        assert_eq!(drive.seek(80), Err(FddError::InvalidArgument));
+        assert_eq!(drive.cylinder(), 79);
+    }
+
+    #[test]
+    pub fn test_seek_broken_track00() {
+        let mut bus = MockBus::new();
+        bus.cylinder = 10;
+        bus.track00_broken = true;
+
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.seek_track00(), Err(FddError::Track00NotFound));
+    }
+
+    #[test]
+    pub fn test_motor_without_index() {
+        let mut bus = MockBus::new();
+        bus.no_index = true;
+
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Err(FddError::NoIndex));
+        assert!(!drive.motor_on());
+        assert!(!drive.bus().motor_on);
+    }
+
+    #[test]
+    pub fn test_read_sector() {
+        let mut bus = MockBus::new();
+        bus.load_sectors(3, 3, 1, 18, 0x5A);
+
+        let mut drive = spinning_drive(bus);
+        let sector = drive.read_sector(1, 3, 7).unwrap();
+        assert_eq!(sector.sector, 7);
+        assert_eq!(sector.head, 1);
+        assert_eq!(sector.data, [0x5A; 512]);
+        assert_eq!(drive.bus().cylinder, 3);
+    }
+
+    #[test]
+    pub fn test_missing_sector() {
+        let mut bus = MockBus::new();
+        bus.load_sectors(0, 0, 0, 9, 0xF6);
+
+        let mut drive = spinning_drive(bus);
+        assert_eq!(
+            drive.read_sector(0, 0, 12).err(),
+            Some(FddError::SectorNotFound)
+        );
+    }
+
+    #[test]
+    pub fn test_track_fixup() {
+        let mut bus = MockBus::new();
+        for cylinder in 0..10 {
+            bus.load_sectors(cylinder, cylinder, 0, 18, cylinder);
+        }
+
+        // The head is two cylinders further in than the driver thinks
+        bus.cylinder = 2;
+        let mut drive = spinning_drive(bus);
+        let sector = drive.read_sector(0, 5, 1).unwrap();
+        assert_eq!(sector.data, [5; 512]);
+        assert_eq!(drive.bus().cylinder, 5);
+        assert_eq!(drive.cylinder(), 5);
+    }
+
+    /** A 1.44M image where every sector is filled with its own number */
+    fn numbered_image() -> std::vec::Vec<u8> {
+        let mut image = std::vec![0u8; 80 * 2 * 18 * 512];
+        for (i, sector) in image.chunks_mut(512).enumerate() {
+            sector.fill(i as u8);
+            sector[0] = (i >> 8) as u8;
+        }
+        return image;
+    }
+
+    #[test]
+    pub fn test_image_read() {
+        let image = numbered_image();
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &image);
+        bus.cylinder = 17;
+
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        for (head, cylinder, sector) in [(0, 0, 1), (1, 79, 18), (0, 40, 9), (1, 2, 3)] {
+            let lba = (cylinder as usize * 2 + head as usize) * 18 + sector as usize - 1;
+            let found = drive.read_sector(head, cylinder, sector).unwrap();
+            assert_eq!(found.data[..], image[lba * 512..(lba + 1) * 512]);
+        }
+    }
+
+    #[test]
+    pub fn test_write_sector() {
+        let image = numbered_image();
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &image);
+
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        let mut data = [0u8; 512];
+        for i in 0..512 {
+            data[i] = (i * 7) as u8;
+        }
+        assert_eq!(drive.write_sector(1, 20, 5, &data), Ok(()));
+        assert_eq!(drive.read_sector(1, 20, 5).unwrap().data, data);
+        assert_eq!(drive.bus().gate_opens, 1);
+
+        // The neighbours survive the write
+        let lba = (20 * 2 + 1) * 18 + 5;
+        let next = drive.bus().peek_sector(20, 1, 6).unwrap();
+        assert_eq!(next[..], image[lba * 512..(lba + 1) * 512]);
+        assert!(drive.bus().peek_sector(20, 1, 4).is_some());
+    }
+
+    #[test]
+    pub fn test_format_track() {
+        let mut drive = FloppyDrive::new(MockBus::new());
+        assert_eq!(drive.set_motor(true), Ok(()));
+        assert_eq!(
+            drive.read_sector(0, 2, 1).err(),
+            Some(FddError::SectorNotFound)
+        );
+
+        assert_eq!(
+            drive.format_track(0, 2, &GEOMETRY_1440K, &mut track_flux()),
+            Ok(())
+        );
+        for sector in 1..=18 {
+            let data = drive.bus().peek_sector(2, 0, sector).unwrap();
+            assert_eq!(data[..], [0xF6; 512]);
+        }
+
+        assert_eq!(drive.read_sector(0, 2, 18).unwrap().data, [0xF6; 512]);
+    }
+
+    #[test]
+    pub fn test_read_track() {
+        let image = numbered_image();
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &image);
+
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        let mut track = TrackBuffer::new(&GEOMETRY_1440K);
+        let start = drive.bus().time;
+        assert_eq!(drive.read_track(1, 0, &mut track), Ok(()));
+
+        // A clean track takes a single revolution
+        assert!(drive.bus().time - start < 200 * MS_TO_NANO);
+        for sector in 1..=18 {
+            let lba = 18 + sector as usize - 1;
+            assert_eq!(
+                track.sector(sector).unwrap()[..],
+                image[lba * 512..(lba + 1) * 512]
+            );
+        }
+
+        // More sectors than the buffer holds
+        let mut dmf = GEOMETRY_1440K;
+        dmf.sectors_per_track = 21;
+        drive.set_geometry(&dmf);
+        let mut track = TrackBuffer::new(&dmf);
+        assert_eq!(
+            drive.read_track(0, 0, &mut track),
+            Err(FddError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    pub fn test_read_track_bad_sector() {
+        let data = std::vec![0x33u8; 18 * 512];
+        let mut flux = encode_track(&GEOMETRY_1440K, 0, 0, &data);
+
+        // Damage the data field of sector 5
+        let marks: std::vec::Vec<SyncMark> = mfm_sync_marks(&flux)
+            .filter(|mark| mark.kind == SyncKind::A1)
+            .collect();
+        let position = marks[9].position + 200;
+        flux[position] = match flux[position] {
+            Symbol::Pulse10 => Symbol::Pulse100,
+            _ => Symbol::Pulse10,
+        };
+
+        let mut bus = MockBus::new();
+        bus.load_track(0, 0, flux);
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        let mut track = TrackBuffer::new(&GEOMETRY_1440K);
+        assert_eq!(drive.read_track(0, 0, &mut track), Err(FddError::DataCrc));
+        assert_eq!(track.status[4], SectorStatus::DataCrc);
+        assert_eq!(track.sector(5), None);
+        assert_eq!(track.good(), 17);
+        assert_eq!(track.sector(6).unwrap()[..], [0x33; 512]);
+    }
+
+    #[test]
+    pub fn test_calibrate() {
+        let image = std::vec![0x5Au8; 1440 * 512];
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_720K, &image);
+        bus.flux_scale = 118;
+        bus.pll = Some(Pll::new(DataRate::Kbps250, MOCK_FLUX_CLOCK));
+        let mut drive = FloppyDrive::new(bus);
+        drive.set_geometry(&GEOMETRY_720K);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        // The limits move out with the slower flux
+        let limits = drive.calibrate().unwrap();
+        let nominal = PulseLimits::nominal(DataRate::Kbps250, MOCK_FLUX_CLOCK);
+        assert!(limits.short > nominal.short * 115 / 100);
+        assert!(limits.medium > nominal.medium * 115 / 100);
+        assert_eq!(drive.bus().pll.as_ref().unwrap().period(), limits.cell());
+        assert_eq!(drive.read_sector(1, 0, 9).unwrap().data, [0x5A; 512]);
+
+        // Each new track gets its own calibration
+        drive.set_track_calibration(true);
+        drive.bus().pll.as_mut().unwrap().reset();
+        drive.bus().flux_scale = 90;
+        assert_eq!(drive.read_sector(0, 3, 1).unwrap().data, [0x5A; 512]);
+        let period = drive.bus().pll.as_ref().unwrap().period();
+        assert!(period < nominal.cell() * 95 / 100);
+
+        // Unformatted tracks don't calibrate
+        drive
+            .bus()
+            .load_track(4, 0, std::vec![Symbol::Pulse1000; 10]);
+        drive.seek(4).unwrap();
+        assert_eq!(drive.calibrate(), Err(FddError::NoFlux));
+    }
+
+    #[test]
+    pub fn test_precompensation() {
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &std::vec![0u8; 2880 * 512]);
+        let mut drive = spinning_drive(bus);
+        assert_eq!(
+            drive.set_precompensation(40, 300),
+            Err(FddError::InvalidArgument)
+        );
+        assert_eq!(drive.set_precompensation(40, 125), Ok(()));
+
+        assert_eq!(drive.write_sector(0, 39, 1, &[0x11; 512]), Ok(()));
+        assert_eq!(drive.bus().precompensation, 0);
+        assert_eq!(drive.write_sector(1, 40, 1, &[0x22; 512]), Ok(()));
+        assert_eq!(drive.bus().precompensation, 125);
+        assert_eq!(drive.read_sector(1, 40, 1).unwrap().data, [0x22; 512]);
+
+        // Raw flux is written exactly as given
+        assert_eq!(drive.write_flux(&[Symbol::Pulse10; 100], false), Ok(()));
+        assert_eq!(drive.bus().precompensation, 0);
+
+        assert_eq!(drive.set_precompensation(0, 0), Ok(()));
+        assert_eq!(
+            drive.format_track(0, 79, &GEOMETRY_1440K, &mut track_flux()),
+            Ok(())
+        );
+        assert_eq!(drive.bus().precompensation, 0);
+    }
+
+    #[test]
+    pub fn test_write_verify() {
+        let mut bus = MockBus::new();
+        bus.load_sectors(0, 0, 0, 18, 0);
+        let mut drive = spinning_drive(bus);
+
+        // Without verify a bad write goes unnoticed
+        drive.bus().bad_writes = 1;
+        assert_eq!(drive.write_sector(0, 0, 1, &[0x5A; 512]), Ok(()));
+        assert!(drive.read_sector(0, 0, 1).is_err());
+
+        // Rewritten until it reads back right
+        drive.set_write_verify(Some(3));
+        drive.bus().bad_writes = 2;
+        drive.bus().writes.clear();
+        assert_eq!(drive.write_sector(0, 0, 2, &[0x5A; 100]), Ok(()));
+        assert_eq!(drive.bus().writes.len(), 3);
+        let mut expected = [0u8; 512];
+        expected[..100].fill(0x5A);
+        assert_eq!(drive.read_sector(0, 0, 2).unwrap().data, expected);
+
+        // Until the retries run out
+        drive.set_write_verify(Some(1));
+        drive.bus().bad_writes = 5;
+        drive.bus().writes.clear();
+        assert!(drive.write_sector(0, 0, 3, &[0x5A; 512]).is_err());
+        assert_eq!(drive.bus().writes.len(), 2);
+    }
+
+    #[test]
+    pub fn test_block_access() {
+        let mut image = std::vec![0u8; 1440 * 512];
+        for (i, block) in image.chunks_mut(512).enumerate() {
+            block.fill(i as u8);
+        }
+
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_720K, &image);
+        let mut drive = FloppyDrive::new(bus);
+        drive.set_geometry(&GEOMETRY_720K);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        let mut buf = [0u8; 512];
+        assert_eq!(drive.read_block(95, &mut buf), Ok(()));
+        assert_eq!(buf, [95; 512]);
+        assert_eq!(drive.bus().cylinder, 5);
+        assert_eq!(drive.bus().side, 0);
+
+        assert_eq!(drive.write_block(1439, &[0xAB; 512]), Ok(()));
+        assert_eq!(drive.read_block(1439, &mut buf), Ok(()));
+        assert_eq!(buf, [0xAB; 512]);
+
+        // Nothing past the end of the media, and the head stays put
+        assert_eq!(
+            drive.read_block(1440, &mut buf),
+            Err(FddError::InvalidArgument)
+        );
+        assert_eq!(
+            drive.write_block(1440, &buf),
+            Err(FddError::InvalidArgument)
+        );
+        assert_eq!(
+            drive.read_block(0, &mut buf[0..256]),
+            Err(FddError::InvalidArgument)
+        );
+        assert_eq!(
+            drive.read_sector(0, 80, 1).err(),
+            Some(FddError::InvalidArgument)
+        );
+        assert_eq!(
+            drive.read_sector(0, 0, 10).err(),
+            Some(FddError::InvalidArgument)
+        );
+        assert_eq!(drive.bus().cylinder, 79);
+    }
+
+    #[test]
+    pub fn test_write_protected() {
+        let mut bus = MockBus::new();
+        bus.load_sectors(0, 0, 0, 18, 0xF6);
+        bus.write_protect = true;
+
+        let mut drive = spinning_drive(bus);
+        assert_eq!(
+            drive.write_sector(0, 0, 1, &[0; 512]),
+            Err(FddError::WriteProtected)
+        );
+        assert_eq!(
+            drive.format_track(0, 0, &GEOMETRY_1440K, &mut track_flux()),
+            Err(FddError::WriteProtected)
+        );
+        assert_eq!(drive.erase_track(MS_TO_NANO), Err(FddError::WriteProtected));
+        assert_eq!(
+            drive.write_flux(&[Symbol::Pulse10; 16], false),
+            Err(FddError::WriteProtected)
+        );
+        assert_eq!(drive.bus().gate_opens, 0);
+    }
+
+    #[test]
+    pub fn test_write_off_speed() {
+        let mut bus = MockBus::new();
+        bus.load_sectors(0, 0, 0, 18, 0xF6);
+        bus.rpm = 280;
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+
+        // Spinning with index pulses, but not yet at 300rpm
+        assert_eq!(
+            drive.write_sector(0, 0, 1, &[0x33; 512]),
+            Err(FddError::MotorOff)
+        );
+        assert_eq!(drive.bus().gate_opens, 0);
+
+        drive.bus().rpm = 302;
+        assert_eq!(drive.write_sector(0, 0, 1, &[0x33; 512]), Ok(()));
+        assert_eq!(drive.read_sector(0, 0, 1).unwrap().data, [0x33; 512]);
+
+        // Formatting is timed against the geometry being laid down
+        assert_eq!(
+            drive.format_track(0, 1, &GEOMETRY_1200K, &mut track_flux()),
+            Err(FddError::MotorOff)
+        );
+        drive.bus().rpm = 360;
+        assert_eq!(
+            drive.format_track(0, 1, &GEOMETRY_1200K, &mut track_flux()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    pub fn test_write_not_ready() {
+        let mut bus = MockBus::new();
+        bus.load_sectors(0, 0, 0, 18, 0xF6);
+
+        // The disk spins, but nobody asked the drive to start it
+        let mut drive = FloppyDrive::new(bus);
+        drive.bus().motor_on = true;
+        assert_eq!(
+            drive.write_sector(0, 0, 1, &[0; 512]),
+            Err(FddError::MotorOff)
+        );
+        assert_eq!(drive.erase_track(MS_TO_NANO), Err(FddError::MotorOff));
+        assert_eq!(drive.bus().gate_opens, 0);
+
+        // Spinning without index pulses
+        let mut drive = spinning_drive(MockBus::new());
+        drive.bus().no_index = true;
+        assert_eq!(
+            drive.format_track(0, 0, &GEOMETRY_1440K, &mut track_flux()),
+            Err(FddError::NoIndex)
+        );
+        assert_eq!(drive.erase_track(MS_TO_NANO), Err(FddError::NoIndex));
+        assert_eq!(
+            drive.write_flux(&[Symbol::Pulse10; 16], false),
+            Err(FddError::NoIndex)
+        );
+        assert_eq!(drive.bus().gate_opens, 0);
+
+        // Once the index shows up again, writes go through
+        drive.bus().no_index = false;
+        assert_eq!(drive.erase_track(MS_TO_NANO), Ok(()));
+        assert_eq!(drive.bus().gate_opens, 1);
+
+        // And stop again after a shutdown
+        drive.shutdown();
+        assert_eq!(drive.erase_track(MS_TO_NANO), Err(FddError::MotorOff));
+        assert_eq!(drive.bus().gate_opens, 1);
+    }
 }
@@ -0,0 +1,222 @@
+use crate::mfm::DataRate;
+
+/**
+ * Describes the shape of the media and how the sectors of a
+ * track are laid out on it.
+ */
+#[derive(Copy, Clone)]
+pub struct Geometry {
+    /// How many cylinders are in use
+    pub cylinders: u8,
+    /// How many sides are in use
+    pub heads: u8,
+    /// The rate at which the media was recorded
+    pub data_rate: DataRate,
+    /// How fast the drive spins this media
+    pub rpm: u32,
+    /// How many sectors live on each track
+    pub sectors_per_track: u8,
+    /// The sector holds 128 << size_code bytes (2 = 512 bytes)
+    pub size_code: u8,
+    /// The number given to the first sector of each track
+    pub first_sector: u8,
+    /// Physical distance between consecutively numbered sectors
+    pub interleave: u8,
+    /// Bytes of 0x4E between a data field and the next ID field
+    pub gap3: u8,
+    /// The byte written into the data field of formatted sectors
+    pub fill_byte: u8,
+}
+
+/**
+ * The standard 3.5" high density layout.
+ */
+pub const GEOMETRY_1440K: Geometry = Geometry {
+    cylinders: 80,
+    heads: 2,
+    data_rate: DataRate::Kbps500,
+    rpm: 300,
+    sectors_per_track: 18,
+    size_code: 2,
+    first_sector: 1,
+    interleave: 1,
+    gap3: 0x6C,
+    fill_byte: 0xF6,
+};
+
+/**
+ * The standard 3.5" double density layout.
+ */
+pub const GEOMETRY_720K: Geometry = Geometry {
+    cylinders: 80,
+    heads: 2,
+    data_rate: DataRate::Kbps250,
+    rpm: 300,
+    sectors_per_track: 9,
+    size_code: 2,
+    first_sector: 1,
+    interleave: 1,
+    gap3: 0x50,
+    fill_byte: 0xF6,
+};
+
+/**
+ * The standard 5.25" high density layout.
+ */
+pub const GEOMETRY_1200K: Geometry = Geometry {
+    cylinders: 80,
+    heads: 2,
+    data_rate: DataRate::Kbps500,
+    rpm: 360,
+    sectors_per_track: 15,
+    size_code: 2,
+    first_sector: 1,
+    interleave: 1,
+    gap3: 0x54,
+    fill_byte: 0xF6,
+};
+
+/**
+ * The standard 5.25" double density layout, in a 300rpm drive.
+ * A high density drive spins it at 360rpm and 300kbps instead.
+ */
+pub const GEOMETRY_360K: Geometry = Geometry {
+    cylinders: 40,
+    heads: 2,
+    data_rate: DataRate::Kbps250,
+    rpm: 300,
+    sectors_per_track: 9,
+    size_code: 2,
+    first_sector: 1,
+    interleave: 1,
+    gap3: 0x50,
+    fill_byte: 0xF6,
+};
+
+impl Geometry {
+    /**
+     * How many bytes are in a sector.
+     */
+    pub fn sector_size(&self) -> usize {
+        return 128 << self.size_code;
+    }
+
+    /**
+     * How many sectors are on the whole disk.
+     */
+    pub fn blocks(&self) -> u32 {
+        return self.cylinders as u32 * self.heads as u32 * self.sectors_per_track as u32;
+    }
+
+    /**
+     * Map a logical block address to (cylinder, head, sector).
+     * Blocks run through every sector of a track, then every
+     * head of a cylinder, then on to the next cylinder.
+     */
+    pub fn lba_to_chs(&self, lba: u32) -> Option<(u8, u8, u8)> {
+        if lba >= self.blocks() {
+            return None;
+        }
+
+        let sectors = self.sectors_per_track as u32;
+        let track = lba / sectors;
+        let cylinder = track / self.heads as u32;
+        let head = track % self.heads as u32;
+        let sector = self.first_sector as u32 + lba % sectors;
+        return Some((cylinder as u8, head as u8, sector as u8));
+    }
+
+    /**
+     * True if the address exists on this media.
+     */
+    pub fn contains(&self, head: u8, cylinder: u8, sector: u8) -> bool {
+        return cylinder < self.cylinders
+            && head < self.heads
+            && sector >= self.first_sector
+            && sector - self.first_sector < self.sectors_per_track;
+    }
+
+    /**
+     * How many raw bytes fit on one revolution of a track.
+     */
+    pub fn track_bytes(&self) -> usize {
+        return (self.data_rate.kbps() as usize * 1000 * 60) / (self.rpm as usize * 8);
+    }
+
+    /**
+     * Fill the table with the sector numbers in the order they
+     * physically pass under the head, honoring the interleave.
+     */
+    pub fn sector_order(&self, table: &mut [u8]) {
+        let count = self.sectors_per_track as usize;
+        let interleave = (self.interleave.max(1)) as usize;
+        let mut taken = [false; 256];
+        let mut position = 0;
+
+        for i in 0..count {
+            while taken[position] {
+                position = (position + 1) % count;
+            }
+
+            taken[position] = true;
+            table[position] = self.first_sector.wrapping_add(i as u8);
+            position = (position + interleave) % count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_geometry {
+    use super::*;
+
+    #[test]
+    pub fn test_track_bytes() {
+        let mut geometry = GEOMETRY_1440K;
+        assert_eq!(geometry.track_bytes(), 12500);
+
+        geometry.data_rate = DataRate::Kbps250;
+        assert_eq!(geometry.track_bytes(), 6250);
+
+        geometry.data_rate = DataRate::Kbps500;
+        geometry.rpm = 360;
+        assert_eq!(geometry.track_bytes(), 10416);
+    }
+
+    #[test]
+    pub fn test_lba_to_chs() {
+        let geometry = GEOMETRY_1440K;
+        assert_eq!(geometry.blocks(), 2880);
+        assert_eq!(geometry.lba_to_chs(0), Some((0, 0, 1)));
+        assert_eq!(geometry.lba_to_chs(17), Some((0, 0, 18)));
+        assert_eq!(geometry.lba_to_chs(18), Some((0, 1, 1)));
+        assert_eq!(geometry.lba_to_chs(36), Some((1, 0, 1)));
+        assert_eq!(geometry.lba_to_chs(2879), Some((79, 1, 18)));
+        assert_eq!(geometry.lba_to_chs(2880), None);
+
+        assert_eq!(GEOMETRY_720K.blocks(), 1440);
+        assert_eq!(GEOMETRY_1200K.blocks(), 2400);
+        assert_eq!(GEOMETRY_360K.blocks(), 720);
+        assert_eq!(GEOMETRY_360K.lba_to_chs(719), Some((39, 1, 9)));
+
+        assert!(geometry.contains(1, 79, 18));
+        assert!(!geometry.contains(2, 0, 1));
+        assert!(!geometry.contains(0, 80, 1));
+        assert!(!geometry.contains(0, 0, 0));
+        assert!(!geometry.contains(0, 0, 19));
+    }
+
+    #[test]
+    pub fn test_sector_order() {
+        let mut geometry = GEOMETRY_1440K;
+        geometry.sectors_per_track = 9;
+        geometry.interleave = 2;
+
+        let mut table = [0u8; 9];
+        geometry.sector_order(&mut table);
+        assert_eq!(table, [1, 6, 2, 7, 3, 8, 4, 9, 5]);
+
+        geometry.interleave = 1;
+        geometry.sector_order(&mut table);
+        assert_eq!(table, [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+}
@@ -0,0 +1,993 @@
+use crate::bus::*;
+use crate::fdd::*;
+use teensycore::prelude::*;
+
+/**
+ * A firmware mode speaking the Greaseweazle host protocol, so the
+ * `gw` tools can image disks through our hardware.
+ *
+ * Every command is a command byte, the total length of the command
+ * and then its parameters. The reply is the command byte and an ack
+ * code, followed by any data. Flux travels as a stream of tick
+ * counts which is terminated by a zero byte.
+ */
+const CMD_GET_INFO: u8 = 0;
+const CMD_SEEK: u8 = 2;
+const CMD_HEAD: u8 = 3;
+const CMD_MOTOR: u8 = 6;
+const CMD_READ_FLUX: u8 = 7;
+const CMD_WRITE_FLUX: u8 = 8;
+const CMD_GET_FLUX_STATUS: u8 = 9;
+const CMD_GET_INDEX_TIMES: u8 = 10;
+const CMD_SELECT: u8 = 12;
+const CMD_DESELECT: u8 = 13;
+const CMD_SET_BUS_TYPE: u8 = 14;
+const CMD_RESET: u8 = 16;
+const CMD_ERASE_FLUX: u8 = 17;
+
+const ACK_OKAY: u8 = 0;
+const ACK_BAD_COMMAND: u8 = 1;
+const ACK_NO_INDEX: u8 = 2;
+const ACK_NO_TRK0: u8 = 3;
+const ACK_FLUX_OVERFLOW: u8 = 4;
+const ACK_WRPROT: u8 = 6;
+const ACK_BAD_UNIT: u8 = 9;
+const ACK_BAD_CYLINDER: u8 = 11;
+
+/** GET_INFO pages */
+const GETINFO_FIRMWARE: u16 = 0;
+const GETINFO_CURRENT_DRIVE: u16 = 7;
+
+/** Bus types for SET_BUS_TYPE */
+const BUS_IBMPC: u8 = 1;
+const BUS_SHUGART: u8 = 2;
+
+/** Escapes inside the flux stream, each followed by a 28 bit value */
+const FLUX_OP: u8 = 0xFF;
+const FLUXOP_INDEX: u8 = 1;
+const FLUXOP_SPACE: u8 = 2;
+const FLUXOP_ASTABLE: u8 = 3;
+
+/** The firmware version we claim to be */
+const GW_FW_MAJOR: u8 = 1;
+const GW_FW_MINOR: u8 = 0;
+
+/** Not one of the official boards */
+const GW_HW_MODEL: u8 = 0;
+
+/** Ticks per second of the flux timings we report */
+pub const GW_SAMPLE_FREQ: u32 = 24_000_000;
+
+/**
+ * Flux intervals in one revolution of the fastest media. 500kbps
+ * at 300rpm is 100,000 data bits, and a run of zeros or ones puts
+ * a transition in every one of them.
+ */
+pub const GW_FLUX_LEN: usize = 100_000;
+
+/** How many index to index times are kept from the last read */
+const GW_INDEX_TIMES: usize = 15;
+
+/**
+ * Encode a 28 bit value for a flux op. Each byte carries seven
+ * bits above a set low bit, so none of them can be zero.
+ */
+fn gw_encode_n28(value: u32) -> [u8; 4] {
+    return [
+        (1 | (value << 1)) as u8,
+        (1 | (value >> 6)) as u8,
+        (1 | (value >> 13)) as u8,
+        (1 | (value >> 20)) as u8,
+    ];
+}
+
+/**
+ * Encode one flux interval into the stream. Short intervals take
+ * a byte, medium ones two and anything longer becomes a space op
+ * followed by the remainder. Returns how many bytes were used.
+ */
+fn gw_encode_ticks(ticks: u32, out: &mut [u8; 7]) -> usize {
+    if ticks < 250 {
+        out[0] = ticks as u8;
+        return 1;
+    }
+
+    let high = (ticks - 250) / 255;
+    if high < 5 {
+        out[0] = 250 + high as u8;
+        out[1] = 1 + ((ticks - 250) % 255) as u8;
+        return 2;
+    }
+
+    out[0] = FLUX_OP;
+    out[1] = FLUXOP_SPACE;
+    out[2..6].copy_from_slice(&gw_encode_n28(ticks - 249));
+    out[6] = 249;
+    return 7;
+}
+
+/**
+ * Append an interval to a flux buffer, spilling long ones over
+ * several entries (see INTERVAL_CONTINUES). Returns the new
+ * length, or None if it doesn't fit.
+ */
+fn gw_store(flux: &mut [u16], mut len: usize, mut ticks: u32) -> Option<usize> {
+    while ticks >= INTERVAL_CONTINUES as u32 {
+        *flux.get_mut(len)? = INTERVAL_CONTINUES;
+        ticks -= INTERVAL_CONTINUES as u32;
+        len += 1;
+    }
+
+    *flux.get_mut(len)? = ticks as u16;
+    return Some(len + 1);
+}
+
+/**
+ * The intervals held in a flux buffer, with the long ones put
+ * back together.
+ */
+fn gw_intervals(flux: &[u16]) -> impl Iterator<Item = u32> + '_ {
+    let mut carry: u32 = 0;
+    return flux.iter().filter_map(move |entry| {
+        carry += *entry as u32;
+        if *entry == INTERVAL_CONTINUES {
+            return None;
+        }
+
+        let ticks = carry;
+        carry = 0;
+        return Some(ticks);
+    });
+}
+
+/**
+ * How many entries of a flux buffer it takes to cover the given
+ * number of ticks. Never cuts a long interval apart.
+ */
+fn gw_truncate(flux: &[u16], ticks: u32) -> usize {
+    let mut elapsed: u32 = 0;
+    for (i, entry) in flux.iter().enumerate() {
+        elapsed += *entry as u32;
+        if elapsed >= ticks && *entry != INTERVAL_CONTINUES {
+            return i + 1;
+        }
+    }
+
+    return flux.len();
+}
+
+/**
+ * Translate a driver error into the closest ack code.
+ */
+fn gw_ack(result: Result<(), FddError>) -> u8 {
+    return match result {
+        Ok(_) => ACK_OKAY,
+        Err(FddError::NoIndex) => ACK_NO_INDEX,
+        Err(FddError::Track00NotFound) => ACK_NO_TRK0,
+        Err(FddError::WriteProtected) => ACK_WRPROT,
+        Err(FddError::MotorOff) => ACK_NO_INDEX,
+        Err(_) => ACK_BAD_COMMAND,
+    };
+}
+
+/**
+ * One item out of a flux stream.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GwFlux {
+    /// Ticks between two flux transitions
+    Interval(u32),
+    /// An index pulse, in ticks after the last transition
+    Index(u32),
+    /// The zero byte closing the stream
+    End,
+}
+
+#[derive(Copy, Clone)]
+enum DecodeState {
+    /// Waiting for the start of an interval or op
+    Start,
+    /// Got the first byte of a two byte interval
+    Second(u8),
+    /// Got the escape, the op comes next
+    Op,
+    /// Collecting the 28 bit value of an op
+    Value(u8, u32, u32),
+}
+
+/**
+ * Turns a flux stream back into intervals, a byte at a time.
+ * Astable regions have no place in MFM, so they are skipped.
+ */
+pub struct GwFluxDecoder {
+    state: DecodeState,
+    space: u32,
+}
+
+impl GwFluxDecoder {
+    pub fn new() -> Self {
+        return GwFluxDecoder {
+            state: DecodeState::Start,
+            space: 0,
+        };
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<GwFlux> {
+        match self.state {
+            DecodeState::Start => match byte {
+                0 => {
+                    return Some(GwFlux::End);
+                }
+                1..=249 => {
+                    return Some(self.interval(byte as u32));
+                }
+                FLUX_OP => {
+                    self.state = DecodeState::Op;
+                }
+                _ => {
+                    self.state = DecodeState::Second(byte);
+                }
+            },
+            DecodeState::Second(high) => {
+                self.state = DecodeState::Start;
+                let ticks = 250 + (high as u32 - 250) * 255 + byte as u32 - 1;
+                return Some(self.interval(ticks));
+            }
+            DecodeState::Op => {
+                self.state = DecodeState::Value(byte, 0, 0);
+            }
+            DecodeState::Value(op, value, count) => {
+                let value = value | ((byte as u32 >> 1) << (7 * count));
+                if count < 3 {
+                    self.state = DecodeState::Value(op, value, count + 1);
+                    return None;
+                }
+
+                self.state = DecodeState::Start;
+                match op {
+                    FLUXOP_INDEX => {
+                        return Some(GwFlux::Index(value));
+                    }
+                    FLUXOP_SPACE => {
+                        self.space += value;
+                    }
+                    FLUXOP_ASTABLE => {}
+                    _ => {}
+                }
+            }
+        }
+
+        return None;
+    }
+
+    fn interval(&mut self, ticks: u32) -> GwFlux {
+        let total = self.space + ticks;
+        self.space = 0;
+        return GwFlux::Interval(total);
+    }
+}
+
+/**
+ * Batches the flux stream into larger writes to the port.
+ */
+struct GwStream<'p, P: SerialPort> {
+    port: &'p mut P,
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl<'p, P: SerialPort> GwStream<'p, P> {
+    fn new(port: &'p mut P) -> Self {
+        return GwStream {
+            port: port,
+            buf: [0; 256],
+            len: 0,
+        };
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        if self.len + bytes.len() > self.buf.len() {
+            self.flush();
+        }
+
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn push_ticks(&mut self, ticks: u32) {
+        let mut encoded = [0u8; 7];
+        let len = gw_encode_ticks(ticks, &mut encoded);
+        self.push(&encoded[0..len]);
+    }
+
+    fn push_index(&mut self) {
+        self.push(&[FLUX_OP, FLUXOP_INDEX]);
+        self.push(&gw_encode_n28(0));
+    }
+
+    fn flush(&mut self) {
+        self.port.write(&self.buf[0..self.len]);
+        self.len = 0;
+    }
+}
+
+/**
+ * What we know about a WRITE_FLUX while its stream arrives.
+ */
+struct GwWrite {
+    cue_at_index: bool,
+    terminate_at_index: bool,
+    decoder: GwFluxDecoder,
+    len: usize,
+    overflow: bool,
+}
+
+/**
+ * Serves Greaseweazle commands from a host against a drive.
+ *
+ * Flux is passed through as raw intervals in ticks of
+ * GW_SAMPLE_FREQ, so nothing is lost to decoding and media that
+ * isn't MFM at all can be imaged. The flux buffer must hold a
+ * whole revolution (see GW_FLUX_LEN).
+ */
+pub struct Greaseweazle<'a, B: FloppyBus> {
+    drive: FloppyDrive<B>,
+    flux: &'a mut [u16],
+    command: [u8; 256],
+    command_len: usize,
+    write: Option<GwWrite>,
+    flux_status: u8,
+    index_times: [u32; GW_INDEX_TIMES],
+}
+
+impl<'a, B: FloppyBus> Greaseweazle<'a, B> {
+    pub fn new(drive: FloppyDrive<B>, flux: &'a mut [u16]) -> Self {
+        return Greaseweazle {
+            drive: drive,
+            flux: flux,
+            command: [0; 256],
+            command_len: 0,
+            write: None,
+            flux_status: ACK_OKAY,
+            index_times: [0; GW_INDEX_TIMES],
+        };
+    }
+
+    /**
+     * Direct access to the drive being served.
+     */
+    pub fn drive(&mut self) -> &mut FloppyDrive<B> {
+        return &mut self.drive;
+    }
+
+    /**
+     * Handle everything the host has sent so far.
+     */
+    pub fn poll<P: SerialPort>(&mut self, port: &mut P) {
+        while let Some(byte) = port.read() {
+            self.receive(byte, port);
+        }
+    }
+
+    fn receive<P: SerialPort>(&mut self, byte: u8, port: &mut P) {
+        if self.write.is_some() {
+            self.receive_flux(byte, port);
+            return;
+        }
+
+        self.command[self.command_len] = byte;
+        self.command_len += 1;
+        if self.command_len < 2 {
+            return;
+        }
+
+        let len = self.command[1] as usize;
+        if len < 2 {
+            port.write(&[self.command[0], ACK_BAD_COMMAND]);
+            self.command_len = 0;
+            return;
+        }
+
+        if self.command_len == len {
+            let command = self.command;
+            self.command_len = 0;
+            self.execute(&command[0..len], port);
+        }
+    }
+
+    fn execute<P: SerialPort>(&mut self, cmd: &[u8], port: &mut P) {
+        let ack = match (cmd[0], cmd.len()) {
+            (CMD_GET_INFO, 4) => {
+                self.get_info(u16::from_le_bytes([cmd[2], cmd[3]]), port);
+                return;
+            }
+            (CMD_SEEK, 3) => self.seek(cmd[2] as i8 as i16),
+            (CMD_SEEK, 4) => self.seek(i16::from_le_bytes([cmd[2], cmd[3]])),
+            (CMD_HEAD, 3) => self.head(cmd[2]),
+            (CMD_MOTOR, 4) => self.motor(cmd[2], cmd[3] != 0),
+            (CMD_READ_FLUX, 8) => {
+                let ticks = u32::from_le_bytes([cmd[2], cmd[3], cmd[4], cmd[5]]);
+                let max_index = u16::from_le_bytes([cmd[6], cmd[7]]);
+                self.read_flux(ticks, max_index, port);
+                return;
+            }
+            (CMD_WRITE_FLUX, 4) => self.start_write(cmd[2] != 0, cmd[3] != 0),
+            (CMD_GET_FLUX_STATUS, 2) => self.flux_status,
+            (CMD_GET_INDEX_TIMES, 4) => {
+                self.get_index_times(cmd[2] as usize, cmd[3] as usize, port);
+                return;
+            }
+            (CMD_SELECT, 3) => self.select(cmd[2]),
+            (CMD_DESELECT, 2) => {
+                self.drive.bus().set_select(false);
+                ACK_OKAY
+            }
+            (CMD_SET_BUS_TYPE, 3) => match cmd[2] {
+                BUS_IBMPC | BUS_SHUGART => ACK_OKAY,
+                _ => ACK_BAD_COMMAND,
+            },
+            (CMD_RESET, 2) => self.reset(),
+            (CMD_ERASE_FLUX, 6) => {
+                let ticks = u32::from_le_bytes([cmd[2], cmd[3], cmd[4], cmd[5]]);
+                self.erase(ticks, port);
+                return;
+            }
+            _ => ACK_BAD_COMMAND,
+        };
+
+        port.write(&[cmd[0], ack]);
+    }
+
+    fn get_info<P: SerialPort>(&mut self, page: u16, port: &mut P) {
+        let mut info = [0u8; 32];
+        match page {
+            GETINFO_FIRMWARE => {
+                info[0] = GW_FW_MAJOR;
+                info[1] = GW_FW_MINOR;
+                // Main firmware, as opposed to the bootloader
+                info[2] = 1;
+                info[3] = CMD_ERASE_FLUX;
+                info[4..8].copy_from_slice(&GW_SAMPLE_FREQ.to_le_bytes());
+                info[8] = GW_HW_MODEL;
+                // High speed USB
+                info[10] = 1;
+                info[12..14].copy_from_slice(&600u16.to_le_bytes());
+                info[14..16].copy_from_slice(&1024u16.to_le_bytes());
+            }
+            GETINFO_CURRENT_DRIVE => {
+                // The cylinder is always known, flag the motor
+                let mut flags: u32 = 1;
+                if self.drive.motor_on() {
+                    flags |= 2;
+                }
+                info[0..4].copy_from_slice(&flags.to_le_bytes());
+                info[4..8].copy_from_slice(&(self.drive.cylinder() as i32).to_le_bytes());
+            }
+            _ => {
+                port.write(&[CMD_GET_INFO, ACK_BAD_COMMAND]);
+                return;
+            }
+        }
+
+        port.write(&[CMD_GET_INFO, ACK_OKAY]);
+        port.write(&info);
+    }
+
+    fn seek(&mut self, cylinder: i16) -> u8 {
+        if cylinder < 0 || cylinder > u8::MAX as i16 {
+            return ACK_BAD_CYLINDER;
+        }
+
+        return match self.drive.seek(cylinder as u8) {
+            Err(FddError::InvalidArgument) => ACK_BAD_CYLINDER,
+            result => gw_ack(result),
+        };
+    }
+
+    fn head(&mut self, head: u8) -> u8 {
+        if head > 1 {
+            return ACK_BAD_COMMAND;
+        }
+
+        self.drive.set_side(head);
+        return ACK_OKAY;
+    }
+
+    fn motor(&mut self, unit: u8, on: bool) -> u8 {
+        if unit != 0 {
+            return ACK_BAD_UNIT;
+        }
+
+        return gw_ack(self.drive.set_motor(on));
+    }
+
+    fn select(&mut self, unit: u8) -> u8 {
+        if unit != 0 {
+            return ACK_BAD_UNIT;
+        }
+
+        self.drive.bus().set_select(true);
+        return ACK_OKAY;
+    }
+
+    fn reset(&mut self) -> u8 {
+        let _ = self.drive.set_motor(false);
+        self.drive.bus().set_select(false);
+        self.flux_status = ACK_OKAY;
+        return ACK_OKAY;
+    }
+
+    fn read_flux<P: SerialPort>(&mut self, ticks: u32, max_index: u16, port: &mut P) {
+        port.write(&[CMD_READ_FLUX, ACK_OKAY]);
+
+        let mut stream = GwStream::new(port);
+        self.flux_status = self.stream_flux(ticks, max_index, &mut stream);
+        stream.push(&[0]);
+        stream.flush();
+    }
+
+    /**
+     * Capture whole revolutions and send each one before waiting
+     * for the next index pulse. The USB link can't keep up with
+     * the disk, so a revolution is skipped between captures, but
+     * everything in the stream runs from one index to the next.
+     */
+    fn stream_flux<P: SerialPort>(
+        &mut self,
+        ticks: u32,
+        max_index: u16,
+        stream: &mut GwStream<P>,
+    ) -> u8 {
+        let mut indexes: usize = 0;
+        let mut total: u32 = 0;
+        self.index_times = [0; GW_INDEX_TIMES];
+
+        if self.drive.wait_index().is_err() {
+            return ACK_NO_INDEX;
+        }
+
+        loop {
+            stream.push_index();
+            indexes += 1;
+
+            let done = match (ticks, max_index) {
+                (0, 0) => indexes > 1,
+                (0, _) => indexes >= max_index as usize,
+                (_, 0) => total >= ticks,
+                _ => total >= ticks || indexes >= max_index as usize,
+            };
+            if done {
+                return ACK_OKAY;
+            }
+
+            let len = match self.capture() {
+                Some(len) => len,
+                None => {
+                    return ACK_FLUX_OVERFLOW;
+                }
+            };
+
+            let mut revolution: u32 = 0;
+            for interval in gw_intervals(&self.flux[0..len]) {
+                revolution += interval;
+                stream.push_ticks(interval);
+            }
+
+            total = total.saturating_add(revolution);
+            if indexes <= GW_INDEX_TIMES {
+                self.index_times[indexes - 1] = revolution;
+            }
+
+            if self.drive.wait_index().is_err() {
+                return ACK_NO_INDEX;
+            }
+        }
+    }
+
+    /**
+     * Fill the flux buffer up to the next index pulse, with the
+     * intervals rescaled from the flux clock to GW_SAMPLE_FREQ.
+     * Returns None if the revolution doesn't fit.
+     */
+    fn capture(&mut self) -> Option<usize> {
+        let bus = self.drive.bus();
+
+        // Samples per flux clock tick, with 32 bits of fraction. The
+        // fraction left over from each interval goes into the next.
+        let scale = ((GW_SAMPLE_FREQ as u64) << 32) / bus.flux_clock() as u64;
+        let mut fraction: u64 = 0;
+        let mut len = 0;
+        let mut was_index = true;
+        loop {
+            fraction += bus.read_interval() as u64 * scale;
+            len = gw_store(self.flux, len, (fraction >> 32) as u32)?;
+            fraction &= 0xFFFF_FFFF;
+
+            let index = bus.index();
+            if index && !was_index {
+                return Some(len);
+            }
+            was_index = index;
+        }
+    }
+
+    fn get_index_times<P: SerialPort>(&mut self, first: usize, count: usize, port: &mut P) {
+        if first + count > GW_INDEX_TIMES {
+            port.write(&[CMD_GET_INDEX_TIMES, ACK_BAD_COMMAND]);
+            return;
+        }
+
+        port.write(&[CMD_GET_INDEX_TIMES, ACK_OKAY]);
+        for time in self.index_times[first..first + count].iter() {
+            port.write(&time.to_le_bytes());
+        }
+    }
+
+    fn start_write(&mut self, cue_at_index: bool, terminate_at_index: bool) -> u8 {
+        if self.drive.read_write_protect() {
+            return ACK_WRPROT;
+        }
+
+        self.write = Some(GwWrite {
+            cue_at_index: cue_at_index,
+            terminate_at_index: terminate_at_index,
+            decoder: GwFluxDecoder::new(),
+            len: 0,
+            overflow: false,
+        });
+        return ACK_OKAY;
+    }
+
+    /**
+     * Collect the flux for a WRITE_FLUX, and write it out once
+     * the stream ends.
+     */
+    fn receive_flux<P: SerialPort>(&mut self, byte: u8, port: &mut P) {
+        let write = self.write.as_mut().unwrap();
+        match write.decoder.push(byte) {
+            Some(GwFlux::Interval(ticks)) => match gw_store(self.flux, write.len, ticks) {
+                Some(len) => write.len = len,
+                None => write.overflow = true,
+            },
+            Some(GwFlux::End) => {
+                self.finish_write();
+
+                // Let the host know we are done
+                port.write(&[0]);
+            }
+            _ => {}
+        }
+    }
+
+    fn finish_write(&mut self) {
+        let write = self.write.take().unwrap();
+        if write.overflow {
+            self.flux_status = ACK_FLUX_OVERFLOW;
+            return;
+        }
+
+        // Stopping at the index means writing at most one revolution
+        let mut len = write.len;
+        if write.terminate_at_index {
+            let revolution = GW_SAMPLE_FREQ * 60 / self.drive.geometry().rpm as u32;
+            len = gw_truncate(&self.flux[0..len], revolution);
+        }
+
+        let result =
+            self.drive
+                .write_intervals(&self.flux[0..len], GW_SAMPLE_FREQ, write.cue_at_index);
+        self.flux_status = gw_ack(result);
+    }
+
+    fn erase<P: SerialPort>(&mut self, ticks: u32, port: &mut P) {
+        if self.drive.read_write_protect() {
+            port.write(&[CMD_ERASE_FLUX, ACK_WRPROT]);
+            return;
+        }
+
+        port.write(&[CMD_ERASE_FLUX, ACK_OKAY]);
+        let duration = ticks as uNano * 1_000_000_000 / GW_SAMPLE_FREQ as uNano;
+        self.flux_status = gw_ack(self.drive.erase_track(duration));
+        port.write(&[0]);
+    }
+}
+
+#[cfg(test)]
+mod test_gw {
+    use super::*;
+    use crate::geometry::*;
+    use crate::mfm::*;
+    use crate::mock::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    fn exchange<B: FloppyBus>(
+        gw: &mut Greaseweazle<B>,
+        port: &mut MockSerial,
+        bytes: &[u8],
+    ) -> Vec<u8> {
+        port.send(bytes);
+        gw.poll(port);
+        return port.take();
+    }
+
+    fn decode(stream: &[u8]) -> Vec<GwFlux> {
+        let mut decoder = GwFluxDecoder::new();
+        return stream
+            .iter()
+            .filter_map(|byte| decoder.push(*byte))
+            .collect();
+    }
+
+    /** Snap an interval to the nearest symbol, 24 ticks to the cell */
+    fn to_symbol(ticks: u32) -> Symbol {
+        return Symbol::from_cells((ticks + 12) / 24);
+    }
+
+    fn spinning_drive(bus: MockBus) -> FloppyDrive<MockBus> {
+        let mut drive = FloppyDrive::new(bus);
+        drive.bus().motor_on = true;
+        return drive;
+    }
+
+    #[test]
+    pub fn test_flux_encoding() {
+        let mut stream = Vec::new();
+        for ticks in [1, 48, 249, 250, 1000, 1524, 1525, 200_000] {
+            let mut encoded = [0u8; 7];
+            let len = gw_encode_ticks(ticks, &mut encoded);
+            assert!(!encoded[0..len].contains(&0));
+            stream.extend_from_slice(&encoded[0..len]);
+        }
+
+        assert_eq!(
+            decode(&stream),
+            [1, 48, 249, 250, 1000, 1524, 1525, 200_000].map(GwFlux::Interval)
+        );
+
+        // 2us, 20.8us, an index 100 ticks in, a zero-length space, then 2us
+        let stream = [
+            0x30, 0xFA, 0xFA, 0xFF, 0x01, 0xC9, 0x01, 0x01, 0x01, 0xFF, 0x02, 0x01, 0x01, 0x01,
+            0x01, 0x30, 0x00,
+        ];
+        assert_eq!(
+            decode(&stream),
+            [
+                GwFlux::Interval(48),
+                GwFlux::Interval(499),
+                GwFlux::Index(100),
+                GwFlux::Interval(48),
+                GwFlux::End,
+            ]
+        );
+
+        // Long gaps spill over several entries of the flux buffer
+        let mut flux = [0u16; 8];
+        let mut len = 0;
+        for ticks in [48, 200_000, 65_535, 96] {
+            len = gw_store(&mut flux, len, ticks).unwrap();
+        }
+        assert_eq!(len, 8);
+        assert_eq!(gw_store(&mut flux, len, 48), None);
+        assert_eq!(
+            gw_intervals(&flux).collect::<Vec<u32>>(),
+            [48, 200_000, 65_535, 96]
+        );
+
+        // Truncating keeps a long interval whole
+        assert_eq!(gw_truncate(&flux, 48), 1);
+        assert_eq!(gw_truncate(&flux, 49), 5);
+        assert_eq!(gw_truncate(&flux, 200_048), 5);
+        assert_eq!(gw_truncate(&flux, 1_000_000), 8);
+    }
+
+    #[test]
+    pub fn test_commands() {
+        let mut flux = vec![0u16; GW_FLUX_LEN];
+        let mut gw = Greaseweazle::new(FloppyDrive::new(MockBus::new()), &mut flux);
+        let mut port = MockSerial::new();
+
+        let info = exchange(&mut gw, &mut port, &[0x00, 0x04, 0x00, 0x00]);
+        assert_eq!(info.len(), 34);
+        assert_eq!(info[0..6], [0x00, 0x00, 0x01, 0x00, 0x01, CMD_ERASE_FLUX]);
+        assert_eq!(info[6..10], [0x00, 0x36, 0x6E, 0x01]);
+
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x0E, 0x03, 0x01]),
+            [0x0E, 0x00]
+        );
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x0C, 0x03, 0x00]),
+            [0x0C, 0x00]
+        );
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x0C, 0x03, 0x01]),
+            [0x0C, 0x09]
+        );
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x06, 0x04, 0x00, 0x01]),
+            [0x06, 0x00]
+        );
+        assert!(gw.drive().bus().motor_on);
+
+        // Commands split across polls still go through
+        assert_eq!(exchange(&mut gw, &mut port, &[0x02]), []);
+        assert_eq!(exchange(&mut gw, &mut port, &[0x03, 0x0A]), [0x02, 0x00]);
+        assert_eq!(gw.drive().bus().cylinder, 10);
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x02, 0x04, 0x54, 0x00]),
+            [0x02, 0x0B]
+        );
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x02, 0x03, 0xFF]),
+            [0x02, 0x0B]
+        );
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x03, 0x03, 0x01]),
+            [0x03, 0x00]
+        );
+        assert_eq!(gw.drive().bus().side, 1);
+
+        let drive = exchange(&mut gw, &mut port, &[0x00, 0x04, 0x07, 0x00]);
+        assert_eq!(
+            drive[0..10],
+            [0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00]
+        );
+
+        assert_eq!(exchange(&mut gw, &mut port, &[0x42, 0x02]), [0x42, 0x01]);
+        assert_eq!(exchange(&mut gw, &mut port, &[0x03, 0x01]), [0x03, 0x01]);
+        assert_eq!(exchange(&mut gw, &mut port, &[0x10, 0x02]), [0x10, 0x00]);
+        assert!(!gw.drive().bus().motor_on);
+    }
+
+    #[test]
+    pub fn test_read_flux() {
+        let mut bus = MockBus::new();
+        let data = vec![0x5Au8; 18 * 512];
+        let track = encode_track(&GEOMETRY_1440K, 0, 0, &data);
+        bus.load_track(0, 0, track.clone());
+
+        let mut flux = vec![0u16; GW_FLUX_LEN];
+        let mut gw = Greaseweazle::new(spinning_drive(bus), &mut flux);
+        let mut port = MockSerial::new();
+
+        // Two revolutions, so three index pulses
+        let reply = exchange(&mut gw, &mut port, &[0x07, 0x08, 0, 0, 0, 0, 0x03, 0x00]);
+        assert_eq!(reply[0..2], [0x07, 0x00]);
+        assert_eq!(exchange(&mut gw, &mut port, &[0x09, 0x02]), [0x09, 0x00]);
+
+        let items = decode(&reply[2..]);
+        assert_eq!(items.last(), Some(&GwFlux::End));
+        let revolutions: Vec<&[GwFlux]> = items[0..items.len() - 1]
+            .split(|item| *item == GwFlux::Index(0))
+            .collect();
+        assert_eq!(revolutions.len(), 4);
+        assert!(revolutions[0].is_empty() && revolutions[3].is_empty());
+
+        for revolution in &revolutions[1..3] {
+            let symbols: Vec<Symbol> = revolution
+                .iter()
+                .map(|item| match item {
+                    GwFlux::Interval(ticks) => to_symbol(*ticks),
+                    _ => panic!("unexpected op"),
+                })
+                .collect();
+            assert_eq!(symbols[0..track.len()], track[..]);
+        }
+
+        // 200ms at 24MHz, give or take a symbol
+        let times = exchange(&mut gw, &mut port, &[0x0A, 0x04, 0x00, 0x02]);
+        assert_eq!(times[0..2], [0x0A, 0x00]);
+        for time in times[2..].chunks(4) {
+            let ticks = u32::from_le_bytes([time[0], time[1], time[2], time[3]]);
+            assert!(ticks.abs_diff(4_800_000) < 200);
+        }
+    }
+
+    #[test]
+    pub fn test_read_raw_flux() {
+        let mut bus = MockBus::new();
+        let track = encode_track(&GEOMETRY_1440K, 0, 0, &vec![0x5Au8; 18 * 512]);
+        bus.load_track(0, 0, track.clone());
+        bus.flux_jitter = 200;
+
+        let mut flux = vec![0u16; GW_FLUX_LEN];
+        let mut gw = Greaseweazle::new(spinning_drive(bus), &mut flux);
+        let mut port = MockSerial::new();
+        let reply = exchange(&mut gw, &mut port, &[0x07, 0x08, 0, 0, 0, 0, 0x02, 0x00]);
+
+        // The jitter comes through as it was read, rather than
+        // being snapped to whole bit cells
+        let intervals: Vec<u32> = decode(&reply[2..])
+            .iter()
+            .filter_map(|item| match item {
+                GwFlux::Interval(ticks) => Some(*ticks),
+                _ => None,
+            })
+            .collect();
+        assert!(intervals.iter().filter(|ticks| *ticks % 24 != 0).count() > 1000);
+        assert!(intervals.iter().all(|ticks| (43..=101).contains(ticks)));
+        let symbols: Vec<Symbol> = intervals.iter().map(|ticks| to_symbol(*ticks)).collect();
+        assert_eq!(symbols[0..track.len()], track[..]);
+    }
+
+    #[test]
+    pub fn test_read_flux_no_index() {
+        let mut bus = MockBus::new();
+        bus.no_index = true;
+        let mut flux = vec![0u16; GW_FLUX_LEN];
+        let mut gw = Greaseweazle::new(spinning_drive(bus), &mut flux);
+        let mut port = MockSerial::new();
+
+        let reply = exchange(&mut gw, &mut port, &[0x07, 0x08, 0, 0, 0, 0, 0x02, 0x00]);
+        assert_eq!(reply, [0x07, 0x00, 0x00]);
+        assert_eq!(exchange(&mut gw, &mut port, &[0x09, 0x02]), [0x09, 0x02]);
+    }
+
+    #[test]
+    pub fn test_write_flux() {
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &vec![0xF6u8; 2880 * 512]);
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+        let mut flux = vec![0u16; GW_FLUX_LEN];
+        let mut gw = Greaseweazle::new(drive, &mut flux);
+        let mut port = MockSerial::new();
+
+        // What the host would send for a freshly formatted track, with
+        // a tick of jitter either way on most pulses
+        let data: Vec<u8> = (0..18 * 512).map(|i| (i / 512) as u8).collect();
+        let mut stream = Vec::new();
+        for (i, sym) in encode_track(&GEOMETRY_1440K, 0, 0, &data)
+            .iter()
+            .enumerate()
+        {
+            let mut encoded = [0u8; 7];
+            let len = gw_encode_ticks(sym.cells() * 24 + (i % 3) as u32 - 1, &mut encoded);
+            stream.extend_from_slice(&encoded[0..len]);
+        }
+        stream.push(0);
+
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x08, 0x04, 0x01, 0x01]),
+            [0x08, 0x00]
+        );
+        assert_eq!(exchange(&mut gw, &mut port, &stream), [0x00]);
+        assert_eq!(exchange(&mut gw, &mut port, &[0x09, 0x02]), [0x09, 0x00]);
+
+        let drive = gw.drive();
+        assert_eq!(drive.bus().writes.len(), 1);
+        assert_eq!(drive.read_sector(0, 0, 5).unwrap().data, [4u8; 512]);
+        assert_eq!(drive.read_sector(0, 0, 18).unwrap().data, [17u8; 512]);
+    }
+
+    #[test]
+    pub fn test_write_protected() {
+        let mut bus = MockBus::new();
+        bus.write_protect = true;
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+        let mut flux = vec![0u16; GW_FLUX_LEN];
+        let mut gw = Greaseweazle::new(drive, &mut flux);
+        let mut port = MockSerial::new();
+
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x08, 0x04, 0x01, 0x01]),
+            [0x08, 0x06]
+        );
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x11, 0x06, 0x00, 0x36, 0x6E, 0x01]),
+            [0x11, 0x06]
+        );
+        assert_eq!(gw.drive().bus().gate_opens, 0);
+
+        // 1 second of erase
+        gw.drive().bus().write_protect = false;
+        assert_eq!(
+            exchange(&mut gw, &mut port, &[0x11, 0x06, 0x00, 0x36, 0x6E, 0x01]),
+            [0x11, 0x00, 0x00]
+        );
+        assert_eq!(exchange(&mut gw, &mut port, &[0x09, 0x02]), [0x09, 0x00]);
+        assert_eq!(gw.drive().bus().gate_opens, 1);
+    }
+}
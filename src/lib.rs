@@ -3,82 +3,117 @@
 #![crate_type = "staticlib"]
 #![no_std]
 
+mod bus;
 mod config;
+mod crc;
+mod fat12;
 mod fdd;
+mod geometry;
+mod gw;
 mod mfm;
+#[cfg(test)]
+mod mock;
+mod pll;
+mod shell;
+#[cfg(not(feature = "testing"))]
+mod teensy;
+mod xmodem;
 
-use core::arch::asm;
+#[cfg(not(feature = "testing"))]
+use bus::FloppyBus;
+#[cfg(not(feature = "testing"))]
+use config::*;
+#[cfg(not(feature = "testing"))]
 use fdd::*;
-use mfm::mfm_dump_stats;
+#[cfg(not(feature = "testing"))]
+use gw::*;
+#[cfg(not(feature = "testing"))]
+use shell::*;
+#[cfg(not(feature = "testing"))]
+use teensy::*;
+#[cfg(not(feature = "testing"))]
 use teensycore::prelude::*;
 
 #[cfg(feature = "testing")]
 extern crate std;
 
+/**
+ * Take the floppy bus, or keep saying why it couldn't be taken
+ * so there is something to see once a terminal is connected.
+ */
+#[cfg(not(feature = "testing"))]
+fn open_bus() -> TeensyBus {
+    loop {
+        match TeensyBus::new(DEFAULT_PINS) {
+            Ok(bus) => {
+                return bus;
+            }
+            Err(error) => {
+                debug_str(error.message());
+                wait_exact_ns(MS_TO_NANO * 1000);
+            }
+        }
+    }
+}
+
+/**
+ * The Greaseweazle flux buffer. It's far too big for the stack,
+ * which shares DTCM with the data, the vector table and the USB
+ * queues, so it goes in OCRAM instead. That region isn't zeroed
+ * at boot, which is fine as flux is always stored before it's read.
+ */
+#[cfg(not(feature = "testing"))]
+#[link_section = ".dmabuffers"]
+static mut GW_FLUX: [u16; GW_FLUX_LEN] = [0; GW_FLUX_LEN];
+
+/**
+ * Hand the drive over to a host running the Greaseweazle tools
+ * on the other end of the USB serial port.
+ */
+#[cfg(not(feature = "testing"))]
+fn greaseweazle_mode() -> ! {
+    let mut bus = open_bus();
+    bus.set_debug(false);
+
+    // Only ever borrowed here, and this never returns
+    let flux = unsafe { &mut *core::ptr::addr_of_mut!(GW_FLUX) };
+    let mut server = Greaseweazle::new(FloppyDrive::new(bus), flux);
+    let mut port = UsbSerial;
+    loop {
+        server.poll(&mut port);
+    }
+}
+
 #[cfg(not(feature = "testing"))]
 teensycore::main!({
     wait_exact_ns(MS_TO_NANO * 3000);
 
-    fdd_init();
-    fdd_set_motor(true);
+    if cfg!(feature = "greaseweazle") {
+        greaseweazle_mode();
+    }
+
+    let mut drive = FloppyDrive::new(open_bus());
+    if drive.set_motor(true).is_err() {
+        debug_str(b"Failed to start the drive");
+    }
 
     wait_exact_ns(MS_TO_NANO * 2000);
 
-    match fdd_read_write_protect() {
-        true => debug_str(b"Media is not write protected"),
-        false => debug_str(b"Media is write protected"),
+    if drive.calibrate().is_err() {
+        debug_str(b"Failed to calibrate, using the nominal pulse limits");
+    }
+
+    match drive.read_write_protect() {
+        true => debug_str(b"Media is write protected"),
+        false => debug_str(b"Media is not write protected"),
     }
 
     wait_exact_ns(MS_TO_NANO * 1000);
 
+    let mut shell = Shell::new();
+    let mut port = UsbSerial;
+    shell.start(&mut port);
     loop {
-        fdd_set_motor(true);
-        match fdd_seek_track00() {
-            Some(cycles) => {
-                print(b"Found track0 in ");
-                print_u32(cycles as u32);
-                print(b" cycles!\n");
-
-                let head = 0;
-                let cylinder = 7;
-                let sector = 2;
-
-                mfm_dump_stats();
-
-                // // Write a sector
-                // debug_str(b"Beginning write seek...");
-                // if fdd_write_sector(head, cylinder, sector, &[0x55; 512]) {
-                //     debug_str(b"Write complete!");
-                // } else {
-                //     debug_str(b"Failed to write");
-                // }
-
-                // Read a sector
-                match fdd_read_sector(head, cylinder, sector) {
-                    None => {
-                        debug_str(b"Failed to find sector");
-                    }
-                    Some(sector) => {
-                        debug_str(b"Found the sector!!");
-
-                        // Dump some bytes
-                        for i in 0..10 {
-                            debug_hex(sector.data[i] as u32, b"");
-                            wait_exact_ns(MS_TO_NANO);
-                        }
-                    }
-                }
-            }
-            None => {
-                debug_str(b"Did not find tack00");
-            }
-        }
-
-        debug_str(b"Entering sleep mode...");
-        fdd_shutdown();
-
-        loop {
-            assembly!("nop");
-        }
+        shell.poll(&mut drive, &mut port);
     }
 });
@@ -1,69 +1,37 @@
-use crate::fdd::fdd_read_index;
-use core::arch::asm;
-use core::arch::global_asm;
+use crate::bus::*;
 use teensycore::prelude::*;
 
-#[cfg(not(feature = "testing"))]
-global_asm!(include_str!("mfm.S"));
-
-extern "C" {
-    pub fn _asm_pulse(cycles: u32);
-    pub fn _asm_read_sym() -> i16;
-    pub fn _asm_sync() -> bool;
-    pub fn _asm_full_write_test();
-}
-
-// const CYCLES_PER_MICRO: u32 = F_CPU / 1000000;
-const T2: u32 = 544 * 2 / 3; //1.375 * CYCLES_PER_MICRO;
-const T3: u32 = 940 / 2; //2.375 * CYCLES_PER_MICRO;
-const T4: u32 = 1336 * 2 / 3; //3.375 * CYCLES_PER_MICRO;
-
 /**
-This is a total hack. Read directly from the gpio register for pin 12.
- Need to bypass the normal pin_read method in teensycore because that
- thing is too bloated.
-*/
-#[no_mangle]
-#[inline(never)]
-fn read_data() -> u32 {
-    unsafe {
-        return *(addrs::GPIO7 as *mut u32) & (0x1 << 1);
-    }
-}
-
-#[no_mangle]
-#[inline(never)]
-#[link_section = ".text"]
-fn open_gate() {
-    unsafe {
-        *((addrs::GPIO7 + 0x88) as *mut u32) = 0x1 << 11;
-    }
+ * The speed at which bits pass under the head.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DataRate {
+    /// Double density 3.5" and 5.25" media
+    Kbps250 = 250,
+    /// Double density 5.25" media in a 360rpm high density drive
+    Kbps300 = 300,
+    /// High density media
+    Kbps500 = 500,
 }
 
-#[no_mangle]
-#[inline(never)]
-#[link_section = ".text"]
-fn close_gate() {
-    unsafe {
-        *((addrs::GPIO7 + 0x84) as *mut u32) = 0x1 << 11;
+impl DataRate {
+    pub fn kbps(&self) -> u32 {
+        return *self as u32;
     }
-}
 
-#[no_mangle]
-#[link_section = ".text"]
-#[inline(never)]
-pub fn data_low() {
-    unsafe {
-        *((addrs::GPIO7 + 0x88) as *mut u32) = 0x1 << 16;
+    /**
+     * Convert a timing measured at 500kbps to this data rate.
+     */
+    pub fn scale(&self, value: u32) -> u32 {
+        return value * 500 / self.kbps();
     }
-}
 
-#[no_mangle]
-#[link_section = ".text"]
-#[inline(never)]
-pub fn data_high() {
-    unsafe {
-        *((addrs::GPIO7 + 0x84) as *mut u32) = 0x1 << 16;
+    /**
+     * Convert nanoseconds at this data rate into ticks of a flux
+     * clock running at the given frequency.
+     */
+    pub fn ticks(&self, ns: u32, clock: u32) -> u32 {
+        return (self.scale(ns) as u64 * clock as u64 / 1_000_000_000) as u32;
     }
 }
 
@@ -94,7 +62,7 @@ impl Parity {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Symbol {
     Pulse10 = 0,
     Pulse100 = 1,
@@ -102,13 +70,92 @@ pub enum Symbol {
 }
 
 impl Symbol {
-    fn from(count: i16) -> Self {
+    pub fn from(count: i16) -> Self {
         return match count {
             0 => Self::Pulse10,
             1 => Self::Pulse100,
             _ => Self::Pulse1000,
         };
     }
+
+    /**
+     * The symbol for an interval of the given number of bit cells.
+     * MFM never puts fewer than 2 or more than 4 cells between
+     * transitions, so anything else is taken as the nearest.
+     */
+    pub fn from_cells(cells: u32) -> Self {
+        return match cells {
+            0..=2 => Self::Pulse10,
+            3 => Self::Pulse100,
+            _ => Self::Pulse1000,
+        };
+    }
+
+    /**
+     * The symbol for an interval of the given number of bit cells,
+     * or None if MFM can't produce it.
+     */
+    pub fn checked_from_cells(cells: u32) -> Option<Self> {
+        return match cells {
+            2..=4 => Some(Self::from_cells(cells)),
+            _ => None,
+        };
+    }
+
+    /**
+     * How many bit cells pass under the head for this symbol.
+     */
+    pub fn cells(&self) -> u32 {
+        return match self {
+            Self::Pulse10 => 2,
+            Self::Pulse100 => 3,
+            Self::Pulse1000 => 4,
+        };
+    }
+}
+
+/**
+ * Where one pulse length ends and the next begins, measured in
+ * ticks of the flux clock. Intervals up to `short` are a Pulse10,
+ * up to `medium` a Pulse100 and anything longer a Pulse1000.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PulseLimits {
+    /// The longest interval read as a Pulse10
+    pub short: u32,
+    /// The longest interval read as a Pulse100
+    pub medium: u32,
+}
+
+impl PulseLimits {
+    /**
+     * Limits halfway between the nominal pulse lengths, at 2.5
+     * and 3.5 bit cells.
+     */
+    pub fn nominal(rate: DataRate, clock: u32) -> Self {
+        return PulseLimits {
+            short: rate.ticks(2500, clock),
+            medium: rate.ticks(3500, clock),
+        };
+    }
+
+    /**
+     * The length of a bit cell, which is the distance between
+     * the two limits.
+     */
+    pub fn cell(&self) -> u32 {
+        return self.medium - self.short;
+    }
+
+    pub fn classify(&self, ticks: u32) -> Symbol {
+        if ticks <= self.short {
+            return Symbol::Pulse10;
+        } else if ticks <= self.medium {
+            return Symbol::Pulse100;
+        }
+
+        return Symbol::Pulse1000;
+    }
 }
 
 fn simplify(byte: u16) -> u16 {
@@ -119,121 +166,484 @@ fn simplify(byte: u16) -> u16 {
     }
 }
 
-#[cfg(not(testing))]
-#[inline(never)]
-pub fn mfm_read_sym() -> Symbol {
-    return unsafe { Symbol::from(_asm_read_sym()) };
-}
-
 /**
 This method will dump the bucketed counts of symbols across
 one index loop
  */
-pub fn mfm_dump_stats() {
-    while fdd_read_index() != 0 {
-        assembly!("nop");
+pub fn mfm_dump_stats<B: FloppyBus>(bus: &mut B) {
+    let [pulse_10, pulse_100, pulse_1000] = mfm_stats(bus);
+
+    debug_u64(pulse_10 as u64, b"pulse_10");
+    debug_u64(pulse_100 as u64, b"pulse_100");
+    debug_u64(pulse_1000 as u64, b"pulse_1000");
+}
+
+/**
+ * Count each kind of symbol across one index loop, in the
+ * order Pulse10, Pulse100, Pulse1000.
+ */
+pub fn mfm_stats<B: FloppyBus>(bus: &mut B) -> [u32; 3] {
+    while !bus.index() {
+        core::hint::spin_loop();
     }
 
-    while fdd_read_index() == 0 {
-        assembly!("nop");
+    while bus.index() {
+        core::hint::spin_loop();
     }
 
-    let mut pulse_10 = 0;
-    let mut pulse_100 = 0;
-    let mut pulse_1000 = 0;
+    let mut counts = [0u32; 3];
+    while !bus.index() {
+        counts[bus.read_symbol() as usize] += 1;
+    }
 
-    while fdd_read_index() != 0 {
-        match mfm_read_sym() {
-            Symbol::Pulse10 => {
-                pulse_10 += 1;
-            }
-            Symbol::Pulse100 => {
-                pulse_100 += 1;
-            }
-            Symbol::Pulse1000 => {
-                pulse_1000 += 1;
-            }
+    return counts;
+}
+
+/** How many histogram bins make up one bit cell */
+const HISTOGRAM_RESOLUTION: u32 = 16;
+
+/** The histogram covers intervals up to this many bit cells */
+const HISTOGRAM_CELLS: u32 = 6;
+
+const HISTOGRAM_BINS: usize = (HISTOGRAM_RESOLUTION * HISTOGRAM_CELLS) as usize;
+
+/** A peak needs at least this many intervals to count */
+const HISTOGRAM_MIN_PEAK: u32 = 16;
+
+/**
+ * How often each flux interval length came up, in bins of a
+ * sixteenth of a nominal bit cell. MFM produces three peaks
+ * around 2, 3 and 4 cells, which drift with the speed of the
+ * drive that wrote the disk and the one reading it.
+ */
+pub struct FluxHistogram {
+    /// The nominal bit cell, in ticks of the flux clock
+    cell: u32,
+    bins: [u32; HISTOGRAM_BINS],
+}
+
+impl FluxHistogram {
+    pub fn new(rate: DataRate, clock: u32) -> Self {
+        return FluxHistogram {
+            cell: rate.ticks(1000, clock).max(1),
+            bins: [0; HISTOGRAM_BINS],
+        };
+    }
+
+    pub fn add(&mut self, ticks: u32) {
+        let bin = (ticks as u64 * HISTOGRAM_RESOLUTION as u64 / self.cell as u64) as usize;
+        if bin < HISTOGRAM_BINS {
+            self.bins[bin] += 1;
         }
     }
 
-    debug_u64(pulse_10 as u64, b"pulse_10");
-    debug_u64(pulse_100 as u64, b"pulse_100");
-    debug_u64(pulse_1000 as u64, b"pulse_1000");
+    /**
+     * Find the tallest bin between two interval lengths (in ticks)
+     * and return the centre of the peak around it, in ticks.
+     */
+    fn peak(&self, from: u32, to: u32) -> Option<u32> {
+        let from = (from * HISTOGRAM_RESOLUTION / self.cell) as usize;
+        let to = ((to * HISTOGRAM_RESOLUTION / self.cell) as usize).min(HISTOGRAM_BINS);
+        let tallest = (from..to).max_by_key(|bin| self.bins[*bin])?;
+        if self.bins[tallest] < HISTOGRAM_MIN_PEAK {
+            return None;
+        }
+
+        // Weigh in the neighbours to get between the bins
+        let mut count: u64 = 0;
+        let mut sum: u64 = 0;
+        for bin in tallest.saturating_sub(2)..(tallest + 3).min(HISTOGRAM_BINS) {
+            count += self.bins[bin] as u64;
+            sum += self.bins[bin] as u64 * (2 * bin as u64 + 1);
+        }
+
+        return Some((sum * self.cell as u64 / (2 * HISTOGRAM_RESOLUTION as u64 * count)) as u32);
+    }
+
+    /**
+     * Derive classification limits halfway between the peaks. Each
+     * peak is looked for half a cell either side of where the ones
+     * before it say it should be, so the drive can be well off
+     * speed. A missing 3T or 4T peak is placed from the cell length.
+     * Returns None if there's no 2T peak to go on.
+     */
+    pub fn limits(&self) -> Option<PulseLimits> {
+        let short = self.peak(self.cell * 3 / 2, self.cell * 5 / 2)?;
+
+        let cell = short / 2;
+        let medium = self.peak(cell * 5 / 2, cell * 7 / 2).unwrap_or(cell * 3);
+
+        let cell = (short + medium) / 5;
+        let long = self.peak(cell * 7 / 2, cell * 9 / 2).unwrap_or(cell * 4);
+
+        return Some(PulseLimits {
+            short: (short + medium) / 2,
+            medium: (medium + long) / 2,
+        });
+    }
+}
+
+/**
+ * Build a histogram of the flux intervals across one index loop.
+ */
+pub fn mfm_histogram<B: FloppyBus>(bus: &mut B, rate: DataRate) -> FluxHistogram {
+    let mut histogram = FluxHistogram::new(rate, bus.flux_clock());
+    while !bus.index() {
+        core::hint::spin_loop();
+    }
+
+    while bus.index() {
+        core::hint::spin_loop();
+    }
+
+    while !bus.index() {
+        histogram.add(bus.read_interval());
+    }
+
+    return histogram;
 }
 
-#[no_mangle]
-#[inline(never)]
-#[link_section = ".text"]
-pub fn mfm_read_flux(dst: &mut [Symbol; 4096], len: usize) {
+/**
+ * Capture raw flux signals straight off the bus.
+ */
+pub fn mfm_read_flux<B: FloppyBus>(bus: &mut B, dst: &mut [Symbol], len: usize) {
     for i in 0..len {
-        dst[i] = unsafe { Symbol::from(_asm_read_sym()) };
+        dst[i] = bus.read_symbol();
     }
 }
 
 /**
- * Wait for a synchronization byte marker
+ * How to move the transition which starts flux_signals[i] to counter
+ * peak shift: -1 for early, 1 for late and 0 to leave it alone.
+ * Transitions push each other apart as they're read back, so one
+ * with a nearer neighbour on one side drifts towards the other.
+ * Writing it a little towards the near neighbour cancels that out.
  */
-#[cfg(not(testing))]
-pub fn mfm_sync() -> bool {
-    unsafe {
-        return _asm_sync();
+pub fn mfm_precomp_shift(flux_signals: &[Symbol], i: usize) -> i32 {
+    if i == 0 || i >= flux_signals.len() {
+        return 0;
+    }
+
+    let before = flux_signals[i - 1].cells();
+    let after = flux_signals[i].cells();
+    if before < after {
+        return -1;
+    } else if before > after {
+        return 1;
     }
+
+    return 0;
 }
 
 /**
- * Fill the array with bytes derived from the flux transitions.
+ * How much to lengthen (or with a negative result, shorten) the
+ * interval of flux_signals[i] when every shifted transition moves
+ * by `amount`. The interval runs from its own transition to the
+ * next one, so it takes up the difference of the two shifts.
+ */
+pub fn mfm_precompensate(flux_signals: &[Symbol], i: usize, amount: u32) -> i32 {
+    let shift = mfm_precomp_shift(flux_signals, i + 1) - mfm_precomp_shift(flux_signals, i);
+    return shift * amount as i32;
+}
+
+/**
+ * Turns flux signals back into bytes. It must be fed the signals
+ * which immediately follow a sync marker, one at a time.
+ */
+pub struct MfmDecoder {
+    byte: u16,
+    state: Parity,
+    weight: u16,
+    primed: bool,
+}
+
+impl MfmDecoder {
+    pub fn new() -> Self {
+        return MfmDecoder {
+            byte: 0,
+            state: Parity::Even,
+            weight: 0x8000,
+            primed: false,
+        };
+    }
+
+    /**
+     * Process the next flux signal. Returns a byte whenever one
+     * has been completed.
+     */
+    #[inline(always)]
+    pub fn push(&mut self, sym: Symbol) -> Option<u8> {
+        let mut ret = None;
+
+        if !self.primed {
+            // This relies on the assumption that we're hot off the press from
+            // a sync marker. As such, the next flux transition has some
+            // weird rules to get back into lock-step with the data bit.
+            match sym {
+                Symbol::Pulse100 => {
+                    self.state = Parity::Odd;
+                    self.weight >>= 1;
+                }
+                Symbol::Pulse1000 => {
+                    self.weight >>= 1;
+                }
+                Symbol::Pulse10 => {}
+            }
+
+            self.primed = true;
+        } else {
+            match sym {
+                Symbol::Pulse1000 => {
+                    // Since it's 3 zeros, doesn't matter what the parity is
+                    // next bit is guaranteed to be a zero.
+                    self.weight >>= 1;
+                }
+                Symbol::Pulse100 => {
+                    if self.state.is(&Parity::Even) {
+                        self.weight >>= 1;
+                    }
+                    // For 1000 and 10 the parity remains unchanged but
+                    // for 100 it's an odd numbered signal so we must
+                    // flip the parity.
+                    self.state = self.state.flip();
+                }
+                _ => {}
+            }
+
+            // When we've exhausted the length of a byte,
+            // we can emit it and adjust values for the
+            // follow up.
+            if self.weight <= 0x80 {
+                ret = Some((self.byte >> 8) as u8);
+                self.byte <<= 8;
+                self.weight <<= 8;
+            }
+        }
+
+        // Set bit
+        self.byte |= self.weight & self.state.as_mask();
+        self.weight >>= 1;
+
+        return ret;
+    }
+}
+
+/**
+ * Decode a buffer of flux signals which immediately follow a sync
+ * marker. Returns how many bytes were decoded and how many
+ * signals were consumed to produce them.
  */
-pub fn mfm_read_bytes(arr: &mut [u8]) -> bool {
-    let mut byte: u16 = 0;
-    let mut state = Parity::Even;
-    let mut weight = 0x8000;
+pub fn mfm_decode(flux_signals: &[Symbol], arr: &mut [u8]) -> (usize, usize) {
+    let mut decoder = MfmDecoder::new();
     let mut n = 0;
+    let mut consumed = 0;
 
-    // This relies on the assumption that we're hot off the press from
-    // a sync marker. As such, the next flux transition has some
-    // weird rules to get back into lock-step with the data bit.
-    match mfm_read_sym() {
-        Symbol::Pulse100 => {
-            state = Parity::Odd;
-            weight >>= 1;
+    for sym in flux_signals {
+        if n == arr.len() {
+            break;
         }
-        Symbol::Pulse1000 => {
-            weight >>= 1;
+
+        consumed += 1;
+        if let Some(byte) = decoder.push(*sym) {
+            arr[n] = byte;
+            n += 1;
         }
-        Symbol::Pulse10 => {}
     }
 
-    // Read the remainder of the data.
-    loop {
-        // Set bit
-        byte |= weight & state.as_mask();
-        weight >>= 1;
-
-        match mfm_read_sym() {
-            Symbol::Pulse1000 => {
-                // Since it's 3 zeros, doesn't matter what the parity is
-                // next bit is guaranteed to be a zero.
-                weight >>= 1;
+    return (n, consumed);
+}
+
+/** How many short pulses must precede a sync marker */
+const SYNC_PREAMBLE: usize = 60;
+
+/** Three 0xA1 sync bytes, as seen after a run of 0x00 */
+const SYNC_A1_PATTERN: [Symbol; 15] = [
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+    Symbol::Pulse10,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+    Symbol::Pulse10,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+];
+
+/** Three 0xC2 sync bytes, as seen after a run of 0x00 */
+const SYNC_C2_PATTERN: [Symbol; 15] = [
+    Symbol::Pulse100,
+    Symbol::Pulse10,
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse10,
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse10,
+    Symbol::Pulse100,
+    Symbol::Pulse1000,
+    Symbol::Pulse100,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SyncKind {
+    /// A1 A1 A1, which precedes an ID or data address mark
+    A1,
+    /// C2 C2 C2, which precedes the index address mark
+    C2,
+}
+
+/**
+ * A sync marker found in a buffer of flux signals. The position
+ * is the index of the first signal after the marker, which is
+ * where decoding of an A1 marked field should begin.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SyncMark {
+    pub kind: SyncKind,
+    pub position: usize,
+}
+
+/**
+ * Looks for the sync markers which follow a run of zeros. Feed it
+ * flux signals one at a time and it reports whenever an A1 data
+ * marker or a C2 index marker completes.
+ */
+pub struct SyncDetector {
+    shorts: usize,
+    matched: usize,
+    a1: bool,
+    c2: bool,
+}
+
+impl SyncDetector {
+    pub fn new() -> Self {
+        return SyncDetector {
+            shorts: 0,
+            matched: 0,
+            a1: false,
+            c2: false,
+        };
+    }
+
+    pub fn push(&mut self, sym: Symbol) -> Option<SyncKind> {
+        if self.matched == 0 {
+            // Collect the run of short pulses from the 0x00 preamble
+            if sym == Symbol::Pulse10 {
+                self.shorts += 1;
+                return None;
             }
-            Symbol::Pulse100 => {
-                if state.is(&Parity::Even) {
-                    weight >>= 1;
-                }
-                // For 1000 and 10 the parity remains unchanged but
-                // for 100 it's an odd numbered signal so we must
-                // flip the parity.
-                state = state.flip();
+
+            if self.shorts < SYNC_PREAMBLE {
+                self.shorts = 0;
+                return None;
+            }
+
+            self.shorts = 0;
+            self.a1 = true;
+            self.c2 = true;
+        }
+
+        self.a1 &= SYNC_A1_PATTERN[self.matched] == sym;
+        self.c2 &= SYNC_C2_PATTERN[self.matched] == sym;
+        self.matched += 1;
+
+        if !self.a1 && !self.c2 {
+            self.matched = 0;
+            self.shorts = match sym {
+                Symbol::Pulse10 => 1,
+                _ => 0,
+            };
+            return None;
+        }
+
+        if self.matched == SYNC_A1_PATTERN.len() {
+            self.matched = 0;
+            return match self.a1 {
+                true => Some(SyncKind::A1),
+                false => Some(SyncKind::C2),
+            };
+        }
+
+        return None;
+    }
+}
+
+/**
+ * Walks a buffer of flux signals and yields every sync marker in it.
+ */
+pub struct SyncMarks<'a> {
+    flux_signals: &'a [Symbol],
+    position: usize,
+    detector: SyncDetector,
+}
+
+impl<'a> Iterator for SyncMarks<'a> {
+    type Item = SyncMark;
+
+    fn next(&mut self) -> Option<SyncMark> {
+        while self.position < self.flux_signals.len() {
+            let sym = self.flux_signals[self.position];
+            self.position += 1;
+
+            if let Some(kind) = self.detector.push(sym) {
+                return Some(SyncMark {
+                    kind: kind,
+                    position: self.position,
+                });
             }
-            _ => {}
         }
 
-        // When we've exhausted the length of a byte,
-        // we can write it and adjust values for the
-        // follow up.
-        arr[n] = (byte >> 8) as u8;
-        if weight <= 0x80 {
-            byte <<= 8;
-            weight <<= 8;
+        return None;
+    }
+}
+
+/**
+ * Find every sync marker in a buffer of captured flux signals.
+ */
+pub fn mfm_sync_marks(flux_signals: &[Symbol]) -> SyncMarks<'_> {
+    return SyncMarks {
+        flux_signals: flux_signals,
+        position: 0,
+        detector: SyncDetector::new(),
+    };
+}
+
+/**
+ * Find the first sync marker in a buffer of captured flux signals.
+ */
+pub fn mfm_find_sync(flux_signals: &[Symbol]) -> Option<SyncMark> {
+    return mfm_sync_marks(flux_signals).next();
+}
+
+/**
+ * Fill the array with bytes derived from the flux transitions.
+ * Returns false if the index pulse arrives first, or if the flux
+ * has an interval MFM can't produce.
+ */
+pub fn mfm_read_bytes<B: FloppyBus>(bus: &mut B, arr: &mut [u8]) -> bool {
+    let mut decoder = MfmDecoder::new();
+    let mut n = 0;
+
+    loop {
+        let sym = match Symbol::checked_from_cells(bus.read_cells()) {
+            Some(sym) => sym,
+            None => {
+                return false;
+            }
+        };
+
+        if let Some(byte) = decoder.push(sym) {
+            arr[n] = byte;
             n += 1;
 
             if n == arr.len() {
@@ -241,7 +651,7 @@ pub fn mfm_read_bytes(arr: &mut [u8]) -> bool {
             }
         }
 
-        if fdd_read_index() == 0 {
+        if bus.index() {
             return false;
         }
     }
@@ -255,11 +665,7 @@ pub fn mfm_read_bytes(arr: &mut [u8]) -> bool {
  *
  * NOTE: The prefix byte should always be 0xFA or 0xFB
  */
-pub fn mfm_prepare_write(
-    prefix_byte: u8,
-    bytes: &[u8],
-    flux_signals: &mut [Symbol; 4096],
-) -> usize {
+pub fn mfm_prepare_write(prefix_byte: u8, bytes: &[u8], flux_signals: &mut [Symbol]) -> usize {
     let mut signal_index = 0;
     let mut ind = 0;
     let mut byte = prefix_byte as u16; // The first byte after a data barrier must be a 0xFB or 0xFA
@@ -332,26 +738,156 @@ pub fn mfm_prepare_write(
     return signal_index;
 }
 
+/** The raw bitcells of 0xA1 with the clock between bits 4 and 5 missing */
+pub const MFM_SYNC_A1: u16 = 0x4489;
+
+/** The raw bitcells of 0xC2 with the clock between bits 3 and 4 missing */
+pub const MFM_SYNC_C2: u16 = 0x5224;
+
 /**
- * This method will commit a series of flux signals to the floppy disk,
- * but it assumes you're already in the right spot. Be sure to call
- * mfm_sync() before invoking this method.
+ * Builds flux signals one bitcell at a time. Unlike mfm_prepare_write
+ * this is able to emit the sync marks (which deliberately break the
+ * clocking rules) so it can be used to lay down an entire track.
+ *
+ * Each emitted symbol is the distance from one flux transition to
+ * the next, starting at the very first transition.
  */
-#[no_mangle]
-#[inline(never)]
-pub fn mfm_write_bytes(flux_signals: &[Symbol]) {
-    open_gate();
-    for sym in flux_signals {
-        unsafe {
-            match sym {
-                Symbol::Pulse10 => _asm_pulse(T2),
-                Symbol::Pulse100 => _asm_pulse(T3),
-                Symbol::Pulse1000 => _asm_pulse(T4),
-            };
+pub struct MfmEncoder<'a> {
+    flux_signals: &'a mut [Symbol],
+    len: usize,
+    cells: u32,
+    bytes: usize,
+    started: bool,
+    last_bit: bool,
+    overflow: bool,
+}
+
+impl<'a> MfmEncoder<'a> {
+    pub fn new(flux_signals: &'a mut [Symbol]) -> Self {
+        return MfmEncoder {
+            flux_signals: flux_signals,
+            len: 0,
+            cells: 0,
+            bytes: 0,
+            started: false,
+            last_bit: false,
+            overflow: false,
+        };
+    }
+
+    /**
+     * How many bytes (including sync marks) have been encoded.
+     */
+    pub fn bytes_written(&self) -> usize {
+        return self.bytes;
+    }
+
+    fn push_cell(&mut self, one: bool) {
+        self.cells += 1;
+        if !one {
+            return;
+        }
+
+        if self.started {
+            self.push_symbol();
+        }
+
+        self.started = true;
+        self.cells = 0;
+    }
+
+    fn push_symbol(&mut self) {
+        if self.len == self.flux_signals.len() {
+            self.overflow = true;
+            return;
         }
+
+        self.flux_signals[self.len] = match self.cells {
+            0..=2 => Symbol::Pulse10,
+            3 => Symbol::Pulse100,
+            _ => Symbol::Pulse1000,
+        };
+        self.len += 1;
+    }
+
+    fn push_raw(&mut self, raw: u16) {
+        let mut mask = 0x8000;
+        while mask > 0 {
+            self.push_cell(raw & mask > 0);
+            mask >>= 1;
+        }
+        self.bytes += 1;
+    }
+
+    /**
+     * Encode a regular data byte, inserting clock bits between
+     * any two zero data bits.
+     */
+    pub fn write_byte(&mut self, byte: u8) {
+        let mut raw: u16 = 0;
+        let mut last = self.last_bit;
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 0x1 > 0;
+            raw <<= 2;
+            if !last && !bit {
+                raw |= 0x2;
+            }
+            if bit {
+                raw |= 0x1;
+            }
+            last = bit;
+        }
+
+        self.push_raw(raw);
+        self.last_bit = last;
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_byte(*byte);
+        }
+    }
+
+    /**
+     * Encode the same byte over and over, used for gaps.
+     */
+    pub fn write_fill(&mut self, byte: u8, count: usize) {
+        for _ in 0..count {
+            self.write_byte(byte);
+        }
+    }
+
+    /**
+     * Encode an 0xA1 sync byte with its missing clock bit.
+     */
+    pub fn write_sync_a1(&mut self) {
+        self.push_raw(MFM_SYNC_A1);
+        self.last_bit = true;
+    }
+
+    /**
+     * Encode an 0xC2 sync byte with its missing clock bit.
+     */
+    pub fn write_sync_c2(&mut self) {
+        self.push_raw(MFM_SYNC_C2);
+        self.last_bit = false;
+    }
+
+    /**
+     * Flush the final transition and return how many flux signals
+     * were produced. Returns None if the buffer was too small.
+     */
+    pub fn finish(mut self) -> Option<usize> {
+        if self.started && self.cells > 0 {
+            self.push_symbol();
+        }
+
+        if self.overflow {
+            return None;
+        }
+
+        return Some(self.len);
     }
-    close_gate();
-    data_high();
 }
 
 // Test the encoding logic
@@ -359,35 +895,240 @@ pub fn mfm_write_bytes(flux_signals: &[Symbol]) {
 mod test_mfm {
     extern crate std;
 
+    use super::mfm_decode;
+    use super::mfm_find_sync;
+    use super::mfm_precomp_shift;
+    use super::mfm_precompensate;
     use super::mfm_prepare_write;
-    use crate::mfm::mfm_write_bytes;
+    use super::mfm_sync_marks;
+    use super::DataRate;
+    use super::FluxHistogram;
+    use super::MfmEncoder;
+    use super::PulseLimits;
+    use super::SyncKind;
+    use crate::bus::FloppyBus;
     use crate::mfm::Symbol;
+    use crate::mock::*;
 
     use std::*;
 
     #[test]
-    pub fn test_encoding() {
+    pub fn test_pulse_limits() {
+        let limits = PulseLimits::nominal(DataRate::Kbps500, 150_000_000);
+        assert_eq!(
+            limits,
+            PulseLimits {
+                short: 375,
+                medium: 525
+            }
+        );
+        assert_eq!(limits.classify(300), Symbol::Pulse10);
+        assert_eq!(limits.classify(375), Symbol::Pulse10);
+        assert_eq!(limits.classify(376), Symbol::Pulse100);
+        assert_eq!(limits.classify(525), Symbol::Pulse100);
+        assert_eq!(limits.classify(526), Symbol::Pulse1000);
+        assert_eq!(limits.classify(100_000), Symbol::Pulse1000);
+
+        let limits = PulseLimits::nominal(DataRate::Kbps250, 24_000_000);
+        assert_eq!(
+            limits,
+            PulseLimits {
+                short: 120,
+                medium: 168
+            }
+        );
+
+        // Raw intervals off the bus land back on the symbols they came from
+        let flux = vec![Symbol::Pulse10, Symbol::Pulse1000, Symbol::Pulse100];
+        let mut bus = MockBus::new();
+        bus.load_track(0, 0, flux.repeat(100));
+        bus.set_motor(true);
+        let limits = PulseLimits::nominal(DataRate::Kbps500, bus.flux_clock());
+        let symbols: vec::Vec<Symbol> = (0..30)
+            .map(|_| limits.classify(bus.read_interval()))
+            .collect();
+        assert_eq!(
+            symbols,
+            [Symbol::Pulse10, Symbol::Pulse1000, Symbol::Pulse100].repeat(10)
+        );
+    }
+
+    #[test]
+    pub fn test_histogram() {
+        // Nominal flux lands on the nominal limits
+        let mut histogram = FluxHistogram::new(DataRate::Kbps500, 150_000_000);
+        for cells in [2, 3, 4, 2, 2, 4, 3, 2].repeat(100) {
+            histogram.add(cells * 150);
+        }
+        let limits = histogram.limits().unwrap();
+        assert!((370..=380).contains(&limits.short));
+        assert!((520..=530).contains(&limits.medium));
+
+        // A drive 15% slow, with short pulses shifted late
+        let mut histogram = FluxHistogram::new(DataRate::Kbps500, 150_000_000);
+        for (i, cells) in [2, 3, 4, 2, 2, 4, 3, 2].repeat(100).iter().enumerate() {
+            let shift = if *cells == 2 { 15 } else { 0 };
+            histogram.add(cells * 172 + shift + (i % 7) as u32);
+        }
+        let limits = histogram.limits().unwrap();
+        assert!((440..=450).contains(&limits.short));
+        assert!((600..=612).contains(&limits.medium));
+        assert_eq!(limits.classify(2 * 172 + 15), Symbol::Pulse10);
+        assert_eq!(limits.classify(3 * 172), Symbol::Pulse100);
+        assert_eq!(limits.classify(4 * 172), Symbol::Pulse1000);
+
+        // Only short pulses, the rest is worked out from them
+        let mut histogram = FluxHistogram::new(DataRate::Kbps250, 150_000_000);
+        for _ in 0..100 {
+            histogram.add(600);
+        }
+        let limits = histogram.limits().unwrap();
+        assert!((745..=765).contains(&limits.short));
+        assert!((1045..=1070).contains(&limits.medium));
+
+        // Nothing to go on
+        let histogram = FluxHistogram::new(DataRate::Kbps500, 150_000_000);
+        assert_eq!(histogram.limits(), None);
+    }
+
+    #[test]
+    pub fn test_precompensation() {
+        use Symbol::*;
+        let flux = [
+            Pulse10, Pulse1000, Pulse100, Pulse10, Pulse10, Pulse100, Pulse1000,
+        ];
+
+        // Early after a shorter interval, late after a longer one
+        let shifts: vec::Vec<i32> = (0..flux.len() + 1)
+            .map(|i| mfm_precomp_shift(&flux, i))
+            .collect();
+        assert_eq!(shifts, [0, -1, 1, 1, 0, -1, -1, 0]);
+
+        // Each interval takes up the difference, and the total is unchanged
+        let adjust: vec::Vec<i32> = (0..flux.len())
+            .map(|i| mfm_precompensate(&flux, i, 125))
+            .collect();
+        assert_eq!(adjust, [-125, 250, 0, -125, -125, 0, 125]);
+        assert_eq!(adjust.iter().sum::<i32>(), 0);
+
+        // Evenly spaced flux is left alone
+        let preamble = [Pulse10; 16];
+        assert!((0..16).all(|i| mfm_precompensate(&preamble, i, 125) == 0));
+    }
+
+    #[test]
+    pub fn test_encoder_sync_marks() {
+        let mut flux_signals: [Symbol; 4096] = [Symbol::Pulse10; 4096];
+        let mut encoder = MfmEncoder::new(&mut flux_signals);
+        encoder.write_fill(0x00, 12);
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_byte(0xFE);
+        encoder.write_fill(0x00, 12);
+        encoder.write_sync_c2();
+        encoder.write_sync_c2();
+        encoder.write_sync_c2();
+        encoder.write_byte(0xFC);
+        assert_eq!(encoder.bytes_written(), 32);
+        let count = encoder.finish().unwrap();
+
+        // The patterns SyncDetector looks for after the zeros
+        let a1 = b"MLMLMSLMLMSLMLM";
+        let c2 = b"MSMLMLSMLMLSMLM";
+        let to_char = |sym: &Symbol| match sym {
+            Symbol::Pulse10 => b'S',
+            Symbol::Pulse100 => b'M',
+            Symbol::Pulse1000 => b'L',
+        };
+
+        let signals: std::vec::Vec<u8> = flux_signals[0..count].iter().map(to_char).collect();
+        let a1_at = signals.windows(a1.len()).position(|w| w == a1).unwrap();
+        let c2_at = signals.windows(c2.len()).position(|w| w == c2).unwrap();
+        assert!(signals[0..a1_at].iter().all(|s| *s == b'S'));
+        assert!(a1_at < c2_at);
+    }
+
+    #[test]
+    pub fn test_encoder_overflow() {
+        let mut flux_signals: [Symbol; 16] = [Symbol::Pulse10; 16];
+        let mut encoder = MfmEncoder::new(&mut flux_signals);
+        encoder.write_fill(0x00, 4);
+        assert_eq!(encoder.finish(), None);
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        // A known pattern, S/M/L being the short/medium/long pulses
         let mut flux_signals: [Symbol; 4096] = [Symbol::Pulse10; 4096];
-        let signal_counts =
+        let signal_count =
             mfm_prepare_write(0xFB, &[0xF6, 0xF6, 0xF6, 0xF6, 0xF6], &mut flux_signals);
+        let expected = b"SSSSLSSSSSLSLSSSLSLSSSLSLSSSLSLSSSLSM".map(|sym| match sym {
+            b'S' => Symbol::Pulse10,
+            b'M' => Symbol::Pulse100,
+            _ => Symbol::Pulse1000,
+        });
+        assert_eq!(flux_signals[0..signal_count], expected);
 
-        let signals = b"SSSSLSSSSSLSLSSSLSLSSSLSLSSSLSLSSSLSM";
-        assert_eq!(signal_counts, signals.len());
+        let data = [
+            0x00, 0xFF, 0xA1, 0x4E, 0x12, 0x34, 0xF6, 0x80, 0x01, 0x55, 0xAA, 0x00, 0x00, 0xC2,
+        ];
 
-        for i in 0..signal_counts {
-            println!("Evaluating signal {i}");
-            let sym = signals[i];
-            match signals[i] {
-                b'S' => {
-                    assert_eq!(flux_signals[i] as usize, 0);
-                }
-                b'M' => {
-                    assert_eq!(flux_signals[i] as usize, 1);
-                }
-                _ => {
-                    assert_eq!(flux_signals[i] as usize, 2);
-                }
-            }
-        }
+        let mut flux_signals: [Symbol; 4096] = [Symbol::Pulse10; 4096];
+        let signal_count = mfm_prepare_write(0xFB, &data, &mut flux_signals[1..]);
+
+        // The decoder starts on the transition at the end of the sync
+        // marker, which is one short pulse away from the 0xFB.
+        let mut decoded = [0u8; 15];
+        let (bytes, consumed) = mfm_decode(&flux_signals[0..signal_count + 1], &mut decoded);
+        assert_eq!(bytes, data.len() + 1);
+        assert!(consumed <= signal_count + 1);
+        assert_eq!(decoded[0], 0xFB);
+        assert_eq!(&decoded[1..], &data);
+    }
+
+    #[test]
+    pub fn test_sync_marks() {
+        let mut flux_signals: [Symbol; 4096] = [Symbol::Pulse10; 4096];
+        let mut encoder = MfmEncoder::new(&mut flux_signals);
+        encoder.write_fill(0x4E, 10);
+        encoder.write_fill(0x00, 12);
+        encoder.write_sync_c2();
+        encoder.write_sync_c2();
+        encoder.write_sync_c2();
+        encoder.write_byte(0xFC);
+        encoder.write_fill(0x4E, 10);
+        encoder.write_fill(0x00, 12);
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_bytes(&[0xFE, 0x07, 0x01, 0x02, 0x02]);
+        encoder.write_fill(0x4E, 10);
+        let count = encoder.finish().unwrap();
+
+        let mut marks = mfm_sync_marks(&flux_signals[0..count]);
+        assert_eq!(marks.next().unwrap().kind, SyncKind::C2);
+        let mark = marks.next().unwrap();
+        assert_eq!(mark.kind, SyncKind::A1);
+        assert_eq!(marks.next(), None);
+
+        let mut decoded = [0u8; 5];
+        mfm_decode(&flux_signals[mark.position..count], &mut decoded);
+        assert_eq!(decoded, [0xFE, 0x07, 0x01, 0x02, 0x02]);
+    }
+
+    #[test]
+    pub fn test_sync_needs_preamble() {
+        let mut flux_signals: [Symbol; 4096] = [Symbol::Pulse10; 4096];
+        let mut encoder = MfmEncoder::new(&mut flux_signals);
+        encoder.write_fill(0x4E, 10);
+        encoder.write_fill(0x00, 4);
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_byte(0xFE);
+        let count = encoder.finish().unwrap();
+
+        assert_eq!(mfm_find_sync(&flux_signals[0..count]), None);
     }
 }
@@ -0,0 +1,606 @@
+use crate::bus::*;
+use crate::crc::*;
+use crate::geometry::*;
+use crate::mfm::*;
+use crate::pll::*;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::vec;
+use std::vec::Vec;
+use teensycore::prelude::*;
+
+/** How long the index pulse stays asserted */
+const INDEX_WIDTH: uNano = 2 * MS_TO_NANO;
+
+/** Polling the clock or the index costs this much time */
+const POLL_COST: uNano = 1000;
+
+/** The flux clock of the mock, which matches the teensy's */
+pub const MOCK_FLUX_CLOCK: u32 = 150_000_000;
+
+/** The innermost cylinder the head can reach */
+const MAX_CYLINDER: u8 = 83;
+
+/**
+ * A simulated drive for running the driver under cargo test.
+ * It models a spinning disk with an index pulse once per
+ * revolution, a head carriage moved by step/dir with a track 0
+ * sensor, head select and a write protect tab.
+ *
+ * Time only moves when the driver waits, polls the clock or
+ * the index, or reads flux, so everything is deterministic.
+ * Flux written while the gate is open lands on the track at
+ * the current rotational position and can be read back.
+ */
+pub struct MockBus {
+    /// Virtual time in nanoseconds
+    pub time: uNano,
+    /// The cylinder the head is physically over
+    pub cylinder: u8,
+    /// The selected head
+    pub side: u8,
+    /// True if the motor is spinning
+    pub motor_on: bool,
+    /// True if the drive is selected
+    pub selected: bool,
+    /// How fast the disk spins
+    pub rpm: u32,
+    /// When set, the track 0 sensor never triggers
+    pub track00_broken: bool,
+    /// When set, the disk spins without ever producing an index pulse
+    pub no_index: bool,
+    /// True if the media has its write protect tab set
+    pub write_protect: bool,
+    /// How many times the write gate was opened
+    pub gate_opens: usize,
+    /// Every write_flux call as (cylinder, head, flux)
+    pub writes: Vec<(u8, u8, Vec<Symbol>)>,
+    /// The write precompensation last asked for, in nanoseconds
+    pub precompensation: u32,
+    /// How long flux intervals read back, in percent of how they were written
+    pub flux_scale: u32,
+    /// The most a flux interval reads back off by, either way, in nanoseconds
+    pub flux_jitter: u32,
+    /// When set, symbols are read back through a data separator
+    /// from the (scaled and jittered) intervals, like on the teensy
+    pub pll: Option<Pll>,
+    /// How many of the coming write_flux calls land on the disk damaged
+    pub bad_writes: usize,
+    noise: u32,
+    rate: DataRate,
+    inward: bool,
+    step_active: bool,
+    gate_open: bool,
+    tracks: HashMap<(u8, u8), MockTrack>,
+    image: Option<(Geometry, Vec<u8>)>,
+}
+
+/**
+ * The flux of one track along with when each symbol starts,
+ * measured in nanoseconds from the leading edge of the index.
+ */
+struct MockTrack {
+    flux: Vec<Symbol>,
+    starts: Vec<u32>,
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        return MockBus {
+            time: 0,
+            cylinder: 0,
+            side: 0,
+            motor_on: false,
+            selected: false,
+            rpm: 300,
+            track00_broken: false,
+            no_index: false,
+            write_protect: false,
+            gate_opens: 0,
+            writes: Vec::new(),
+            precompensation: 0,
+            flux_scale: 100,
+            flux_jitter: 0,
+            pll: None,
+            bad_writes: 0,
+            noise: 1,
+            rate: DataRate::Kbps500,
+            inward: false,
+            step_active: false,
+            gate_open: false,
+            tracks: HashMap::new(),
+            image: None,
+        };
+    }
+
+    /**
+     * Put the flux for a whole track on the disk. The first
+     * symbol lines up with the leading edge of the index. Timing
+     * is based on the data rate selected at the time of loading.
+     */
+    pub fn load_track(&mut self, cylinder: u8, head: u8, flux: Vec<Symbol>) {
+        let track = self.build_track(flux);
+        self.tracks.insert((cylinder, head), track);
+    }
+
+    /**
+     * Format a track with the given number of 512 byte sectors,
+     * each one filled with the fill byte. The ID fields claim
+     * to be on `id_cylinder`, which lets tests simulate a head
+     * that is out of alignment.
+     */
+    pub fn load_sectors(&mut self, cylinder: u8, id_cylinder: u8, head: u8, sectors: u8, fill: u8) {
+        let geometry = Geometry {
+            sectors_per_track: sectors,
+            ..GEOMETRY_1440K
+        };
+        let data = vec![fill; sectors as usize * 512];
+        let flux = encode_track(&geometry, id_cylinder, head, &data);
+        self.load_track(cylinder, head, flux);
+    }
+
+    /**
+     * Insert a disk holding a sector image in logical block
+     * order. Tracks are encoded the first time the head lands
+     * on them.
+     */
+    pub fn load_image(&mut self, geometry: &Geometry, image: &[u8]) {
+        self.rpm = geometry.rpm;
+        self.rate = geometry.data_rate;
+        self.tracks.clear();
+        self.image = Some((*geometry, image.to_vec()));
+    }
+
+    /** One revolution of the disk */
+    fn revolution(&self) -> uNano {
+        return 60_000 * MS_TO_NANO / self.rpm as uNano;
+    }
+
+    /** How long a symbol takes to pass under the head */
+    fn duration(&self, sym: Symbol) -> uNano {
+        return self.rate.scale(sym.cells() * 1000) as uNano;
+    }
+
+    /**
+     * Find the symbol under the head at the current point in the
+     * revolution. Unformatted areas read as long pulses, which
+     * never decode to a sync marker.
+     */
+    fn next_symbol(&mut self) -> Symbol {
+        let position = self.time % self.revolution();
+        let rate = self.rate;
+        let mut sym = Symbol::Pulse1000;
+        let mut start = None;
+        let mut end: uNano = 0;
+        if self.motor_on {
+            if let Some(track) = self.current_track(false) {
+                let index = track
+                    .starts
+                    .partition_point(|start| (*start as uNano) <= position);
+                if index > 0 {
+                    let found = track.flux[index - 1];
+                    let found_start = track.starts[index - 1] as uNano;
+                    end = found_start + rate.scale(found.cells() * 1000) as uNano;
+                    if index < track.flux.len() || position < end {
+                        sym = found;
+                        start = Some(found_start);
+                    }
+                }
+            }
+        }
+
+        // Past the recorded flux the track reads as a steady
+        // run of long intervals
+        let gap = self.duration(Symbol::Pulse1000);
+        let start = start.unwrap_or_else(|| end + (position - end) / gap * gap);
+
+        // Finish on the boundary with the next symbol
+        self.time += start + self.duration(sym) - position;
+        return sym;
+    }
+
+    fn build_track(&self, flux: Vec<Symbol>) -> MockTrack {
+        let mut starts = Vec::with_capacity(flux.len());
+        let mut elapsed: uNano = 0;
+        for sym in flux.iter() {
+            starts.push(elapsed as u32);
+            elapsed += self.duration(*sym);
+        }
+
+        return MockTrack {
+            flux: flux,
+            starts: starts,
+        };
+    }
+
+    /**
+     * The track under the head. Tracks backed by the image are
+     * encoded on demand. Writing to a track that was never
+     * formatted starts from a revolution of noise.
+     */
+    fn current_track(&mut self, create: bool) -> Option<&mut MockTrack> {
+        let key = (self.cylinder, self.side);
+        if !self.tracks.contains_key(&key) {
+            let mut flux = None;
+            if let Some((geometry, image)) = &self.image {
+                let track_size = geometry.sectors_per_track as usize * geometry.sector_size();
+                let track = key.0 as usize * geometry.heads as usize + key.1 as usize;
+                let offset = track * track_size;
+                if key.1 < geometry.heads && offset + track_size <= image.len() {
+                    flux = Some(encode_track(
+                        geometry,
+                        key.0,
+                        key.1,
+                        &image[offset..offset + track_size],
+                    ));
+                }
+            }
+
+            if flux.is_none() && create {
+                let count = self.revolution() / self.duration(Symbol::Pulse1000);
+                flux = Some(vec![Symbol::Pulse1000; count as usize]);
+            }
+
+            match flux {
+                Some(flux) => {
+                    let track = self.build_track(flux);
+                    self.tracks.insert(key, track);
+                }
+                None => {
+                    return None;
+                }
+            }
+        }
+
+        return self.tracks.get_mut(&key);
+    }
+
+    /**
+     * Overwrite the track starting at the given point in the
+     * revolution. Anything running past the index wraps around
+     * to the start of the track.
+     */
+    fn splice(&mut self, position: uNano, flux_signals: &[Symbol]) {
+        let revolution = self.revolution();
+        let mut length: uNano = 0;
+        let mut split = flux_signals.len();
+        for (i, sym) in flux_signals.iter().enumerate() {
+            if position + length >= revolution {
+                split = i;
+                break;
+            }
+            length += self.duration(*sym);
+        }
+
+        let track = self.current_track(true).unwrap();
+        let first = track
+            .starts
+            .partition_point(|start| (*start as uNano) < position);
+        let last = track
+            .starts
+            .partition_point(|start| (*start as uNano) < position + length);
+
+        let mut flux = Vec::with_capacity(track.flux.len() + split);
+        flux.extend_from_slice(&track.flux[0..first]);
+        flux.extend_from_slice(&flux_signals[0..split]);
+        flux.extend_from_slice(&track.flux[last..]);
+
+        let track = self.build_track(flux);
+        self.tracks.insert((self.cylinder, self.side), track);
+
+        if split < flux_signals.len() {
+            self.splice(0, &flux_signals[split..]);
+        }
+    }
+
+    /**
+     * Read back a sector straight from the simulated media,
+     * bypassing the driver. Returns None if it can't be decoded.
+     */
+    pub fn peek_sector(&mut self, cylinder: u8, head: u8, sector: u8) -> Option<Vec<u8>> {
+        let (cylinder_was, side_was) = (self.cylinder, self.side);
+        self.cylinder = cylinder;
+        self.side = head;
+        let flux = self.current_track(false).map(|track| track.flux.clone());
+        self.cylinder = cylinder_was;
+        self.side = side_was;
+
+        let flux = flux?;
+        let mut marks = mfm_sync_marks(&flux).filter(|mark| mark.kind == SyncKind::A1);
+        while let Some(mark) = marks.next() {
+            let mut id = [0u8; 7];
+            mfm_decode(&flux[mark.position..], &mut id);
+            if id[0] != 0xFE || id[1] != cylinder || id[2] != head || id[3] != sector {
+                continue;
+            }
+
+            let size = 128usize << id[4];
+            let data_mark = marks.next()?;
+            let mut field = vec![0u8; size + 3];
+            mfm_decode(&flux[data_mark.position..], &mut field);
+            let crc = ((field[size + 1] as u16) << 8) | field[size + 2] as u16;
+            if crc16_field(&field[0..size + 1]) != crc {
+                return None;
+            }
+
+            return Some(field[1..size + 1].to_vec());
+        }
+
+        return None;
+    }
+}
+
+/**
+ * Encode one track in the IBM System/34 layout, the same way
+ * format_track lays it out, with the sector data taken from
+ * `data` in sector number order.
+ */
+pub fn encode_track(geometry: &Geometry, cylinder: u8, head: u8, data: &[u8]) -> Vec<Symbol> {
+    let mut flux = vec![Symbol::Pulse10; geometry.track_bytes() * 8];
+    let mut encoder = MfmEncoder::new(&mut flux);
+    let sector_size = geometry.sector_size();
+    let mut order = [0u8; 256];
+    geometry.sector_order(&mut order);
+
+    encoder.write_fill(0x4E, 80);
+    encoder.write_fill(0x00, 12);
+    encoder.write_sync_c2();
+    encoder.write_sync_c2();
+    encoder.write_sync_c2();
+    encoder.write_byte(0xFC);
+    encoder.write_fill(0x4E, 50);
+
+    for i in 0..geometry.sectors_per_track as usize {
+        let sector = order[i];
+        let offset = (sector - geometry.first_sector) as usize * sector_size;
+        let payload = &data[offset..offset + sector_size];
+
+        let id = [0xFE, cylinder, head, sector, geometry.size_code];
+        let id_crc = crc16_field(&id);
+        encoder.write_fill(0x00, 12);
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_bytes(&id);
+        encoder.write_bytes(&[(id_crc >> 8) as u8, (id_crc & 0xFF) as u8]);
+        encoder.write_fill(0x4E, 22);
+
+        let data_crc = crc16_update(crc16_field(&[0xFB]), payload);
+        encoder.write_fill(0x00, 12);
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_sync_a1();
+        encoder.write_byte(0xFB);
+        encoder.write_bytes(payload);
+        encoder.write_bytes(&[(data_crc >> 8) as u8, (data_crc & 0xFF) as u8]);
+        encoder.write_fill(0x4E, geometry.gap3 as usize);
+    }
+
+    let count = encoder.finish().unwrap();
+    flux.truncate(count);
+    return flux;
+}
+
+impl FloppyBus for MockBus {
+    fn set_step(&mut self, active: bool) {
+        // The head moves on the leading edge of the pulse
+        if active && !self.step_active {
+            if self.inward && self.cylinder < MAX_CYLINDER {
+                self.cylinder += 1;
+            } else if !self.inward && self.cylinder > 0 {
+                self.cylinder -= 1;
+            }
+        }
+
+        self.step_active = active;
+    }
+
+    fn set_dir(&mut self, inward: bool) {
+        self.inward = inward;
+    }
+
+    fn set_side(&mut self, head: u8) {
+        self.side = head;
+    }
+
+    fn set_motor(&mut self, on: bool) {
+        self.motor_on = on;
+    }
+
+    fn set_select(&mut self, selected: bool) {
+        self.selected = selected;
+    }
+
+    fn set_gate(&mut self, open: bool) {
+        if open && !self.gate_open {
+            self.gate_opens += 1;
+        }
+
+        self.gate_open = open;
+    }
+
+    fn set_write_data(&mut self, _active: bool) {}
+
+    fn index(&mut self) -> bool {
+        self.time += POLL_COST;
+        return self.motor_on && !self.no_index && self.time % self.revolution() < INDEX_WIDTH;
+    }
+
+    fn track00(&mut self) -> bool {
+        return self.cylinder == 0 && !self.track00_broken;
+    }
+
+    fn write_protected(&mut self) -> bool {
+        return self.write_protect;
+    }
+
+    fn ready(&mut self) -> bool {
+        return self.motor_on && self.selected;
+    }
+
+    fn read_data(&mut self) -> bool {
+        return false;
+    }
+
+    fn nanos(&mut self) -> uNano {
+        self.time += POLL_COST;
+        return self.time;
+    }
+
+    fn wait_ns(&mut self, ns: uNano) {
+        self.time += ns;
+    }
+
+    fn set_data_rate(&mut self, rate: DataRate) {
+        self.rate = rate;
+    }
+
+    fn flux_clock(&self) -> u32 {
+        return MOCK_FLUX_CLOCK;
+    }
+
+    /**
+     * The length of the next symbol, stretched by the flux scale
+     * and thrown off by some repeatable noise.
+     */
+    fn read_interval(&mut self) -> u32 {
+        let sym = self.next_symbol();
+        let mut ns = (self.duration(sym) * self.flux_scale as uNano / 100) as i64;
+        if self.flux_jitter > 0 {
+            self.noise = self.noise.wrapping_mul(1103515245).wrapping_add(12345);
+            let spread = 2 * self.flux_jitter + 1;
+            ns += ((self.noise >> 16) % spread) as i64 - self.flux_jitter as i64;
+        }
+
+        return (ns.max(0) as u64 * MOCK_FLUX_CLOCK as u64 / 1_000_000_000) as u32;
+    }
+
+    fn read_symbol(&mut self) -> Symbol {
+        return Symbol::from_cells(self.read_cells());
+    }
+
+    fn read_cells(&mut self) -> u32 {
+        if self.pll.is_none() {
+            return self.next_symbol().cells();
+        }
+
+        let ticks = self.read_interval();
+        return self.pll.as_mut().unwrap().push(ticks);
+    }
+
+    fn reset_separator(&mut self) {
+        if let Some(pll) = self.pll.as_mut() {
+            pll.reset();
+        }
+    }
+
+    /**
+     * Flux is kept as whole symbols, so the shift itself is lost.
+     */
+    fn set_precompensation(&mut self, ns: u32) {
+        self.precompensation = ns;
+    }
+
+    fn set_pulse_limits(&mut self, limits: &PulseLimits) {
+        if let Some(pll) = self.pll.as_mut() {
+            pll.calibrate(limits);
+        }
+    }
+
+    /**
+     * Record the flux onto the track under the head. Nothing
+     * sticks unless the disk is spinning and unprotected, the
+     * same as a real drive.
+     */
+    fn write_flux(&mut self, flux_signals: &[Symbol]) {
+        self.set_gate(true);
+
+        let position = self.time % self.revolution();
+        if self.motor_on && !self.write_protect {
+            let mut flux = flux_signals.to_vec();
+            if self.bad_writes > 0 {
+                // Swap two different symbols half way through, which
+                // keeps the length but not the data
+                self.bad_writes -= 1;
+                let middle = flux.len() / 2;
+                if let Some(i) = (middle..flux.len() - 1).find(|&i| flux[i] != flux[i + 1]) {
+                    flux.swap(i, i + 1);
+                }
+            }
+            self.splice(position, &flux);
+        }
+
+        for sym in flux_signals {
+            self.time += self.duration(*sym);
+        }
+        self.set_gate(false);
+
+        self.writes
+            .push((self.cylinder, self.side, flux_signals.to_vec()));
+    }
+
+    /**
+     * The simulated track only holds whole symbols, so each
+     * interval is rounded to the nearest one on the way down.
+     */
+    fn write_intervals(&mut self, intervals: &[u16], clock: u32) {
+        let cell = self.rate.scale(1000) as u64;
+        let mut flux = Vec::with_capacity(intervals.len());
+        let mut ticks: u64 = 0;
+        for interval in intervals {
+            ticks += *interval as u64;
+            if *interval != INTERVAL_CONTINUES {
+                let ns = ticks * 1_000_000_000 / clock as u64;
+                flux.push(Symbol::from_cells(((ns + cell / 2) / cell) as u32));
+                ticks = 0;
+            }
+        }
+
+        self.write_flux(&flux);
+    }
+}
+
+/**
+ * A serial link to a pretend host. Bytes queued on the input
+ * are handed out one at a time and everything written back is
+ * collected on the output.
+ */
+pub struct MockSerial {
+    /// Bytes waiting to be read
+    pub input: VecDeque<u8>,
+    /// Everything that was written
+    pub output: Vec<u8>,
+}
+
+impl MockSerial {
+    pub fn new() -> Self {
+        return MockSerial {
+            input: VecDeque::new(),
+            output: Vec::new(),
+        };
+    }
+
+    /**
+     * Queue bytes as if the host had sent them.
+     */
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes.iter());
+    }
+
+    /**
+     * Take everything written so far.
+     */
+    pub fn take(&mut self) -> Vec<u8> {
+        return core::mem::take(&mut self.output);
+    }
+}
+
+impl SerialPort for MockSerial {
+    fn read(&mut self) -> Option<u8> {
+        return self.input.pop_front();
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::mfm::*;
+
+/**
+ * A software data separator. Rather than sorting each flux interval
+ * into fixed windows, it keeps its own estimate of the bit cell
+ * length and nudges it after every interval, the same way the
+ * phase-locked loop in a PC floppy controller follows a drive that
+ * runs a little fast or slow.
+ *
+ * Timings are kept in ticks of the flux clock with PLL_FRACTION
+ * bits of fraction.
+ */
+const PLL_FRACTION: u32 = 8;
+
+/** How much of the phase error is corrected straight away, out of 256 */
+const PHASE_GAIN: i64 = 166;
+
+/** How much of the phase error is folded into the cell length, out of 256 */
+const FREQUENCY_GAIN: i64 = 13;
+
+/** How far the cell length may wander from nominal, in percent */
+const PLL_RANGE: u32 = 25;
+
+/**
+ * Intervals this long are a gap in the flux rather than data, so
+ * the loop isn't trained on them.
+ */
+const MAX_CELLS: i64 = 16;
+
+pub struct Pll {
+    /// The nominal cell length
+    nominal: u32,
+    /// The current estimate of the cell length
+    period: u32,
+    /// Phase error carried into the next interval
+    phase: i64,
+    /// Added to every interval, to line calibrated limits up with the cells
+    bias: i64,
+}
+
+impl Pll {
+    pub fn new(rate: DataRate, clock: u32) -> Self {
+        let nominal =
+            (rate.scale(1000) as u64 * clock as u64 * (1 << PLL_FRACTION) / 1_000_000_000) as u32;
+        return Pll {
+            nominal: nominal,
+            period: nominal,
+            phase: 0,
+            bias: 0,
+        };
+    }
+
+    /**
+     * Centre the loop on limits measured off the disk. The cell is
+     * the distance between them, and intervals are shifted so the
+     * short limit falls 2.5 cells in, where the loop rounds.
+     */
+    pub fn calibrate(&mut self, limits: &PulseLimits) {
+        let cell = limits.cell().max(1) << PLL_FRACTION;
+        self.nominal = cell;
+        self.bias = (cell as i64 * 5 / 2) - ((limits.short as i64) << PLL_FRACTION);
+        self.reset();
+    }
+
+    /**
+     * Forget what the loop has learned and start again from the
+     * nominal cell length.
+     */
+    pub fn reset(&mut self) {
+        self.period = self.nominal;
+        self.phase = 0;
+    }
+
+    /**
+     * The current estimate of the cell length, in ticks.
+     */
+    pub fn period(&self) -> u32 {
+        return (self.period + (1 << (PLL_FRACTION - 1))) >> PLL_FRACTION;
+    }
+
+    /**
+     * Take the next flux interval, in ticks, and return how many bit
+     * cells it spans. That's a run of empty cells with the
+     * transition in the last one.
+     */
+    pub fn push(&mut self, ticks: u32) -> u32 {
+        let interval = ((ticks as i64) << PLL_FRACTION) + self.bias + self.phase;
+        let period = self.period as i64;
+        let cells = ((interval + period / 2) / period).clamp(1, MAX_CELLS);
+        if cells == MAX_CELLS {
+            self.phase = 0;
+            return cells as u32;
+        }
+
+        let error = interval - cells * period;
+        let slack = (self.nominal * PLL_RANGE / 100) as i64;
+        let nominal = self.nominal as i64;
+        self.period = (period + error * FREQUENCY_GAIN / 256 / cells)
+            .clamp(nominal - slack, nominal + slack) as u32;
+        self.phase = error * (256 - PHASE_GAIN) / 256;
+        return cells as u32;
+    }
+}
+
+#[cfg(test)]
+mod test_pll {
+    use super::*;
+    use crate::bus::*;
+    use crate::fdd::*;
+    use crate::geometry::*;
+    use crate::mock::*;
+    use std::vec;
+
+    #[test]
+    pub fn test_lock() {
+        let mut pll = Pll::new(DataRate::Kbps500, 150_000_000);
+        assert_eq!(pll.period(), 150);
+        for cells in [2, 3, 4, 2, 2, 4, 3] {
+            assert_eq!(pll.push(cells * 150), cells);
+        }
+        assert_eq!(pll.period(), 150);
+
+        // A drive running 10% slow with some jitter on every pulse
+        let pattern = [2, 2, 3, 4, 2, 3, 3, 2, 4, 4, 2];
+        let jitter = [-30, 25, 10, -20, 35, -5, 0, 15, -35, 20, -10];
+        let mut errors = 0;
+        for round in 0..40 {
+            for i in 0..pattern.len() {
+                let ticks = (pattern[i] * 165) as i32 + jitter[(i + round) % jitter.len()];
+                if pll.push(ticks as u32) != pattern[i] && round > 4 {
+                    errors += 1;
+                }
+            }
+        }
+        assert_eq!(errors, 0);
+        assert!((162..=168).contains(&pll.period()));
+
+        // Gaps don't drag the clock away
+        assert_eq!(pll.push(100_000), MAX_CELLS as u32);
+        assert!((162..=168).contains(&pll.period()));
+
+        pll.reset();
+        assert_eq!(pll.period(), 150);
+    }
+
+    #[test]
+    pub fn test_marginal_disk() {
+        let image = vec![0xC3u8; 18 * 512];
+
+        // Slow, jittery flux that falls outside the fixed windows
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &image);
+        bus.flux_scale = 110;
+        bus.flux_jitter = 250;
+        bus.pll = Some(Pll::new(DataRate::Kbps500, MOCK_FLUX_CLOCK));
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+        for sector in [1, 9, 18] {
+            let id = drive.read_sector(0, 0, sector).ok().unwrap();
+            assert_eq!(id.data, [0xC3; 512]);
+        }
+
+        // Against the fixed windows, interval by interval
+        let flux = encode_track(&GEOMETRY_1440K, 0, 0, &image);
+        let mut bus = MockBus::new();
+        bus.load_track(0, 0, flux.clone());
+        bus.set_motor(true);
+        bus.flux_scale = 110;
+        bus.flux_jitter = 250;
+        let limits = PulseLimits::nominal(DataRate::Kbps500, MOCK_FLUX_CLOCK);
+        let mut pll = Pll::new(DataRate::Kbps500, MOCK_FLUX_CLOCK);
+        let mut fixed_errors = 0;
+        let mut pll_errors = 0;
+        for (i, sym) in flux.iter().enumerate().take(20_000) {
+            let ticks = bus.read_interval();
+            fixed_errors += (limits.classify(ticks) != *sym) as usize;
+            pll_errors += (pll.push(ticks) != sym.cells() && i > 100) as usize;
+        }
+        assert!(fixed_errors > 100);
+        assert_eq!(pll_errors, 0);
+    }
+
+    #[test]
+    pub fn test_separator_errors() {
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &vec![0u8; 18 * 512]);
+        bus.flux_scale = 110;
+        bus.pll = Some(Pll::new(DataRate::Kbps500, MOCK_FLUX_CLOCK));
+        let mut drive = FloppyDrive::new(bus);
+        assert_eq!(drive.set_motor(true), Ok(()));
+        assert!(drive.read_sector(0, 0, 1).is_ok());
+        assert!(drive.bus().pll.as_ref().unwrap().period() > 160);
+
+        // Another head means other flux, so the loop starts over
+        drive.set_side(1);
+        assert_eq!(drive.bus().pll.as_ref().unwrap().period(), 150);
+
+        // Flux too fast for the loop to follow comes out as single
+        // cells, which is an error rather than a short pulse
+        drive.set_side(0);
+        drive.bus().flux_scale = 50;
+        let mut buf = [0u8; 16];
+        assert!(!mfm_read_bytes(drive.bus(), &mut buf));
+    }
+}
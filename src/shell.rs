@@ -0,0 +1,579 @@
+use crate::bus::*;
+use crate::fdd::*;
+use crate::mfm::*;
+use crate::xmodem::*;
+use teensycore::prelude::*;
+
+/** The longest line the shell will collect */
+const SHELL_LINE_LEN: usize = 80;
+
+const PROMPT: &[u8] = b"> ";
+
+const HELP: &[u8] = b"motor on|off      spin the motor up or down\r
+seek <cyl>        move the head to a cylinder\r
+recal             seek back to track 0\r
+side <h>          select a head\r
+read <h> <c> <s>  hex dump a sector\r
+write <h> <c> <s> <pattern>  fill a sector with a byte\r
+stats             count the flux symbols in one revolution\r
+readid            show the next sector ID under the head\r
+rpm               measure the spindle speed\r
+wp                show the write protect tab\r
+dump              send the disk image over YMODEM\r
+restore           write a disk image received over YMODEM\r
+calibrate         fit the pulse limits to the flux under the head\r
+precomp <c> <ns>  shift written pulses by ns from cylinder c inwards\r
+verify <n>|off    read back each written sector, rewriting up to n times\r
+";
+
+/**
+ * Everything the shell knows how to do.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Spin the motor up or down
+    Motor(bool),
+    /// Move the head to a cylinder
+    Seek(u8),
+    /// Seek back to track 0
+    Recal,
+    /// Select a head
+    Side(u8),
+    /// Hex dump a sector, as (head, cylinder, sector)
+    Read(u8, u8, u8),
+    /// Fill a sector with a byte, as (head, cylinder, sector, pattern)
+    Write(u8, u8, u8, u8),
+    /// Count the flux symbols in one revolution
+    Stats,
+    /// Show the next sector ID under the head
+    ReadId,
+    /// Measure the spindle speed
+    Rpm,
+    /// Fit the pulse limits to the flux under the head
+    Calibrate,
+    /// Precompensate writes, as (starting cylinder, nanoseconds)
+    Precomp(u8, u16),
+    /// Read back every sector written, rewriting it up to this many times
+    Verify(Option<u8>),
+    /// Show the write protect tab
+    Wp,
+    /// Send the disk as an image over YMODEM
+    Dump,
+    /// Write an image received over YMODEM
+    Restore,
+    /// List the commands
+    Help,
+}
+
+/**
+ * Why a line didn't make sense.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// There was nothing but whitespace
+    Empty,
+    /// The first word isn't a command
+    UnknownCommand,
+    /// Too few or too many arguments
+    WrongArguments,
+    /// An argument isn't a number in range
+    BadNumber,
+}
+
+impl ParseError {
+    pub fn message(&self) -> &'static [u8] {
+        return match self {
+            ParseError::Empty => b"empty line",
+            ParseError::UnknownCommand => b"unknown command, try help",
+            ParseError::WrongArguments => b"wrong number of arguments",
+            ParseError::BadNumber => b"bad number",
+        };
+    }
+}
+
+/**
+ * Parse a number no bigger than `max`, either decimal or hex with
+ * a 0x prefix.
+ */
+fn parse_number(word: &[u8], max: u32) -> Option<u32> {
+    let (digits, radix) = match word {
+        [b'0', b'x' | b'X', rest @ ..] => (rest, 16),
+        _ => (word, 10),
+    };
+
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut value: u32 = 0;
+    for digit in digits {
+        value = value * radix + (*digit as char).to_digit(radix)?;
+        if value > max {
+            return None;
+        }
+    }
+
+    return Some(value);
+}
+
+/**
+ * Parse exactly N byte sized arguments.
+ */
+fn parse_numbers<const N: usize>(args: &[&[u8]]) -> Result<[u8; N], ParseError> {
+    if args.len() != N {
+        return Err(ParseError::WrongArguments);
+    }
+
+    let mut values = [0u8; N];
+    for i in 0..N {
+        values[i] = parse_number(args[i], 0xFF).ok_or(ParseError::BadNumber)? as u8;
+    }
+
+    return Ok(values);
+}
+
+/**
+ * Turn a line typed at the shell into a command.
+ */
+pub fn parse_command(line: &[u8]) -> Result<Command, ParseError> {
+    let mut words: [&[u8]; 6] = [&[]; 6];
+    let mut count = 0;
+    for word in line
+        .split(|byte| *byte == b' ' || *byte == b'\t')
+        .filter(|word| !word.is_empty())
+    {
+        if count == words.len() {
+            return Err(ParseError::WrongArguments);
+        }
+
+        words[count] = word;
+        count += 1;
+    }
+
+    if count == 0 {
+        return Err(ParseError::Empty);
+    }
+
+    let args = &words[1..count];
+    let command = match words[0] {
+        b"motor" => match args {
+            [b"on"] => Command::Motor(true),
+            [b"off"] => Command::Motor(false),
+            _ => {
+                return Err(ParseError::WrongArguments);
+            }
+        },
+        b"seek" => {
+            let [cylinder] = parse_numbers(args)?;
+            Command::Seek(cylinder)
+        }
+        b"side" => match parse_numbers(args)? {
+            [head] if head <= 1 => Command::Side(head),
+            _ => {
+                return Err(ParseError::BadNumber);
+            }
+        },
+        b"read" => {
+            let [head, cylinder, sector] = parse_numbers(args)?;
+            Command::Read(head, cylinder, sector)
+        }
+        b"write" => {
+            let [head, cylinder, sector, pattern] = parse_numbers(args)?;
+            Command::Write(head, cylinder, sector, pattern)
+        }
+        b"precomp" => match args {
+            [cylinder, ns] => {
+                let cylinder = parse_number(cylinder, 0xFF).ok_or(ParseError::BadNumber)?;
+                let ns = parse_number(ns, 0xFFFF).ok_or(ParseError::BadNumber)?;
+                Command::Precomp(cylinder as u8, ns as u16)
+            }
+            _ => {
+                return Err(ParseError::WrongArguments);
+            }
+        },
+        b"verify" => match args {
+            [b"off"] => Command::Verify(None),
+            _ => {
+                let [retries] = parse_numbers(args)?;
+                Command::Verify(Some(retries))
+            }
+        },
+        b"recal" | b"stats" | b"readid" | b"rpm" | b"calibrate" | b"wp" | b"dump" | b"restore"
+        | b"help"
+            if !args.is_empty() =>
+        {
+            return Err(ParseError::WrongArguments);
+        }
+        b"recal" => Command::Recal,
+        b"stats" => Command::Stats,
+        b"readid" => Command::ReadId,
+        b"rpm" => Command::Rpm,
+        b"calibrate" => Command::Calibrate,
+        b"wp" => Command::Wp,
+        b"dump" => Command::Dump,
+        b"restore" => Command::Restore,
+        b"help" => Command::Help,
+        _ => {
+            return Err(ParseError::UnknownCommand);
+        }
+    };
+
+    return Ok(command);
+}
+
+/**
+ * Sixteen bytes to a line, with the printable ones alongside.
+ */
+fn hex_dump<P: SerialPort>(port: &mut P, data: &[u8]) {
+    for (row, bytes) in data.chunks(16).enumerate() {
+        port.write_hex((row * 16) as u32, 4);
+        port.write(b" ");
+        for byte in bytes {
+            port.write(b" ");
+            port.write_hex(*byte as u32, 2);
+        }
+
+        port.write(b"  ");
+        for byte in bytes {
+            match byte {
+                0x20..=0x7E => port.write(&[*byte]),
+                _ => port.write(b"."),
+            }
+        }
+        port.write(b"\r\n");
+    }
+}
+
+/**
+ * Say how an image transfer went, once the terminal is back from
+ * its file transfer.
+ */
+fn finish_transfer<P: SerialPort>(port: &mut P, result: Result<TransferReport, XmodemError>) {
+    match result {
+        Ok(report) => {
+            port.write(b"\r\n");
+            port.write_dec(report.sectors);
+            port.write(b" sectors, ");
+            port.write_dec(report.bad_count as u32);
+            port.write(b" bad\r\n");
+            report.write_to(port);
+            port.write(b"ok\r\n");
+        }
+        Err(error) => {
+            port.write(b"\r\nerror: ");
+            port.write(error.message());
+            port.write(b"\r\n");
+        }
+    }
+}
+
+/**
+ * Carry out a command against the drive, reporting back on the
+ * port.
+ */
+pub fn execute<B: FloppyBus, P: SerialPort>(
+    command: Command,
+    drive: &mut FloppyDrive<B>,
+    port: &mut P,
+) {
+    let result = match command {
+        Command::Motor(on) => drive.set_motor(on),
+        Command::Seek(cylinder) => drive.seek(cylinder),
+        Command::Recal => drive.seek_track00().map(|steps| {
+            port.write(b"track 0 after ");
+            port.write_dec(steps as u32);
+            port.write(b" steps\r\n");
+        }),
+        Command::Side(head) => {
+            drive.set_side(head);
+            Ok(())
+        }
+        Command::Read(head, cylinder, sector) => drive
+            .read_sector(head, cylinder, sector)
+            .map(|id| hex_dump(port, &id.data)),
+        Command::Write(head, cylinder, sector, pattern) => {
+            drive.write_sector(head, cylinder, sector, &[pattern; 512])
+        }
+        Command::Precomp(cylinder, ns) => drive.set_precompensation(cylinder, ns as u32),
+        Command::Verify(retries) => {
+            drive.set_write_verify(retries);
+            Ok(())
+        }
+        Command::Stats => match drive.motor_on() {
+            false => Err(FddError::MotorOff),
+            true => {
+                let counts = mfm_stats(drive.bus());
+                let names: [&[u8]; 3] = [b"pulse_10: ", b"pulse_100: ", b"pulse_1000: "];
+                for i in 0..3 {
+                    port.write(names[i]);
+                    port.write_dec(counts[i]);
+                    port.write(b"\r\n");
+                }
+                Ok(())
+            }
+        },
+        Command::ReadId => drive.read_id().map(|id| {
+            port.write(b"cylinder ");
+            port.write_dec(id.cylinder as u32);
+            port.write(b" head ");
+            port.write_dec(id.head as u32);
+            port.write(b" sector ");
+            port.write_dec(id.sector as u32);
+            port.write(b" size ");
+            port.write_dec(id.size as u32);
+            port.write(b"\r\n");
+        }),
+        Command::Rpm => drive.revolution_time().map(|time| {
+            port.write_dec(((60_000 * MS_TO_NANO + time / 2) / time) as u32);
+            port.write(b" rpm, ");
+            port.write_dec((time / 1000) as u32);
+            port.write(b"us per revolution\r\n");
+        }),
+        Command::Calibrate => drive.calibrate().map(|limits| {
+            let clock = drive.bus().flux_clock() as u64;
+            port.write(b"limits at ");
+            port.write_dec((limits.short as u64 * 1_000_000_000 / clock) as u32);
+            port.write(b"ns and ");
+            port.write_dec((limits.medium as u64 * 1_000_000_000 / clock) as u32);
+            port.write(b"ns\r\n");
+        }),
+        Command::Wp => {
+            match drive.read_write_protect() {
+                true => port.write(b"write protected\r\n"),
+                false => port.write(b"not write protected\r\n"),
+            }
+            Ok(())
+        }
+        Command::Dump => {
+            port.write(b"start a YMODEM receive now\r\n");
+            let result = dump_image(drive, port);
+            return finish_transfer(port, result);
+        }
+        Command::Restore => {
+            port.write(b"start a YMODEM send now\r\n");
+            let result = restore_image(drive, port);
+            return finish_transfer(port, result);
+        }
+        Command::Help => {
+            port.write(HELP);
+            Ok(())
+        }
+    };
+
+    match result {
+        Ok(_) => port.write(b"ok\r\n"),
+        Err(error) => {
+            port.write(b"error: ");
+            port.write(error.message());
+            port.write(b"\r\n");
+        }
+    }
+}
+
+/**
+ * A line editor on a serial port which runs each command it
+ * is given against the drive.
+ */
+pub struct Shell {
+    line: [u8; SHELL_LINE_LEN],
+    len: usize,
+    last: u8,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        return Shell {
+            line: [0; SHELL_LINE_LEN],
+            len: 0,
+            last: 0,
+        };
+    }
+
+    /**
+     * Greet whoever is on the other end and show the prompt.
+     */
+    pub fn start<P: SerialPort>(&mut self, port: &mut P) {
+        port.write(b"floppy shell, type help for a list of commands\r\n");
+        port.write(PROMPT);
+    }
+
+    /**
+     * Handle everything typed so far. Characters are echoed back
+     * and a command runs once its line is finished.
+     */
+    pub fn poll<B: FloppyBus, P: SerialPort>(&mut self, drive: &mut FloppyDrive<B>, port: &mut P) {
+        while let Some(byte) = port.read() {
+            match byte {
+                // Treat \r\n as a single line ending
+                b'\n' if self.last == b'\r' => {}
+                b'\r' | b'\n' => {
+                    port.write(b"\r\n");
+                    match parse_command(&self.line[0..self.len]) {
+                        Ok(command) => execute(command, drive, port),
+                        Err(ParseError::Empty) => {}
+                        Err(error) => {
+                            port.write(b"error: ");
+                            port.write(error.message());
+                            port.write(b"\r\n");
+                        }
+                    }
+
+                    self.len = 0;
+                    port.write(PROMPT);
+                }
+                // Backspace and delete
+                0x08 | 0x7F => {
+                    if self.len > 0 {
+                        self.len -= 1;
+                        port.write(b"\x08 \x08");
+                    }
+                }
+                0x20..=0x7E => {
+                    if self.len < self.line.len() {
+                        self.line[self.len] = byte;
+                        self.len += 1;
+                        port.write(&[byte]);
+                    }
+                }
+                _ => {}
+            }
+
+            self.last = byte;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_shell {
+    use super::*;
+    use crate::geometry::*;
+    use crate::mock::*;
+    use std::string::String;
+
+    fn run(
+        shell: &mut Shell,
+        drive: &mut FloppyDrive<MockBus>,
+        port: &mut MockSerial,
+        line: &str,
+    ) -> String {
+        port.send(line.as_bytes());
+        shell.poll(drive, port);
+        return String::from_utf8(port.take()).unwrap();
+    }
+
+    #[test]
+    pub fn test_parse() {
+        assert_eq!(parse_command(b"motor on"), Ok(Command::Motor(true)));
+        assert_eq!(parse_command(b"  motor\toff "), Ok(Command::Motor(false)));
+        assert_eq!(parse_command(b"seek 79"), Ok(Command::Seek(79)));
+        assert_eq!(parse_command(b"side 1"), Ok(Command::Side(1)));
+        assert_eq!(parse_command(b"read 0 7 2"), Ok(Command::Read(0, 7, 2)));
+        assert_eq!(
+            parse_command(b"write 1 0x10 18 0xe5"),
+            Ok(Command::Write(1, 16, 18, 0xE5))
+        );
+        assert_eq!(parse_command(b"readid"), Ok(Command::ReadId));
+        assert_eq!(parse_command(b"wp"), Ok(Command::Wp));
+        assert_eq!(parse_command(b"calibrate"), Ok(Command::Calibrate));
+        assert_eq!(
+            parse_command(b"precomp 40 125"),
+            Ok(Command::Precomp(40, 125))
+        );
+        assert_eq!(
+            parse_command(b"precomp 40 400"),
+            Ok(Command::Precomp(40, 400))
+        );
+        assert_eq!(
+            parse_command(b"precomp 256 100"),
+            Err(ParseError::BadNumber)
+        );
+        assert_eq!(parse_command(b"verify 3"), Ok(Command::Verify(Some(3))));
+        assert_eq!(parse_command(b"verify off"), Ok(Command::Verify(None)));
+
+        assert_eq!(parse_command(b"   "), Err(ParseError::Empty));
+        assert_eq!(parse_command(b"format"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse_command(b"motor"), Err(ParseError::WrongArguments));
+        assert_eq!(
+            parse_command(b"motor maybe"),
+            Err(ParseError::WrongArguments)
+        );
+        assert_eq!(parse_command(b"rpm 300"), Err(ParseError::WrongArguments));
+        assert_eq!(parse_command(b"read 0 7"), Err(ParseError::WrongArguments));
+        assert_eq!(parse_command(b"seek 256"), Err(ParseError::BadNumber));
+        assert_eq!(parse_command(b"seek 0x"), Err(ParseError::BadNumber));
+        assert_eq!(parse_command(b"seek -1"), Err(ParseError::BadNumber));
+        assert_eq!(parse_command(b"side 2"), Err(ParseError::BadNumber));
+    }
+
+    #[test]
+    pub fn test_session() {
+        let image: std::vec::Vec<u8> = (0..2880 * 512).map(|i| (i / 512) as u8).collect();
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY_1440K, &image);
+        bus.cylinder = 30;
+
+        let mut drive = FloppyDrive::new(bus);
+        let mut port = MockSerial::new();
+        let mut shell = Shell::new();
+        shell.start(&mut port);
+        assert!(port.take().ends_with(b"> "));
+
+        assert_eq!(
+            run(&mut shell, &mut drive, &mut port, "wp\r\n"),
+            "wp\r\nnot write protected\r\nok\r\n> "
+        );
+        assert_eq!(
+            run(&mut shell, &mut drive, &mut port, "stats\r"),
+            "stats\r\nerror: motor is not up to speed\r\n> "
+        );
+        assert_eq!(
+            run(&mut shell, &mut drive, &mut port, "motor on\r"),
+            "motor on\r\nok\r\n> "
+        );
+        assert_eq!(drive.bus().cylinder, 0);
+
+        // Typing mistakes can be rubbed out
+        assert_eq!(
+            run(&mut shell, &mut drive, &mut port, "seeq\x08k 3\r"),
+            "seeq\x08 \x08k 3\r\nok\r\n> "
+        );
+        assert_eq!(drive.bus().cylinder, 3);
+        assert_eq!(
+            run(&mut shell, &mut drive, &mut port, "seek 200\r"),
+            "seek 200\r\nerror: invalid argument\r\n> "
+        );
+        assert_eq!(drive.bus().cylinder, 3);
+        assert!(run(&mut shell, &mut drive, &mut port, "recal\r").contains("track 0 after 3 steps"));
+
+        let dump = run(&mut shell, &mut drive, &mut port, "read 0 0 2\r");
+        assert!(dump.contains(
+            "0000  01 01 01 01 01 01 01 01 01 01 01 01 01 01 01 01  ................\r\n"
+        ));
+        assert!(dump.contains("01F0  01 01"));
+        assert!(dump.ends_with("ok\r\n> "));
+
+        assert_eq!(
+            run(&mut shell, &mut drive, &mut port, "write 0 0 2 0x41\r"),
+            "write 0 0 2 0x41\r\nok\r\n> "
+        );
+        let dump = run(&mut shell, &mut drive, &mut port, "read 0 0 2\r");
+        assert!(dump.contains(
+            "0000  41 41 41 41 41 41 41 41 41 41 41 41 41 41 41 41  AAAAAAAAAAAAAAAA\r\n"
+        ));
+
+        let id = run(&mut shell, &mut drive, &mut port, "readid\r");
+        assert!(id.contains("cylinder 0 head 0 sector "));
+        assert!(run(&mut shell, &mut drive, &mut port, "rpm\r").contains("300 rpm, "));
+        assert!(run(&mut shell, &mut drive, &mut port, "stats\r").contains("pulse_10: "));
+
+        assert_eq!(
+            run(&mut shell, &mut drive, &mut port, "read 0 0 19\r"),
+            "read 0 0 19\r\nerror: invalid argument\r\n> "
+        );
+        assert_eq!(
+            run(&mut shell, &mut drive, &mut port, "eject\r"),
+            "eject\r\nerror: unknown command, try help\r\n> "
+        );
+        assert_eq!(run(&mut shell, &mut drive, &mut port, "\r"), "\r\n> ");
+    }
+}
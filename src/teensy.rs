@@ -0,0 +1,583 @@
+use crate::bus::*;
+use crate::config::*;
+use crate::mfm::*;
+use crate::pll::*;
+use core::arch::asm;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use teensycore::prelude::*;
+
+global_asm!(include_str!("mfm.S"));
+
+extern "C" {
+    pub fn _asm_pulse(cycles: u32);
+    pub fn _asm_wait(cycles: u32);
+    pub fn _asm_full_write_test();
+}
+
+// These timings are all for 500kbps and get scaled for slower media.
+// const CYCLES_PER_MICRO: u32 = F_CPU / 1000000;
+const T2: u32 = 544 * 2 / 3; //1.375 * CYCLES_PER_MICRO;
+const T3: u32 = 940 / 2; //2.375 * CYCLES_PER_MICRO;
+const T4: u32 = 1336 * 2 / 3; //3.375 * CYCLES_PER_MICRO;
+
+/**
+ * The write pulse widths are tuned by hand rather than worked out,
+ * so the delay loop counts per microsecond come from the spread
+ * between the shortest and longest pulse, two cells apart.
+ */
+const DELAY_PER_MICRO: u32 = (T4 - T2) / 2;
+
+/**
+ * What a pulse costs on top of its delay loop count, found by
+ * running the same line back from T2 to zero.
+ */
+const PULSE_OVERHEAD: u32 = 2 * DELAY_PER_MICRO - T2;
+
+/**
+ * The low part of every pulse, which _asm_pulse takes out of its
+ * count. Anything shorter would wrap around to an endless wait.
+ */
+const PULSE_LOW: u32 = 148;
+
+/**
+ * The write pin is toggled from the middle of _asm_pulse, which
+ * can't be handed a bus, so it has to live here. That is also
+ * why only one TeensyBus may exist at a time.
+ */
+static mut WRITE_FAST: FastPin = FastPin::new(DEFAULT_PINS.write);
+
+/** Set while a TeensyBus owns the pins */
+static BUS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+#[no_mangle]
+#[link_section = ".text"]
+#[inline(never)]
+pub fn data_low() {
+    unsafe { WRITE_FAST.clear() };
+}
+
+#[no_mangle]
+#[link_section = ".text"]
+#[inline(never)]
+pub fn data_high() {
+    unsafe { WRITE_FAST.set() };
+}
+
+/**
+ * Why a TeensyBus couldn't be created.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BusError {
+    /// Another TeensyBus already owns the pins
+    InUse,
+    /// The read pin doesn't reach a quad timer input
+    ReadPin,
+}
+
+impl BusError {
+    pub fn message(&self) -> &'static [u8] {
+        return match self {
+            BusError::InUse => b"the floppy bus is already in use",
+            BusError::ReadPin => b"the read pin must be 10, 11 or 12",
+        };
+    }
+}
+
+/** The quad timers count the 150MHz IPG clock */
+const FLUX_CLOCK: u32 = 150_000_000;
+
+/** Quad timer 1, whose four channels are 0x20 apart */
+const TMR1: u32 = 0x401D_C000;
+const TMR_CHANNEL: u32 = 0x20;
+const TMR_CAPT: u32 = 0x04;
+const TMR_LOAD: u32 = 0x06;
+const TMR_CNTR: u32 = 0x0A;
+const TMR_CTRL: u32 = 0x0C;
+const TMR_SCTRL: u32 = 0x0E;
+
+/** Count rising edges of the IPG clock, undivided */
+const CTRL_COUNT_IPG: u16 = (0b001 << 13) | (0b1000 << 9);
+
+/** The SCTRL flags, which are cleared by writing a zero */
+const SCTRL_TCF: u16 = 1 << 15;
+const SCTRL_TOF: u16 = 1 << 13;
+const SCTRL_IEF: u16 = 1 << 11;
+const SCTRL_FLAGS: u16 = SCTRL_TCF | SCTRL_TOF | SCTRL_IEF;
+
+/** Latch the count on a falling edge, which is where a flux pulse starts */
+const SCTRL_CAPTURE_FALLING: u16 = 0b10 << 6;
+
+/** The clock gate for quad timer 1 */
+const CCM_CCGR6: u32 = 0x400F_C080;
+const CCGR6_QTIMER1: u32 = 0b11 << 26;
+
+/** Hand a pad to its first alt function, leaving the gpio able to read it */
+const MUX_ALT1_SION: u32 = 0x1 | (1 << 4);
+
+/** The teensy pins which reach a quad timer 1 input, as (pin, channel, pad mux register) */
+const CAPTURE_PINS: [(usize, u32, u32); 3] = [
+    (10, 0, 0x401F_813C),
+    (12, 1, 0x401F_8140),
+    (11, 2, 0x401F_8144),
+];
+
+/**
+ * A quad timer channel which free runs off the IPG clock and
+ * latches its count whenever a flux pulse arrives on the read pin.
+ * Intervals are the difference between two captures, so they
+ * don't depend on how quickly the code gets around to reading
+ * them, as long as it does so before the next pulse.
+ */
+struct FluxTimer {
+    base: u32,
+    last: u16,
+}
+
+impl FluxTimer {
+    /**
+     * Find the timer channel behind a pin, which has to be one
+     * of CAPTURE_PINS.
+     */
+    fn channel(pin: usize) -> Result<(u32, u32), BusError> {
+        return match CAPTURE_PINS.iter().find(|entry| entry.0 == pin) {
+            Some((_, channel, mux)) => Ok((*channel, *mux)),
+            None => Err(BusError::ReadPin),
+        };
+    }
+
+    fn new((channel, mux): (u32, u32)) -> Self {
+        let timer = FluxTimer {
+            base: TMR1 + channel * TMR_CHANNEL,
+            last: 0,
+        };
+
+        unsafe {
+            let gate = CCM_CCGR6 as *mut u32;
+            gate.write_volatile(gate.read_volatile() | CCGR6_QTIMER1);
+            (mux as *mut u32).write_volatile(MUX_ALT1_SION);
+        }
+
+        // Stop the channel while it's set up, then capture from its own pin
+        timer.write(TMR_CTRL, 0);
+        timer.write(TMR_LOAD, 0);
+        timer.write(TMR_CNTR, 0);
+        timer.write(TMR_SCTRL, SCTRL_CAPTURE_FALLING);
+        timer.write(TMR_CTRL, CTRL_COUNT_IPG | ((channel as u16) << 7));
+        return timer;
+    }
+
+    fn read(&self, register: u32) -> u16 {
+        return unsafe { ((self.base + register) as *const u16).read_volatile() };
+    }
+
+    fn write(&self, register: u32, value: u16) {
+        unsafe { ((self.base + register) as *mut u16).write_volatile(value) };
+    }
+
+    /** Clear one of the SCTRL flags without disturbing the others */
+    fn clear(&self, sctrl: u16, flag: u16) {
+        self.write(TMR_SCTRL, (sctrl | SCTRL_FLAGS) & !flag);
+    }
+
+    /**
+     * Wait for the next capture and return the ticks since the
+     * one before. The counter only holds 16 bits (437us), so
+     * longer gaps are made up by counting its overflows.
+     */
+    fn next(&mut self) -> u32 {
+        let mut overflows: u32 = 0;
+        loop {
+            let sctrl = self.read(TMR_SCTRL);
+            if sctrl & SCTRL_TOF > 0 {
+                self.clear(sctrl, SCTRL_TOF);
+                overflows += 1;
+            }
+
+            if sctrl & SCTRL_IEF > 0 {
+                let capture = self.read(TMR_CAPT);
+                self.clear(sctrl, SCTRL_IEF);
+
+                // A capture below the last one used up an overflow
+                if capture < self.last {
+                    overflows = overflows.saturating_sub(1);
+                }
+
+                let ticks = capture.wrapping_sub(self.last) as u32 + (overflows << 16);
+                self.last = capture;
+                return ticks;
+            }
+        }
+    }
+}
+
+/**
+ * The floppy bus wired straight to the teensy pins.
+ */
+pub struct TeensyBus {
+    pins: PinMap,
+    /// Direct gpio access for the pins polled in the timing critical code.
+    /// These bypass pin_read and pin_out in teensycore, which are too slow.
+    index_fast: FastPin,
+    read_fast: FastPin,
+    gate_fast: FastPin,
+    /// The write pulse widths handed to _asm_pulse, as (T2, T3, T4)
+    widths: (u32, u32, u32),
+    debug: bool,
+    timer: FluxTimer,
+    pll: Pll,
+    /// Write precompensation, in delay loop counts
+    precompensation: u32,
+}
+
+impl TeensyBus {
+    /**
+     * Configure pull-ups and set a default value on every
+     * pin in the map. Fails if another TeensyBus is still around,
+     * or if the read pin can't be timed.
+     */
+    pub fn new(pins: PinMap) -> Result<Self, BusError> {
+        let capture = FluxTimer::channel(pins.read)?;
+        if BUS_TAKEN.swap(true, Ordering::Acquire) {
+            return Err(BusError::InUse);
+        }
+
+        // Create a generic configuration for normal pins
+        let generic_config: PadConfig = PadConfig {
+            hysterisis: false,
+            resistance: PullUpDown::PullUp100k,
+            pull_keep: PullKeep::Keeper,
+            pull_keep_en: false,
+            open_drain: false,
+            speed: PinSpeed::Max200MHz,
+            drive_strength: DriveStrength::Max,
+            fast_slew_rate: true,
+        };
+
+        pin_pad_config(pins.gate, generic_config.clone());
+        pin_pad_config(pins.dir, generic_config.clone());
+        pin_pad_config(pins.step, generic_config.clone());
+        pin_pad_config(pins.head_sel, generic_config.clone());
+        pin_pad_config(pins.drive, generic_config.clone());
+        pin_pad_config(pins.motor, generic_config.clone());
+        pin_pad_config(pins.write, generic_config.clone());
+
+        pin_out(pins.drive, Power::High);
+        pin_out(pins.motor, Power::High);
+        pin_out(pins.dir, Power::High);
+        pin_out(pins.step, Power::High);
+        pin_out(pins.head_sel, Power::High);
+        pin_out(pins.gate, Power::High);
+        pin_out(pins.write, Power::High);
+
+        pin_mode(pins.dir, Mode::Output);
+        pin_mode(pins.step, Mode::Output);
+        pin_mode(pins.gate, Mode::Output);
+        pin_mode(pins.head_sel, Mode::Output);
+        pin_mode(pins.write, Mode::Output);
+        pin_mode(pins.drive, Mode::Output);
+        pin_mode(pins.motor, Mode::Output);
+
+        // Create a generic configuration for pullup resistors
+        let pullup_config: PadConfig = PadConfig {
+            hysterisis: false,
+            resistance: PullUpDown::PullUp22k,
+            pull_keep: PullKeep::Pull,
+            pull_keep_en: true,
+            open_drain: true,
+            speed: PinSpeed::Max200MHz,
+            drive_strength: DriveStrength::Max,
+            fast_slew_rate: true,
+        };
+
+        // Set them to outputs
+        pin_mode(pins.index, Mode::Input);
+        pin_mode(pins.track00, Mode::Input);
+        pin_mode(pins.write_protect, Mode::Input);
+        pin_mode(pins.ready, Mode::Input);
+        pin_mode(pins.read, Mode::Input);
+        pin_pad_config(pins.index, pullup_config.clone());
+        pin_pad_config(pins.track00, pullup_config.clone());
+        pin_pad_config(pins.write_protect, pullup_config.clone());
+        pin_pad_config(pins.ready, pullup_config.clone());
+        pin_pad_config(pins.read, pullup_config.clone());
+
+        // The read pin is timed by a quad timer, and the gpio
+        // registers are still used to poll it directly
+        let timer = FluxTimer::new(capture);
+
+        // Derive the gpio register used by the assembly
+        unsafe {
+            WRITE_FAST = FastPin::new(pins.write);
+        }
+
+        return Ok(TeensyBus {
+            pins: pins,
+            index_fast: FastPin::new(pins.index),
+            read_fast: FastPin::new(pins.read),
+            gate_fast: FastPin::new(pins.gate),
+            widths: (T2, T3, T4),
+            debug: true,
+            timer: timer,
+            pll: Pll::new(DataRate::Kbps500, FLUX_CLOCK),
+            precompensation: 0,
+        });
+    }
+
+    /** The delay loop count for a write pulse */
+    #[inline(always)]
+    fn width(&self, sym: &Symbol) -> u32 {
+        return match sym {
+            Symbol::Pulse10 => self.widths.0,
+            Symbol::Pulse100 => self.widths.1,
+            Symbol::Pulse1000 => self.widths.2,
+        };
+    }
+
+    /**
+     * Every width a precompensated pulse can take, indexed by the
+     * slot of the symbol before it, the symbol itself and the one
+     * after it.
+     */
+    fn precompensated_widths(&self) -> [[[u32; 3]; 3]; 3] {
+        let symbols = [Symbol::Pulse10, Symbol::Pulse100, Symbol::Pulse1000];
+        let mut table = [[[0; 3]; 3]; 3];
+        for before in symbols {
+            for this in symbols {
+                for after in symbols {
+                    let adjust = mfm_precompensate(&[before, this, after], 1, self.precompensation);
+                    table[slot(&before)][slot(&this)][slot(&after)] =
+                        (self.width(&this) as i32 + adjust) as u32;
+                }
+            }
+        }
+
+        return table;
+    }
+}
+
+/** Where a symbol goes in the precompensated width table */
+#[inline(always)]
+fn slot(sym: &Symbol) -> usize {
+    return sym.cells() as usize - 2;
+}
+
+impl Drop for TeensyBus {
+    fn drop(&mut self) {
+        BUS_TAKEN.store(false, Ordering::Release);
+    }
+}
+
+/** Every floppy signal is active low */
+fn level(active: bool) -> Power {
+    return match active {
+        true => Power::Low,
+        false => Power::High,
+    };
+}
+
+impl FloppyBus for TeensyBus {
+    fn set_step(&mut self, active: bool) {
+        pin_out(self.pins.step, level(active));
+    }
+
+    fn set_dir(&mut self, inward: bool) {
+        pin_out(self.pins.dir, level(inward));
+    }
+
+    fn set_side(&mut self, head: u8) {
+        pin_out(self.pins.head_sel, level(head != 0));
+    }
+
+    fn set_motor(&mut self, on: bool) {
+        pin_out(self.pins.motor, level(on));
+    }
+
+    fn set_select(&mut self, selected: bool) {
+        pin_out(self.pins.drive, level(selected));
+    }
+
+    fn set_gate(&mut self, open: bool) {
+        pin_out(self.pins.gate, level(open));
+    }
+
+    fn set_write_data(&mut self, active: bool) {
+        pin_out(self.pins.write, level(active));
+    }
+
+    fn index(&mut self) -> bool {
+        return self.index_fast.read() == 0;
+    }
+
+    fn track00(&mut self) -> bool {
+        return pin_read(self.pins.track00) == 0;
+    }
+
+    fn write_protected(&mut self) -> bool {
+        return pin_read(self.pins.write_protect) == 0;
+    }
+
+    fn ready(&mut self) -> bool {
+        return pin_read(self.pins.ready) == 0;
+    }
+
+    fn read_data(&mut self) -> bool {
+        return self.read_fast.read() == 0;
+    }
+
+    fn nanos(&mut self) -> uNano {
+        return nanos();
+    }
+
+    fn wait_ns(&mut self, ns: uNano) {
+        wait_exact_ns(ns);
+    }
+
+    /**
+     * Derive the data separator clock and write pulse widths from
+     * the data rate of the media.
+     */
+    fn set_data_rate(&mut self, rate: DataRate) {
+        self.pll = Pll::new(rate, FLUX_CLOCK);
+        self.widths = (rate.scale(T2), rate.scale(T3), rate.scale(T4));
+    }
+
+    fn flux_clock(&self) -> u32 {
+        return FLUX_CLOCK;
+    }
+
+    #[inline(always)]
+    fn read_interval(&mut self) -> u32 {
+        return self.timer.next();
+    }
+
+    /**
+     * Intervals go through the data separator, which follows the
+     * drive's actual bit rate.
+     */
+    #[inline(always)]
+    fn read_symbol(&mut self) -> Symbol {
+        return Symbol::from_cells(self.read_cells());
+    }
+
+    #[inline(always)]
+    fn read_cells(&mut self) -> u32 {
+        return self.pll.push(self.timer.next());
+    }
+
+    fn reset_separator(&mut self) {
+        self.pll.reset();
+    }
+
+    fn set_pulse_limits(&mut self, limits: &PulseLimits) {
+        self.pll.calibrate(limits);
+    }
+
+    fn set_precompensation(&mut self, ns: u32) {
+        self.precompensation = ns * DELAY_PER_MICRO / 1000;
+    }
+
+    /**
+     * This method will commit a series of flux signals to the floppy disk,
+     * but it assumes you're already in the right spot. Be sure to call
+     * sync() before invoking this method.
+     *
+     * Precompensated widths are worked out before the gate opens,
+     * so each pulse only costs a table lookup, about the same as
+     * picking a width in the plain loop.
+     */
+    #[inline(never)]
+    fn write_flux(&mut self, flux_signals: &[Symbol]) {
+        let table = self.precompensated_widths();
+
+        self.gate_fast.clear();
+        if self.precompensation == 0 {
+            for sym in flux_signals {
+                unsafe { _asm_pulse(self.width(sym)) };
+            }
+        } else if let Some(first) = flux_signals.first() {
+            // The symbols at either end have no neighbour to be
+            // pushed away from, just as if it were the same length
+            let mut before = slot(first);
+            let mut this = before;
+            for sym in &flux_signals[1..] {
+                let after = slot(sym);
+                unsafe { _asm_pulse(table[before][this][after]) };
+                before = this;
+                this = after;
+            }
+            unsafe { _asm_pulse(table[before][this][this]) };
+        }
+        self.gate_fast.set();
+        data_high();
+    }
+
+    /**
+     * Intervals are turned into delay loop counts along the same
+     * line as the tuned pulse widths. The multiply costs about the
+     * same as picking a width in write_flux, which is already
+     * folded into PULSE_OVERHEAD.
+     */
+    #[inline(never)]
+    fn write_intervals(&mut self, intervals: &[u16], clock: u32) {
+        // Delay loop counts per tick, with 16 bits of fraction
+        let scale = ((DELAY_PER_MICRO as u64) << 16) * 1_000_000 / clock as u64;
+        let continues = ((INTERVAL_CONTINUES as u64 * scale) >> 16) as u32;
+
+        self.gate_fast.clear();
+        for interval in intervals {
+            unsafe {
+                if *interval == INTERVAL_CONTINUES {
+                    _asm_wait(continues);
+                } else {
+                    let counts = ((*interval as u64 * scale) >> 16) as u32;
+                    _asm_pulse(counts.saturating_sub(PULSE_OVERHEAD).max(PULSE_LOW));
+                }
+            }
+        }
+        self.gate_fast.set();
+        data_high();
+    }
+
+    fn debug_str(&mut self, message: &[u8]) {
+        if self.debug {
+            debug_str(message);
+        }
+    }
+
+    fn set_debug(&mut self, enabled: bool) {
+        self.debug = enabled;
+    }
+}
+
+/** The most bytes handed to the USB stack before flushing */
+const USB_CHUNK: usize = 256;
+
+/** How long to wait for the USB stack to take a chunk */
+const USB_FLUSH_TIMEOUT: uNano = MS_TO_NANO;
+
+/**
+ * The USB serial port the teensy enumerates as.
+ */
+pub struct UsbSerial;
+
+impl SerialPort for UsbSerial {
+    fn read(&mut self) -> Option<u8> {
+        return usb_serial_read();
+    }
+
+    /**
+     * The USB stack drops bytes once its staging buffer is full,
+     * so feed it in chunks and flush each one out.
+     */
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(USB_CHUNK) {
+            usb_serial_write(chunk);
+
+            let start = nanos();
+            while usb_serial_flush() == 0 && (nanos() - start) < USB_FLUSH_TIMEOUT {
+                assembly!("nop");
+            }
+        }
+    }
+}
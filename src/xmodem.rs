@@ -0,0 +1,1280 @@
+use crate::bus::*;
+use crate::crc::*;
+use crate::fdd::*;
+use crate::geometry::*;
+use teensycore::prelude::*;
+
+/**
+ * Moving whole disks to and from a PC as raw sector images over
+ * YMODEM, which is XMODEM-1K with a header block naming each file.
+ * Plain XMODEM senders are accepted when restoring.
+ *
+ * The protocol itself lives in YmodemSender and YmodemReceiver,
+ * which are fed the bytes from the other end and say what to send
+ * back. They never touch a port, so they can be run against each
+ * other on the host.
+ */
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1A;
+
+/** The receiver asks for CRC-16 blocks with this */
+const CRC_MODE: u8 = b'C';
+
+/** Payload of a 1K block */
+const BLOCK_LEN: usize = 1024;
+
+/** Block type, number, its complement, payload and crc */
+const PACKET_LEN: usize = 3 + BLOCK_LEN + 2;
+
+/** How often a block may fail before giving up */
+const MAX_RETRIES: u8 = 10;
+
+/** How many timeouts to sit through before the other end starts */
+const START_RETRIES: u8 = 20;
+
+/** How long to wait to hear from the other end */
+const XMODEM_TIMEOUT: uNano = 3000 * MS_TO_NANO;
+
+/** How many bad sectors are listed individually */
+pub const MAX_BAD_SECTORS: usize = 64;
+
+/** The name the image goes by on the PC */
+const IMAGE_NAME: &[u8] = b"disk.img";
+
+/** The name of the list of bad sectors which follows the image */
+const TRAILER_NAME: &[u8] = b"badsect.txt";
+
+/**
+ * Why a transfer stopped.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum XmodemError {
+    /// The other end cancelled the transfer
+    Cancelled,
+    /// The other end stopped answering, or kept rejecting a block
+    TooManyRetries,
+    /// The image is not the size of the media
+    WrongSize,
+    /// The drive can't carry on
+    Drive(FddError),
+}
+
+impl XmodemError {
+    pub fn message(&self) -> &'static [u8] {
+        return match self {
+            XmodemError::Cancelled => b"transfer cancelled",
+            XmodemError::TooManyRetries => b"too many retries",
+            XmodemError::WrongSize => b"image does not match the media",
+            XmodemError::Drive(error) => error.message(),
+        };
+    }
+}
+
+/**
+ * A sector which couldn't be read or written.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BadSector {
+    pub cylinder: u8,
+    pub head: u8,
+    pub sector: u8,
+    pub error: FddError,
+}
+
+/**
+ * How a dump or restore went.
+ */
+pub struct TransferReport {
+    /// Sectors transferred, good or bad
+    pub sectors: u32,
+    /// The first bad sectors
+    pub bad: [BadSector; MAX_BAD_SECTORS],
+    /// How many sectors were bad in total
+    pub bad_count: usize,
+}
+
+impl TransferReport {
+    pub fn new() -> Self {
+        return TransferReport {
+            sectors: 0,
+            bad: [BadSector {
+                cylinder: 0,
+                head: 0,
+                sector: 0,
+                error: FddError::SectorNotFound,
+            }; MAX_BAD_SECTORS],
+            bad_count: 0,
+        };
+    }
+
+    fn add_bad(&mut self, geometry: &Geometry, lba: u32, error: FddError) {
+        if self.bad_count < MAX_BAD_SECTORS {
+            let (cylinder, head, sector) = geometry.lba_to_chs(lba).unwrap_or((0, 0, 0));
+            self.bad[self.bad_count] = BadSector {
+                cylinder: cylinder,
+                head: head,
+                sector: sector,
+                error: error,
+            };
+        }
+        self.bad_count += 1;
+    }
+
+    /**
+     * List the bad sectors, one to a line.
+     */
+    pub fn write_to<P: SerialPort>(&self, port: &mut P) {
+        for bad in self.bad[0..self.bad_count.min(MAX_BAD_SECTORS)].iter() {
+            port.write(b"cylinder ");
+            port.write_dec(bad.cylinder as u32);
+            port.write(b" head ");
+            port.write_dec(bad.head as u32);
+            port.write(b" sector ");
+            port.write_dec(bad.sector as u32);
+            port.write(b": ");
+            port.write(bad.error.message());
+            port.write(b"\r\n");
+        }
+
+        if self.bad_count > MAX_BAD_SECTORS {
+            port.write(b"and ");
+            port.write_dec((self.bad_count - MAX_BAD_SECTORS) as u32);
+            port.write(b" more\r\n");
+        }
+    }
+}
+
+/**
+ * Text collected in memory, so the trailer can be put together
+ * with the same helpers used to talk to the port.
+ */
+struct TextBuffer<const N: usize> {
+    text: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TextBuffer<N> {
+    fn new() -> Self {
+        return TextBuffer {
+            text: [0; N],
+            len: 0,
+        };
+    }
+
+    fn text(&self) -> &[u8] {
+        return &self.text[0..self.len];
+    }
+}
+
+impl<const N: usize> SerialPort for TextBuffer<N> {
+    fn read(&mut self) -> Option<u8> {
+        return None;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(self.text.len() - self.len);
+        self.text[self.len..self.len + len].copy_from_slice(&bytes[0..len]);
+        self.len += len;
+    }
+}
+
+/**
+ * Put a block together. Payloads up to 128 bytes go in a short
+ * block, anything else in a 1K block, padded out with the given
+ * byte.
+ */
+fn build_packet(packet: &mut [u8; PACKET_LEN], number: u8, data: &[u8], pad: u8) -> usize {
+    let size = if data.len() <= 128 { 128 } else { BLOCK_LEN };
+    packet[0] = if size == 128 { SOH } else { STX };
+    packet[1] = number;
+    packet[2] = !number;
+    packet[3..3 + data.len()].copy_from_slice(data);
+    packet[3 + data.len()..3 + size].fill(pad);
+
+    let crc = crc16_update(0, &packet[3..3 + size]);
+    packet[3 + size] = (crc >> 8) as u8;
+    packet[4 + size] = (crc & 0xFF) as u8;
+    return 5 + size;
+}
+
+/**
+ * What the sender needs done after hearing from the receiver.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TxEvent {
+    /// Nothing to do yet
+    Pending,
+    /// Send the outgoing packet (again)
+    Transmit,
+    /// The receiver is ready for whatever comes next: a header,
+    /// data, the end of a file or the end of the batch
+    Ready,
+    /// The batch is over
+    Done,
+    /// The receiver cancelled
+    Cancelled,
+    /// The receiver stopped cooperating, cancel the transfer
+    Failed,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum TxState {
+    /// Waiting for the receiver to ask for a file
+    Start,
+    /// A packet is out, and this is where an ACK leads
+    Ack(AfterAck),
+    /// The caller has to send something
+    Idle,
+    Done,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum AfterAck {
+    Start,
+    Idle,
+    Done,
+}
+
+/**
+ * The sending half of YMODEM.
+ */
+pub struct YmodemSender {
+    state: TxState,
+    number: u8,
+    packet: [u8; PACKET_LEN],
+    len: usize,
+    retries: u8,
+}
+
+impl YmodemSender {
+    pub fn new() -> Self {
+        return YmodemSender {
+            state: TxState::Start,
+            number: 0,
+            packet: [0; PACKET_LEN],
+            len: 0,
+            retries: 0,
+        };
+    }
+
+    /**
+     * The packet to put on the wire.
+     */
+    pub fn outgoing(&self) -> &[u8] {
+        return &self.packet[0..self.len];
+    }
+
+    pub fn push(&mut self, byte: u8) -> TxEvent {
+        return match (self.state, byte) {
+            (TxState::Done, _) => TxEvent::Pending,
+            (_, CAN) => {
+                self.state = TxState::Done;
+                TxEvent::Cancelled
+            }
+            (TxState::Start, CRC_MODE) => {
+                self.retries = 0;
+                self.state = TxState::Idle;
+                TxEvent::Ready
+            }
+            (TxState::Ack(after), ACK) => {
+                self.retries = 0;
+                match after {
+                    AfterAck::Start => {
+                        self.state = TxState::Start;
+                        TxEvent::Pending
+                    }
+                    AfterAck::Idle => {
+                        self.state = TxState::Idle;
+                        TxEvent::Ready
+                    }
+                    AfterAck::Done => {
+                        self.state = TxState::Done;
+                        TxEvent::Done
+                    }
+                }
+            }
+            (TxState::Ack(_), NAK | CRC_MODE) => self.retry(),
+            _ => TxEvent::Pending,
+        };
+    }
+
+    /**
+     * Nothing arrived for a while.
+     */
+    pub fn timeout(&mut self) -> TxEvent {
+        return match self.state {
+            TxState::Ack(_) => self.retry(),
+            TxState::Start => {
+                self.retries += 1;
+                if self.retries > START_RETRIES {
+                    self.state = TxState::Done;
+                    return TxEvent::Failed;
+                }
+                TxEvent::Pending
+            }
+            _ => TxEvent::Pending,
+        };
+    }
+
+    fn retry(&mut self) -> TxEvent {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.state = TxState::Done;
+            return TxEvent::Failed;
+        }
+
+        return TxEvent::Transmit;
+    }
+
+    /**
+     * Announce a file with its name and size in bytes.
+     */
+    pub fn send_header(&mut self, name: &[u8], size: u32) -> &[u8] {
+        let mut header = TextBuffer::<128>::new();
+        header.write(name);
+        header.write(&[0]);
+        header.write_dec(size);
+
+        self.number = 0;
+        self.len = build_packet(&mut self.packet, 0, header.text(), 0);
+        self.state = TxState::Ack(AfterAck::Start);
+        return self.outgoing();
+    }
+
+    /**
+     * Send up to 1K of the file.
+     */
+    pub fn send_data(&mut self, data: &[u8]) -> &[u8] {
+        self.number = self.number.wrapping_add(1);
+        self.len = build_packet(&mut self.packet, self.number, data, SUB);
+        self.state = TxState::Ack(AfterAck::Idle);
+        return self.outgoing();
+    }
+
+    /**
+     * Finish the current file.
+     */
+    pub fn send_eot(&mut self) -> &[u8] {
+        self.packet[0] = EOT;
+        self.len = 1;
+        self.state = TxState::Ack(AfterAck::Start);
+        return self.outgoing();
+    }
+
+    /**
+     * Finish the batch with an empty header.
+     */
+    pub fn send_end(&mut self) -> &[u8] {
+        self.number = 0;
+        self.len = build_packet(&mut self.packet, 0, &[], 0);
+        self.state = TxState::Ack(AfterAck::Done);
+        return self.outgoing();
+    }
+}
+
+/**
+ * What the receiver needs done after hearing from the sender.
+ * Apart from Pending and Cancelled, every event comes with a
+ * reply which goes back once the event has been dealt with.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RxEvent {
+    /// Nothing to do yet
+    Pending,
+    /// Send the reply straight away
+    Transmit,
+    /// A file was announced (see size)
+    Header,
+    /// A block of the file arrived (see data)
+    Data,
+    /// The current file is complete
+    EndOfFile,
+    /// The sender has nothing more
+    Done,
+    /// The sender cancelled
+    Cancelled,
+    /// The sender stopped cooperating, cancel the transfer
+    Failed,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RxState {
+    /// Waiting for the start of a packet
+    Idle,
+    /// Collecting a packet of the given length
+    Packet(usize),
+    Done,
+}
+
+/**
+ * The receiving half of YMODEM, which also copes with plain
+ * XMODEM senders that go straight to block 1.
+ */
+pub struct YmodemReceiver {
+    state: RxState,
+    packet: [u8; PACKET_LEN],
+    len: usize,
+    reply: [u8; 2],
+    reply_len: usize,
+    /// True between a header (or the first block) and the EOT
+    in_file: bool,
+    /// False for a plain XMODEM sender
+    batch: bool,
+    started: bool,
+    expected: u8,
+    size: Option<u32>,
+    retries: u8,
+}
+
+impl YmodemReceiver {
+    pub fn new() -> Self {
+        return YmodemReceiver {
+            state: RxState::Idle,
+            packet: [0; PACKET_LEN],
+            len: 0,
+            reply: [0; 2],
+            reply_len: 0,
+            in_file: false,
+            batch: true,
+            started: false,
+            expected: 0,
+            size: None,
+            retries: 0,
+        };
+    }
+
+    /**
+     * Ask the sender to start.
+     */
+    pub fn start(&mut self) -> &[u8] {
+        return self.set_reply(&[CRC_MODE]);
+    }
+
+    /**
+     * What to send back to the sender.
+     */
+    pub fn reply(&self) -> &[u8] {
+        return &self.reply[0..self.reply_len];
+    }
+
+    /**
+     * The size given in the header of the current file, if any.
+     */
+    pub fn size(&self) -> Option<u32> {
+        return self.size;
+    }
+
+    /**
+     * The payload of the block which just arrived.
+     */
+    pub fn data(&self) -> &[u8] {
+        let size = self.len - 5;
+        return &self.packet[3..3 + size];
+    }
+
+    fn set_reply(&mut self, reply: &[u8]) -> &[u8] {
+        self.reply[0..reply.len()].copy_from_slice(reply);
+        self.reply_len = reply.len();
+        return self.reply();
+    }
+
+    pub fn push(&mut self, byte: u8) -> RxEvent {
+        match self.state {
+            RxState::Done => {
+                return RxEvent::Pending;
+            }
+            RxState::Idle => {
+                let length = match byte {
+                    SOH => 5 + 128,
+                    STX => PACKET_LEN,
+                    EOT => {
+                        return self.end_of_file();
+                    }
+                    CAN => {
+                        self.state = RxState::Done;
+                        return RxEvent::Cancelled;
+                    }
+                    _ => {
+                        return RxEvent::Pending;
+                    }
+                };
+
+                self.packet[0] = byte;
+                self.len = 1;
+                self.state = RxState::Packet(length);
+            }
+            RxState::Packet(length) => {
+                self.packet[self.len] = byte;
+                self.len += 1;
+                if self.len == length {
+                    self.state = RxState::Idle;
+                    return self.check_packet();
+                }
+            }
+        }
+
+        return RxEvent::Pending;
+    }
+
+    /**
+     * Nothing arrived for a while, so nudge the sender.
+     */
+    pub fn timeout(&mut self) -> RxEvent {
+        if self.state == RxState::Done {
+            return RxEvent::Pending;
+        }
+
+        // Whatever was half received is lost
+        self.state = RxState::Idle;
+        self.retries += 1;
+        if !self.started {
+            if self.retries > START_RETRIES {
+                self.state = RxState::Done;
+                return RxEvent::Failed;
+            }
+            self.set_reply(&[CRC_MODE]);
+        } else {
+            if self.retries > MAX_RETRIES {
+                self.state = RxState::Done;
+                return RxEvent::Failed;
+            }
+            self.set_reply(&[NAK]);
+        }
+
+        return RxEvent::Transmit;
+    }
+
+    fn reject(&mut self) -> RxEvent {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.state = RxState::Done;
+            return RxEvent::Failed;
+        }
+
+        self.set_reply(&[NAK]);
+        return RxEvent::Transmit;
+    }
+
+    fn check_packet(&mut self) -> RxEvent {
+        let size = self.len - 5;
+        let number = self.packet[1];
+        let crc = ((self.packet[3 + size] as u16) << 8) | self.packet[4 + size] as u16;
+        if self.packet[2] != !number || crc16_update(0, &self.packet[3..3 + size]) != crc {
+            return self.reject();
+        }
+
+        self.retries = 0;
+        if self.in_file && number == self.expected.wrapping_sub(1) {
+            // Our ACK went missing, so the sender tried again
+            self.set_reply(&[ACK]);
+            return RxEvent::Transmit;
+        }
+
+        if self.in_file && number == self.expected {
+            self.expected = self.expected.wrapping_add(1);
+            self.set_reply(&[ACK]);
+            return RxEvent::Data;
+        }
+
+        if !self.in_file && number == 0 {
+            self.started = true;
+            if self.packet[3] == 0 {
+                self.state = RxState::Done;
+                self.set_reply(&[ACK]);
+                return RxEvent::Done;
+            }
+
+            self.in_file = true;
+            self.expected = 1;
+            self.size = self.parse_size();
+            self.set_reply(&[ACK, CRC_MODE]);
+            return RxEvent::Header;
+        }
+
+        if !self.started && number == 1 {
+            // A plain XMODEM sender, with no header
+            self.started = true;
+            self.batch = false;
+            self.in_file = true;
+            self.expected = 2;
+            self.set_reply(&[ACK]);
+            return RxEvent::Data;
+        }
+
+        self.state = RxState::Done;
+        return RxEvent::Failed;
+    }
+
+    fn end_of_file(&mut self) -> RxEvent {
+        if !self.in_file {
+            // We already took the EOT, but the sender missed our ACK
+            self.set_reply(&[ACK, CRC_MODE]);
+            return RxEvent::Transmit;
+        }
+
+        self.in_file = false;
+        self.size = None;
+        if !self.batch {
+            self.state = RxState::Done;
+            self.set_reply(&[ACK]);
+            return RxEvent::Done;
+        }
+
+        self.set_reply(&[ACK, CRC_MODE]);
+        return RxEvent::EndOfFile;
+    }
+
+    /**
+     * The header is the file name, a zero byte and then the size
+     * in decimal, optionally followed by more fields.
+     */
+    fn parse_size(&self) -> Option<u32> {
+        let header = &self.packet[3..self.len - 2];
+        let name_end = header.iter().position(|byte| *byte == 0)?;
+        let mut size: Option<u32> = None;
+        for byte in header[name_end + 1..].iter() {
+            match byte {
+                b'0'..=b'9' => {
+                    size = Some(size.unwrap_or(0) * 10 + (byte - b'0') as u32);
+                }
+                _ => break,
+            }
+        }
+
+        return size;
+    }
+}
+
+/**
+ * Wait for the next byte from the other end.
+ */
+fn next_byte<B: FloppyBus, P: SerialPort>(drive: &mut FloppyDrive<B>, port: &mut P) -> Option<u8> {
+    let start = drive.bus().nanos();
+    loop {
+        if let Some(byte) = port.read() {
+            return Some(byte);
+        }
+
+        if (drive.bus().nanos() - start) > XMODEM_TIMEOUT {
+            return None;
+        }
+    }
+}
+
+fn status_error(status: SectorStatus) -> FddError {
+    return match status {
+        SectorStatus::IdCrc => FddError::IdCrc,
+        SectorStatus::DataCrc => FddError::DataCrc,
+        _ => FddError::SectorNotFound,
+    };
+}
+
+/**
+ * What the dump sends next.
+ */
+#[derive(Copy, Clone, PartialEq)]
+enum DumpStep {
+    ImageHeader,
+    Image,
+    TrailerHeader,
+    Trailer,
+    End,
+}
+
+/**
+ * Read the whole disk in geometry order and send it as a raw
+ * image. Unreadable sectors are sent as zeros, and once the image
+ * is across they are listed in a second file.
+ *
+ * Debug output is turned off while the transfer runs, as it would
+ * end up in the middle of the packets.
+ */
+pub fn dump_image<B: FloppyBus, P: SerialPort>(
+    drive: &mut FloppyDrive<B>,
+    port: &mut P,
+) -> Result<TransferReport, XmodemError> {
+    drive.bus().set_debug(false);
+    let result = send_image(drive, port);
+    drive.bus().set_debug(true);
+    return result;
+}
+
+fn send_image<B: FloppyBus, P: SerialPort>(
+    drive: &mut FloppyDrive<B>,
+    port: &mut P,
+) -> Result<TransferReport, XmodemError> {
+    let geometry = *drive.geometry();
+    let blocks = geometry.blocks();
+    let sector_size = geometry.sector_size();
+    if sector_size != 512 {
+        return Err(XmodemError::WrongSize);
+    }
+
+    // Tracks are read whole, so they have to fit in a track buffer
+    if geometry.sectors_per_track as usize > MAX_TRACK_SECTORS {
+        return Err(XmodemError::Drive(FddError::InvalidArgument));
+    }
+
+    let mut report = TransferReport::new();
+    let mut sender = YmodemSender::new();
+    let mut step = DumpStep::ImageHeader;
+    let mut track = TrackBuffer::new(&geometry);
+    let mut loaded: Option<(u8, u8)> = None;
+    let mut trailer = TextBuffer::<4096>::new();
+    let mut offset = 0;
+
+    loop {
+        let event = match next_byte(drive, port) {
+            Some(byte) => sender.push(byte),
+            None => sender.timeout(),
+        };
+
+        match event {
+            TxEvent::Pending => {}
+            TxEvent::Transmit => port.write(sender.outgoing()),
+            TxEvent::Ready => match step {
+                DumpStep::ImageHeader => {
+                    port.write(sender.send_header(IMAGE_NAME, blocks * sector_size as u32));
+                    step = DumpStep::Image;
+                }
+                DumpStep::Image if report.sectors < blocks => {
+                    let mut block = [0u8; BLOCK_LEN];
+                    for chunk in block.chunks_mut(sector_size) {
+                        let lba = report.sectors;
+                        let (cylinder, head, sector) = match geometry.lba_to_chs(lba) {
+                            Some(chs) => chs,
+                            None => break,
+                        };
+
+                        // Sectors come a whole track at a time
+                        if loaded != Some((cylinder, head)) {
+                            match drive.read_track(head, cylinder, &mut track) {
+                                Err(FddError::NoIndex) => {
+                                    port.write(&[CAN, CAN]);
+                                    return Err(XmodemError::Drive(FddError::NoIndex));
+                                }
+                                _ => {}
+                            }
+                            loaded = Some((cylinder, head));
+                        }
+
+                        match track.sector(sector) {
+                            Some(data) => chunk.copy_from_slice(data),
+                            None => {
+                                let slot = (sector - geometry.first_sector) as usize;
+                                report.add_bad(&geometry, lba, status_error(track.status[slot]));
+                            }
+                        }
+                        report.sectors += 1;
+                    }
+                    port.write(sender.send_data(&block));
+                }
+                DumpStep::Image => {
+                    port.write(sender.send_eot());
+                    step = match report.bad_count {
+                        0 => DumpStep::End,
+                        _ => DumpStep::TrailerHeader,
+                    };
+                }
+                DumpStep::TrailerHeader => {
+                    report.write_to(&mut trailer);
+                    port.write(sender.send_header(TRAILER_NAME, trailer.len as u32));
+                    step = DumpStep::Trailer;
+                }
+                DumpStep::Trailer if offset < trailer.len => {
+                    let end = (offset + BLOCK_LEN).min(trailer.len);
+                    port.write(sender.send_data(&trailer.text()[offset..end]));
+                    offset = end;
+                }
+                DumpStep::Trailer => {
+                    port.write(sender.send_eot());
+                    step = DumpStep::End;
+                }
+                DumpStep::End => {
+                    port.write(sender.send_end());
+                }
+            },
+            TxEvent::Done => {
+                return Ok(report);
+            }
+            TxEvent::Cancelled => {
+                return Err(XmodemError::Cancelled);
+            }
+            TxEvent::Failed => {
+                port.write(&[CAN, CAN]);
+                return Err(XmodemError::TooManyRetries);
+            }
+        }
+    }
+}
+
+/**
+ * Receive a raw image and write it back sector by sector, reading
+ * each one back to verify it. Sectors which fail are reported
+ * rather than stopping the transfer. Only the first file of a
+ * batch is written.
+ */
+pub fn restore_image<B: FloppyBus, P: SerialPort>(
+    drive: &mut FloppyDrive<B>,
+    port: &mut P,
+) -> Result<TransferReport, XmodemError> {
+    drive.bus().set_debug(false);
+    let result = receive_image(drive, port);
+    drive.bus().set_debug(true);
+    return result;
+}
+
+fn receive_image<B: FloppyBus, P: SerialPort>(
+    drive: &mut FloppyDrive<B>,
+    port: &mut P,
+) -> Result<TransferReport, XmodemError> {
+    let geometry = *drive.geometry();
+    let blocks = geometry.blocks();
+    let sector_size = geometry.sector_size();
+    if sector_size != 512 {
+        return Err(XmodemError::WrongSize);
+    }
+
+    let mut report = TransferReport::new();
+    let mut receiver = YmodemReceiver::new();
+    let mut files = 0;
+    let mut check = [0u8; 512];
+    port.write(receiver.start());
+
+    loop {
+        let event = match next_byte(drive, port) {
+            Some(byte) => receiver.push(byte),
+            None => receiver.timeout(),
+        };
+
+        match event {
+            RxEvent::Pending => {}
+            RxEvent::Header => {
+                files += 1;
+                if files == 1 && receiver.size() != Some(blocks * sector_size as u32) {
+                    port.write(&[CAN, CAN]);
+                    return Err(XmodemError::WrongSize);
+                }
+                port.write(receiver.reply());
+            }
+            RxEvent::Data => {
+                // A plain XMODEM sender skips the header
+                if files <= 1 {
+                    for chunk in receiver.data().chunks(sector_size) {
+                        let lba = report.sectors;
+                        if lba >= blocks || chunk.len() != sector_size {
+                            break;
+                        }
+
+                        let result = drive
+                            .write_block(lba, chunk)
+                            .and_then(|_| drive.read_block(lba, &mut check));
+                        match result {
+                            Err(error) => report.add_bad(&geometry, lba, error),
+                            Ok(_) if check[..] != *chunk => {
+                                report.add_bad(&geometry, lba, FddError::DataCrc)
+                            }
+                            Ok(_) => {}
+                        }
+                        report.sectors += 1;
+                    }
+                }
+                port.write(receiver.reply());
+            }
+            RxEvent::Transmit | RxEvent::EndOfFile => port.write(receiver.reply()),
+            RxEvent::Done => {
+                port.write(receiver.reply());
+                return Ok(report);
+            }
+            RxEvent::Cancelled => {
+                return Err(XmodemError::Cancelled);
+            }
+            RxEvent::Failed => {
+                port.write(&[CAN, CAN]);
+                return Err(XmodemError::TooManyRetries);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_xmodem {
+    use super::*;
+    use crate::mfm::*;
+    use crate::mock::*;
+    use std::collections::VecDeque;
+    use std::vec;
+    use std::vec::Vec;
+
+    const GEOMETRY: Geometry = Geometry {
+        cylinders: 2,
+        ..GEOMETRY_1440K
+    };
+
+    /**
+     * A file as it arrived at the host.
+     */
+    struct File {
+        name: Vec<u8>,
+        size: Option<u32>,
+        data: Vec<u8>,
+    }
+
+    /**
+     * A host receiving a batch, wired straight to the port.
+     */
+    struct ReceivingHost {
+        receiver: YmodemReceiver,
+        input: VecDeque<u8>,
+        files: Vec<File>,
+        /// Which of the bytes written to flip, to upset a crc
+        corrupt: Option<usize>,
+        written: usize,
+        naks: usize,
+        done: bool,
+    }
+
+    impl ReceivingHost {
+        fn new() -> Self {
+            let mut receiver = YmodemReceiver::new();
+            let input = receiver.start().iter().cloned().collect();
+            return ReceivingHost {
+                receiver: receiver,
+                input: input,
+                files: Vec::new(),
+                corrupt: None,
+                written: 0,
+                naks: 0,
+                done: false,
+            };
+        }
+    }
+
+    impl SerialPort for ReceivingHost {
+        fn read(&mut self) -> Option<u8> {
+            return self.input.pop_front();
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for byte in bytes {
+                let byte = match self.corrupt == Some(self.written) {
+                    true => !*byte,
+                    false => *byte,
+                };
+                self.written += 1;
+
+                match self.receiver.push(byte) {
+                    RxEvent::Pending | RxEvent::Cancelled | RxEvent::Failed => continue,
+                    RxEvent::Header => {
+                        let data = self.receiver.data();
+                        let name = data.iter().position(|byte| *byte == 0).unwrap();
+                        self.files.push(File {
+                            name: data[0..name].to_vec(),
+                            size: self.receiver.size(),
+                            data: Vec::new(),
+                        });
+                    }
+                    RxEvent::Data => {
+                        let data = self.receiver.data().to_vec();
+                        self.files.last_mut().unwrap().data.extend(data);
+                    }
+                    RxEvent::Transmit if self.receiver.reply() == [NAK] => self.naks += 1,
+                    RxEvent::Done => self.done = true,
+                    _ => {}
+                }
+                self.input.extend(self.receiver.reply().iter());
+            }
+        }
+    }
+
+    /**
+     * A host sending an image, wired straight to the port. Without
+     * a size it behaves like a plain XMODEM sender and skips the
+     * header.
+     */
+    struct SendingHost {
+        sender: YmodemSender,
+        input: VecDeque<u8>,
+        image: Vec<u8>,
+        size: Option<u32>,
+        offset: usize,
+        header: bool,
+        eot: bool,
+        done: bool,
+        cancelled: bool,
+    }
+
+    impl SendingHost {
+        fn new(image: Vec<u8>, size: Option<u32>) -> Self {
+            return SendingHost {
+                sender: YmodemSender::new(),
+                input: VecDeque::new(),
+                image: image,
+                size: size,
+                offset: 0,
+                header: false,
+                eot: false,
+                done: false,
+                cancelled: false,
+            };
+        }
+
+        fn next_packet(&mut self) -> Vec<u8> {
+            if let (Some(size), false) = (self.size, self.header) {
+                self.header = true;
+                return self.sender.send_header(b"disk.img", size).to_vec();
+            }
+
+            if self.offset < self.image.len() {
+                let end = (self.offset + BLOCK_LEN).min(self.image.len());
+                let packet = self.sender.send_data(&self.image[self.offset..end]);
+                self.offset = end;
+                return packet.to_vec();
+            }
+
+            if !self.eot {
+                self.eot = true;
+                return self.sender.send_eot().to_vec();
+            }
+
+            return self.sender.send_end().to_vec();
+        }
+    }
+
+    impl SerialPort for SendingHost {
+        fn read(&mut self) -> Option<u8> {
+            return self.input.pop_front();
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for byte in bytes {
+                let packet = match self.sender.push(*byte) {
+                    TxEvent::Ready => self.next_packet(),
+                    TxEvent::Transmit => self.sender.outgoing().to_vec(),
+                    TxEvent::Done => {
+                        self.done = true;
+                        continue;
+                    }
+                    TxEvent::Cancelled => {
+                        self.cancelled = true;
+                        continue;
+                    }
+                    _ => continue,
+                };
+                self.input.extend(packet);
+            }
+        }
+    }
+
+    fn test_image() -> Vec<u8> {
+        let mut image = vec![0u8; GEOMETRY.blocks() as usize * 512];
+        for (i, block) in image.chunks_mut(512).enumerate() {
+            block.fill(i as u8 ^ 0x5A);
+        }
+        return image;
+    }
+
+    fn drive_with(image: &[u8]) -> FloppyDrive<MockBus> {
+        let mut bus = MockBus::new();
+        bus.load_image(&GEOMETRY, image);
+        let mut drive = FloppyDrive::new(bus);
+        drive.set_geometry(&GEOMETRY);
+        assert_eq!(drive.set_motor(true), Ok(()));
+        return drive;
+    }
+
+    #[test]
+    pub fn test_packets() {
+        let mut sender = YmodemSender::new();
+        let mut receiver = YmodemReceiver::new();
+        assert_eq!(sender.push(receiver.start()[0]), TxEvent::Ready);
+
+        let header = sender.send_header(b"disk.img", 1474560).to_vec();
+        assert_eq!(header.len(), 133);
+        assert_eq!(header[0..3], [SOH, 0, 0xFF]);
+        assert_eq!(header[3..20], *b"disk.img\x001474560\x00");
+
+        let events: Vec<RxEvent> = header.iter().map(|byte| receiver.push(*byte)).collect();
+        assert_eq!(events[132], RxEvent::Header);
+        assert_eq!(receiver.size(), Some(1474560));
+        assert_eq!(receiver.reply(), [ACK, CRC_MODE]);
+        assert_eq!(sender.push(ACK), TxEvent::Pending);
+        assert_eq!(sender.push(CRC_MODE), TxEvent::Ready);
+
+        // A rejected block goes out again, and a repeat is only acknowledged
+        let data = sender.send_data(&[0x11; 1000]).to_vec();
+        assert_eq!(data.len(), PACKET_LEN);
+        assert_eq!(data[1027 - 24..1027], [SUB; 24]);
+        assert_eq!(sender.push(NAK), TxEvent::Transmit);
+        assert_eq!(sender.outgoing(), &data[..]);
+        for byte in data.iter() {
+            receiver.push(*byte);
+        }
+        assert_eq!(receiver.data()[0..1000], [0x11; 1000]);
+        let repeat: Vec<RxEvent> = data.iter().map(|byte| receiver.push(*byte)).collect();
+        assert_eq!(repeat[PACKET_LEN - 1], RxEvent::Transmit);
+        assert_eq!(receiver.reply(), [ACK]);
+        assert_eq!(sender.push(ACK), TxEvent::Ready);
+
+        assert_eq!(receiver.push(sender.send_eot()[0]), RxEvent::EndOfFile);
+        assert_eq!(sender.push(ACK), TxEvent::Pending);
+        assert_eq!(sender.push(CRC_MODE), TxEvent::Ready);
+        let end = sender.send_end().to_vec();
+        let events: Vec<RxEvent> = end.iter().map(|byte| receiver.push(*byte)).collect();
+        assert_eq!(events[132], RxEvent::Done);
+        assert_eq!(sender.push(ACK), TxEvent::Done);
+
+        // Something that isn't the next block
+        let mut receiver = YmodemReceiver::new();
+        let mut sender = YmodemSender::new();
+        sender.send_data(&[0; 128]);
+        let block = sender.send_data(&[0; 128]).to_vec();
+        let events: Vec<RxEvent> = block.iter().map(|byte| receiver.push(*byte)).collect();
+        assert_eq!(events[132], RxEvent::Failed);
+    }
+
+    #[test]
+    pub fn test_dump() {
+        let image = test_image();
+        let mut drive = drive_with(&image);
+        let mut host = ReceivingHost::new();
+
+        // The first data block arrives damaged
+        host.corrupt = Some(133 + 100);
+
+        let report = dump_image(&mut drive, &mut host).ok().unwrap();
+        assert_eq!(report.sectors, GEOMETRY.blocks());
+        assert_eq!(report.bad_count, 0);
+        assert!(host.done);
+        assert_eq!(host.naks, 1);
+
+        assert_eq!(host.files.len(), 1);
+        let file = &host.files[0];
+        assert_eq!(file.name, b"disk.img");
+        assert_eq!(file.size, Some(image.len() as u32));
+        assert_eq!(file.data[0..image.len()], image[..]);
+    }
+
+    #[test]
+    pub fn test_dump_bad_sector() {
+        let image = test_image();
+        let mut drive = drive_with(&image);
+
+        // Damage the data field of sector 5 on the first track
+        let mut flux = encode_track(&GEOMETRY, 0, 0, &image[0..18 * 512]);
+        let marks: Vec<SyncMark> = mfm_sync_marks(&flux)
+            .filter(|mark| mark.kind == SyncKind::A1)
+            .collect();
+        let position = marks[9].position + 200;
+        flux[position] = match flux[position] {
+            Symbol::Pulse10 => Symbol::Pulse100,
+            _ => Symbol::Pulse10,
+        };
+        drive.bus().load_track(0, 0, flux);
+
+        let mut host = ReceivingHost::new();
+        let report = dump_image(&mut drive, &mut host).ok().unwrap();
+        assert_eq!(report.sectors, GEOMETRY.blocks());
+        assert_eq!(report.bad_count, 1);
+        assert_eq!(
+            report.bad[0],
+            BadSector {
+                cylinder: 0,
+                head: 0,
+                sector: 5,
+                error: FddError::DataCrc,
+            }
+        );
+
+        // The sector is left blank in the image and listed in the trailer
+        assert_eq!(host.files.len(), 2);
+        assert_eq!(host.files[0].data[4 * 512..5 * 512], [0; 512]);
+        assert_eq!(host.files[0].data[5 * 512..], image[5 * 512..]);
+
+        let trailer = b"cylinder 0 head 0 sector 5: data field failed its CRC\r\n";
+        assert_eq!(host.files[1].name, b"badsect.txt");
+        assert_eq!(host.files[1].size, Some(trailer.len() as u32));
+        assert_eq!(host.files[1].data[0..trailer.len()], trailer[..]);
+    }
+
+    #[test]
+    pub fn test_dump_cancelled() {
+        let image = test_image();
+        let mut drive = drive_with(&image);
+        let mut host = MockSerial::new();
+        host.send(&[CAN, CAN]);
+        assert_eq!(
+            dump_image(&mut drive, &mut host).err(),
+            Some(XmodemError::Cancelled)
+        );
+        assert_eq!(host.take(), []);
+    }
+
+    #[test]
+    pub fn test_dump_long_track() {
+        let image = test_image();
+        let mut drive = drive_with(&image);
+        let mut dmf = GEOMETRY_1440K;
+        dmf.sectors_per_track = 21;
+        drive.set_geometry(&dmf);
+
+        let mut host = ReceivingHost::new();
+        assert_eq!(
+            dump_image(&mut drive, &mut host).err(),
+            Some(XmodemError::Drive(FddError::InvalidArgument))
+        );
+        assert!(host.files.is_empty());
+    }
+
+    #[test]
+    pub fn test_restore() {
+        let image = test_image();
+        let mut drive = drive_with(&vec![0xF6; image.len()]);
+        let mut host = SendingHost::new(image.clone(), Some(image.len() as u32));
+
+        let report = restore_image(&mut drive, &mut host).ok().unwrap();
+        assert!(host.done);
+        assert_eq!(report.sectors, GEOMETRY.blocks());
+        assert_eq!(report.bad_count, 0);
+
+        let mut buf = [0u8; 512];
+        for lba in [0, 17, 18, 71] {
+            assert_eq!(drive.read_block(lba, &mut buf), Ok(()));
+            assert_eq!(buf[..], image[lba as usize * 512..(lba as usize + 1) * 512]);
+        }
+    }
+
+    #[test]
+    pub fn test_restore_xmodem() {
+        let image = test_image();
+        let mut drive = drive_with(&vec![0xF6; image.len()]);
+        let mut host = SendingHost::new(image.clone(), None);
+
+        let report = restore_image(&mut drive, &mut host).ok().unwrap();
+        assert!(host.eot);
+        assert_eq!(report.sectors, GEOMETRY.blocks());
+        assert_eq!(report.bad_count, 0);
+        assert_eq!(
+            drive.bus().peek_sector(1, 1, 18),
+            Some(vec![71 ^ 0x5A; 512])
+        );
+    }
+
+    #[test]
+    pub fn test_restore_wrong_size() {
+        let image = test_image();
+        let mut drive = drive_with(&image);
+        let mut host = SendingHost::new(vec![0; 1024], Some(1024));
+
+        assert_eq!(
+            restore_image(&mut drive, &mut host).err(),
+            Some(XmodemError::WrongSize)
+        );
+        assert!(host.cancelled);
+    }
+}